@@ -18,6 +18,10 @@ fn main() {
         build.define("MI_MALLOC", "1");
     }
 
+    if cfg!(feature = "tracing") {
+        build.define("NEPTUNE_TRACING", None);
+    }
+
     build
         .include("vendor/github.com/dcleblanc")
         .include("vendor/github.com/microsoft/mimalloc/src")
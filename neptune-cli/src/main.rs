@@ -1,10 +1,7 @@
-use std::{
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 use tokio::time::{sleep, Duration};
 
-use neptune_lang::{EFuncError, InterpretError, ModuleLoader, ToNeptuneValue, VM};
+use neptune_lang::{EFuncError, FileSystemModuleLoader, InterpretError, ToNeptuneValue, VM};
 use rustyline::{
     validate::{self, Validator},
     Editor,
@@ -19,31 +16,7 @@ fn main() {
 }
 
 fn try_main() -> Result<(), Box<dyn std::error::Error>> {
-    let vm = VM::new(FileSystemModuleLoader);
-    vm.create_efunc("print", |cx| -> Result<(), EFuncError> {
-        println!("{}", cx.as_string()?);
-        Ok(())
-    })
-    .unwrap();
-    vm.exec_sync("<prelude>", include_str!("prelude.np"))
-        .unwrap();
-
-    vm.create_efunc("timeNow", |_| -> Result<f64, ()> {
-        Ok(std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64())
-    })
-    .unwrap();
-    vm.create_efunc_async("sleep", |cx| {
-        let time = cx.as_int();
-        async move {
-            sleep(Duration::from_millis(time? as u64)).await;
-            Result::<(), EFuncError>::Ok(())
-        }
-    })
-    .unwrap();
-    vm.exec_sync("time", include_str!("time.np")).unwrap();
+    let vm = new_vm()?;
 
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -67,32 +40,28 @@ fn try_main() -> Result<(), Box<dyn std::error::Error>> {
     })
 }
 
-#[derive(Clone, Copy)]
-struct FileSystemModuleLoader;
-
-impl ModuleLoader for FileSystemModuleLoader {
-    fn resolve(&self, caller_module: &str, module: &str) -> Option<String> {
-        let current_path: PathBuf;
-        if caller_module == "<repl>" {
-            if let Ok(dir) = std::env::current_dir() {
-                current_path = dir;
-            } else {
-                return None;
-            }
-        } else {
-            let caller_path = Path::new(caller_module);
-            current_path = caller_path.parent().unwrap().into();
-        };
-        let module_path = current_path.join(module);
-        match std::fs::canonicalize(module_path) {
-            Ok(path) => Some(path.to_string_lossy().into_owned()),
-            Err(_) => None,
+// Creates a VM with the time module
+fn new_vm() -> Result<VM, Box<dyn std::error::Error>> {
+    let vm = VM::new(
+        FileSystemModuleLoader::new(std::env::current_dir()?).search_paths_from_env("NEPTUNE_PATH"),
+    );
+    vm.create_efunc("timeNow", |_| -> Result<f64, ()> {
+        Ok(std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64())
+    })
+    .unwrap();
+    vm.create_efunc_async("sleep", |cx| {
+        let time = cx.as_int();
+        async move {
+            sleep(Duration::from_millis(time? as u64)).await;
+            Result::<(), EFuncError>::Ok(())
         }
-    }
-
-    fn load(&self, module: &str) -> Option<String> {
-        std::fs::read_to_string(module).ok()
-    }
+    })
+    .unwrap();
+    vm.exec_sync("time", include_str!("time.np")).unwrap();
+    Ok(vm)
 }
 
 #[derive(Helper, Hinter, Highlighter, Completer)]
@@ -145,9 +114,7 @@ async fn repl(vm: &VM) -> Result<(), InterpretError> {
         }
     })
     .unwrap();
-    if let Err(e) = vm.exec("<repl>", include_str!("repl.np")).await {
-        return Err(e);
-    }
+    run_repl(vm).await?;
     if let Some(file) = histfile {
         if let Err(e) = rl.lock().unwrap().save_history(&file) {
             eprintln!("Error in saving REPL history: {}", e)
@@ -156,6 +123,11 @@ async fn repl(vm: &VM) -> Result<(), InterpretError> {
     Ok(())
 }
 
+// Evaluates the lines returned by the efunc replReadline until it throws @eof
+async fn run_repl(vm: &VM) -> Result<(), InterpretError> {
+    vm.exec("<script>", include_str!("repl.np")).await
+}
+
 struct ReadlineError(rustyline::error::ReadlineError);
 
 impl ToNeptuneValue for ReadlineError {
@@ -258,9 +230,13 @@ pub fn are_brackets_balanced(s: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::Write;
+    use std::rc::Rc;
     use std::time::Duration;
 
-    use super::are_brackets_balanced;
+    use super::{are_brackets_balanced, new_vm, run_repl, ReadlineError};
     use crate::FileSystemModuleLoader;
     use neptune_lang::{EFuncError, VM};
     use tokio::time::sleep;
@@ -277,7 +253,7 @@ mod tests {
 
     #[test]
     fn test_import() {
-        let vm = VM::new(FileSystemModuleLoader);
+        let vm = VM::new(FileSystemModuleLoader::new(env!("CARGO_MANIFEST_DIR")));
         vm.exec_sync(
             concat!(env!("CARGO_MANIFEST_DIR"), "/test/test_import1.np"),
             include_str!("../test/test_import1.np"),
@@ -291,7 +267,7 @@ mod tests {
             .enable_all()
             .build()
             .unwrap();
-        let vm = VM::new(FileSystemModuleLoader);
+        let vm = VM::new(FileSystemModuleLoader::new(env!("CARGO_MANIFEST_DIR")));
         vm.create_efunc_async("sleep", |cx| {
             let time = cx.as_int();
             async move {
@@ -307,4 +283,52 @@ mod tests {
             ))
             .unwrap();
     }
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_repl() {
+        let vm = new_vm().unwrap();
+        let output = Output::default();
+        vm.set_stdout(output.clone());
+        let mut lines: VecDeque<_> = [
+            "let source = 1 + 2",
+            "source * 2",
+            "const {now} = import('time')",
+            "now().getClass()",
+            "null",
+            "1 +",
+            "throw new Error('a')",
+        ]
+        .into_iter()
+        .collect();
+        vm.create_efunc("replReadline", move |_| match lines.pop_front() {
+            Some(line) => Ok(line.to_string()),
+            None => Err(ReadlineError(rustyline::error::ReadlineError::Eof)),
+        })
+        .unwrap();
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(run_repl(&vm))
+            .unwrap();
+        let output = String::from_utf8(output.0.take()).unwrap();
+        let mut output = output.lines();
+        assert_eq!(output.next(), Some("6"));
+        assert_eq!(output.next(), Some("<Class Float>"));
+        assert_eq!(output.next(), Some("In module <script>"));
+        assert_eq!(output.next(), Some("line 1: Expect expression at end"));
+        assert_eq!(output.next(), Some("Uncaught exception:"));
+    }
 }
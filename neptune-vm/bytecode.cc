@@ -0,0 +1,957 @@
+#include "neptune-vm.h"
+#include <algorithm>
+#include <unordered_map>
+#include <unordered_set>
+
+namespace neptune_vm {
+#define OP(x) 1 +
+constexpr unsigned NUM_OPS = OPS 0;
+#undef OP
+
+const char *op_name(Op op) {
+#define OP(x)                                                                  \
+  case Op::x:                                                                  \
+    return #x;
+  switch (op) { OPS }
+#undef OP
+  return "Invalid";
+}
+
+static uint8_t operand_types(Op op, OperandType *types) {
+  using T = OperandType;
+  auto set = [&](std::initializer_list<OperandType> list) {
+    std::copy(list.begin(), list.end(), types);
+    return static_cast<uint8_t>(list.size());
+  };
+  switch (op) {
+  case Op::LoadRegister:
+  case Op::StoreRegister:
+  case Op::LoadSubscript:
+  case Op::AddRegister:
+  case Op::SubtractRegister:
+  case Op::MultiplyRegister:
+  case Op::DivideRegister:
+  case Op::ModRegister:
+  case Op::ConcatRegister:
+  case Op::Equal:
+  case Op::NotEqual:
+  case Op::StrictEqual:
+  case Op::StrictNotEqual:
+  case Op::GreaterThan:
+  case Op::LesserThan:
+  case Op::GreaterThanOrEqual:
+  case Op::LesserThanOrEqual:
+  case Op::Range:
+  case Op::Close:
+    return set({T::Register});
+  case Op::LoadConstant:
+  case Op::MakeFunction:
+  case Op::MakeClass:
+  case Op::JumpConstant:
+  case Op::JumpIfFalseOrNullConstant:
+  case Op::JumpIfNotFalseOrNullConstant:
+    return set({T::Constant});
+  case Op::Move:
+  case Op::StoreSubscript:
+    return set({T::Register, T::Register});
+  case Op::LoadModuleVariable:
+  case Op::StoreModuleVariable:
+    return set({T::ModuleVariable});
+  case Op::LoadProperty:
+  case Op::StoreProperty:
+    return set({T::Register, T::Constant});
+  case Op::LoadUpvalue:
+  case Op::StoreUpvalue:
+    return set({T::Upvalue});
+  case Op::StoreArrayUnchecked:
+  case Op::StoreArray:
+    return set({T::Register, T::Unsigned});
+  case Op::AddInt:
+  case Op::SubtractInt:
+  case Op::MultiplyInt:
+  case Op::DivideInt:
+  case Op::ModInt:
+    return set({T::Signed});
+  case Op::Call:
+  case Op::Construct:
+    return set({T::Arguments, T::ArgCount});
+  case Op::CallMethod:
+    return set({T::Register, T::Constant, T::Arguments, T::ArgCount});
+  case Op::SuperCall:
+    return set({T::Constant, T::Arguments, T::ArgCount});
+  case Op::NewArray:
+  case Op::NewMap:
+  case Op::NewObject:
+    return set({T::Unsigned, T::Register});
+  case Op::ForLoop:
+  case Op::ForLoopChecked:
+  case Op::BeginForLoop:
+    return set({T::JumpOffset, T::Register});
+  case Op::BeginForLoopConstant:
+    return set({T::Constant, T::Register});
+  case Op::Jump:
+  case Op::JumpIfFalseOrNull:
+  case Op::JumpIfNotFalseOrNull:
+  case Op::JumpBack:
+    return set({T::JumpOffset});
+  case Op::Switch:
+    return set({T::JumpTable});
+  case Op::LoadSmallInt:
+    return set({T::SmallInt});
+  default:
+    return 0;
+  }
+}
+
+// ArgCount and SmallInt operands are a byte regardless of the prefix
+static uint8_t operand_width(const Instruction &instruction, uint8_t i) {
+  if (instruction.types[i] == OperandType::ArgCount ||
+      instruction.types[i] == OperandType::SmallInt)
+    return 1;
+  else
+    return instruction.width;
+}
+
+Instruction decode_instruction(const vector<uint8_t> &bytecode,
+                               uint32_t offset) {
+  auto begin = bytecode.data();
+  auto end = begin + bytecode.size();
+  if (offset >= bytecode.size())
+    throw std::runtime_error("Expected an instruction at offset " +
+                             std::to_string(offset));
+  auto ip = begin + offset;
+  Instruction instruction;
+  instruction.width = 1;
+  auto op = checked_read<uint8_t>(ip, end);
+  if (op == static_cast<uint8_t>(Op::Wide) ||
+      op == static_cast<uint8_t>(Op::ExtraWide)) {
+    instruction.width = op == static_cast<uint8_t>(Op::Wide) ? 2 : 4;
+    if (ip == end)
+      throw std::runtime_error("Expected an op after " +
+                               std::string(op_name(static_cast<Op>(op))) +
+                               " at offset " + std::to_string(offset));
+    op = checked_read<uint8_t>(ip, end);
+  }
+  if (op >= NUM_OPS)
+    throw std::runtime_error("Invalid op " + std::to_string(op) +
+                             " at offset " + std::to_string(offset));
+  instruction.op = static_cast<Op>(op);
+  instruction.num_operands = operand_types(instruction.op, instruction.types);
+  if (instruction.width != 1 &&
+      (instruction.num_operands == 0 || instruction.op == Op::LoadSmallInt))
+    throw std::runtime_error(std::string(op_name(instruction.op)) +
+                             " at offset " + std::to_string(offset) +
+                             " does not have a wide variant");
+  for (uint8_t i = 0; i < instruction.num_operands; i++) {
+    instruction.offsets[i] = static_cast<uint32_t>(ip - begin);
+    if (static_cast<size_t>(end - ip) < operand_width(instruction, i))
+      throw std::runtime_error("Unexpected end of bytecode in " +
+                               std::string(op_name(instruction.op)) +
+                               " at offset " + std::to_string(offset));
+    uint32_t operand;
+    switch (instruction.types[i]) {
+    case OperandType::ArgCount:
+      operand = read<uint8_t>(ip);
+      break;
+    case OperandType::SmallInt:
+      operand = static_cast<uint32_t>(static_cast<int32_t>(read<int8_t>(ip)));
+      break;
+    case OperandType::Signed:
+      if (instruction.width == 1)
+        operand = static_cast<uint32_t>(static_cast<int32_t>(read<int8_t>(ip)));
+      else if (instruction.width == 2)
+        operand =
+            static_cast<uint32_t>(static_cast<int32_t>(read<int16_t>(ip)));
+      else
+        operand = static_cast<uint32_t>(read<int32_t>(ip));
+      break;
+    default:
+      if (instruction.width == 1)
+        operand = read<uint8_t>(ip);
+      else if (instruction.width == 2)
+        operand = read<uint16_t>(ip);
+      else
+        operand = read<uint32_t>(ip);
+    }
+    instruction.operands[i] = operand;
+  }
+  instruction.end = static_cast<uint32_t>(ip - begin);
+  return instruction;
+}
+
+// The stack of a task is allocated for the registers of its main function up
+// front so huge register counts are rejected instead of failing to allocate
+static constexpr uint32_t MAX_REGISTERS = 1 << 20;
+// Functions and classes in constants are read and verified recursively
+static constexpr uint32_t MAX_FUNCTION_DEPTH = 256;
+
+class BytecodeVerifier {
+  const FunctionInfo &f;
+  // The function that creates f, used to check its upvalues
+  const FunctionInfo *parent;
+  bool is_method;
+  // The number of functions f is nested in
+  uint32_t depth;
+  const std::unordered_set<uint32_t> &module_variables;
+  std::vector<bool> boundaries;
+
+  [[noreturn]] void error(const std::string &message) const {
+    throw std::runtime_error(message + " in function " + f.name);
+  }
+
+  [[noreturn]] void error(const Instruction &instruction, uint32_t offset,
+                          const std::string &message) const {
+    error(std::string(op_name(instruction.op)) + " at offset " +
+          std::to_string(offset) + ' ' + message);
+  }
+
+  void check_register(const Instruction &instruction, uint32_t offset,
+                      uint64_t reg) const {
+    if (reg >= f.max_registers)
+      error(instruction, offset,
+            "uses register " + std::to_string(reg) + " but the function has " +
+                std::to_string(f.max_registers) + " registers");
+  }
+
+  void check_target(const Instruction &instruction, uint32_t offset,
+                    int64_t target) const {
+    if (target < 0 || static_cast<uint64_t>(target) >= f.bytecode.size() ||
+        !boundaries[static_cast<size_t>(target)])
+      error(instruction, offset,
+            "jumps to " + std::to_string(target) +
+                " which is not the start of an instruction");
+  }
+
+  Value constant(const Instruction &instruction, uint32_t offset,
+                 uint32_t index) const {
+    if (index >= f.constants.size())
+      error(instruction, offset,
+            "uses constant " + std::to_string(index) + " but there are only " +
+                std::to_string(f.constants.size()) + " constants");
+    return f.constants[index];
+  }
+
+  template <typename O>
+  void check_constant_type(const Instruction &instruction, uint32_t offset,
+                           Value v) const {
+    if (!v.is_ptr() || !v.as_ptr()->is<O>())
+      error(instruction, offset,
+            "expects a constant of a different type than " +
+                std::string(v.type_string()));
+  }
+
+  void check_operands(const Instruction &instruction, uint32_t offset) const {
+    for (uint8_t i = 0; i < instruction.num_operands; i++) {
+      auto operand = instruction.operands[i];
+      switch (instruction.types[i]) {
+      case OperandType::Register:
+        check_register(instruction, offset, operand);
+        break;
+      case OperandType::Constant: {
+        auto v = constant(instruction, offset, operand);
+        switch (instruction.op) {
+        case Op::LoadProperty:
+        case Op::StoreProperty:
+        case Op::CallMethod:
+        case Op::SuperCall:
+          check_constant_type<Symbol>(instruction, offset, v);
+          break;
+        case Op::MakeFunction:
+          check_constant_type<FunctionInfo>(instruction, offset, v);
+          break;
+        case Op::MakeClass:
+          check_constant_type<Class>(instruction, offset, v);
+          break;
+        case Op::JumpConstant:
+        case Op::JumpIfFalseOrNullConstant:
+        case Op::JumpIfNotFalseOrNullConstant:
+        case Op::BeginForLoopConstant:
+          if (!v.is_int())
+            error(instruction, offset, "expects an Int constant");
+          check_target(instruction, offset,
+                       static_cast<int64_t>(offset) +
+                           static_cast<uint32_t>(v.as_int()));
+          break;
+        default:
+          // Function infos and classes are only valid as operands of
+          // MakeFunction and MakeClass
+          if (v.is_ptr() && (v.as_ptr()->is<FunctionInfo>() ||
+                             v.as_ptr()->is<Class>()))
+            error(instruction, offset,
+                  "cannot load a constant of type " +
+                      std::string(v.type_string()));
+        }
+      } break;
+      case OperandType::ModuleVariable:
+        if (module_variables.find(operand) == module_variables.end())
+          error(instruction, offset,
+                "uses module variable " + std::to_string(operand) +
+                    " which does not belong to module " + f.module);
+        break;
+      case OperandType::Upvalue:
+        if (operand >= f.upvalues.size())
+          error(instruction, offset,
+                "uses upvalue " + std::to_string(operand) +
+                    " but the function has " +
+                    std::to_string(f.upvalues.size()) + " upvalues");
+        break;
+      case OperandType::JumpOffset:
+        if (instruction.op == Op::JumpBack ||
+            instruction.op == Op::ForLoopChecked)
+          check_target(instruction, offset,
+                       static_cast<int64_t>(offset) - operand);
+        else
+          check_target(instruction, offset,
+                       static_cast<int64_t>(offset) + operand);
+        break;
+      case OperandType::JumpTable:
+        if (operand >= f.jump_tables.size())
+          error(instruction, offset,
+                "uses jump table " + std::to_string(operand) +
+                    " but there are only " +
+                    std::to_string(f.jump_tables.size()) + " jump tables");
+        // HashTable cannot be iterated through a const reference
+        for (auto pair :
+             const_cast<FunctionInfo &>(f).jump_tables[operand])
+          check_target(instruction, offset,
+                       static_cast<int64_t>(instruction.end) + pair.second);
+        break;
+      // Checked by check_implicit_registers
+      case OperandType::Arguments:
+      case OperandType::Unsigned:
+      case OperandType::Signed:
+      case OperandType::SmallInt:
+      case OperandType::ArgCount:
+        break;
+      }
+    }
+  }
+
+  // Checks the registers that are used without being an operand
+  void check_implicit_registers(const Instruction &instruction,
+                                uint32_t offset) const {
+    auto op = static_cast<uint8_t>(instruction.op);
+    if (op >= static_cast<uint8_t>(Op::LoadR0) &&
+        op <= static_cast<uint8_t>(Op::LoadR15))
+      check_register(instruction, offset,
+                     op - static_cast<uint8_t>(Op::LoadR0));
+    if (op >= static_cast<uint8_t>(Op::StoreR0) &&
+        op <= static_cast<uint8_t>(Op::StoreR15))
+      check_register(instruction, offset,
+                     op - static_cast<uint8_t>(Op::StoreR0));
+    switch (instruction.op) {
+    // The arguments are stored after the register operand
+    case Op::Call:
+      if (instruction.operands[1] != 0)
+        check_register(instruction, offset,
+                       uint64_t{instruction.operands[0]} +
+                           instruction.operands[1] - 1);
+      break;
+    // The receiver is stored in the register operand followed by the
+    // arguments
+    case Op::Construct:
+      check_register(instruction, offset,
+                     uint64_t{instruction.operands[0]} +
+                         instruction.operands[1]);
+      break;
+    case Op::SuperCall:
+      if (!is_method)
+        error(instruction, offset, "is only allowed in methods");
+      check_register(instruction, offset,
+                     uint64_t{instruction.operands[1]} +
+                         instruction.operands[2]);
+      break;
+    case Op::CallMethod:
+      check_register(instruction, offset,
+                     uint64_t{instruction.operands[2]} +
+                         instruction.operands[3]);
+      break;
+    // The end of the range is stored after the iterator
+    case Op::BeginForLoop:
+    case Op::BeginForLoopConstant:
+    case Op::ForLoopChecked:
+      check_register(instruction, offset,
+                     uint64_t{instruction.operands[1]} + 1);
+      break;
+    default:
+      break;
+    }
+  }
+
+public:
+  BytecodeVerifier(const FunctionInfo &f, const FunctionInfo *parent,
+                   bool is_method, uint32_t depth,
+                   const std::unordered_set<uint32_t> &module_variables)
+      : f(f), parent(parent), is_method(is_method), depth(depth),
+        module_variables(module_variables) {}
+
+  void verify() {
+    if (depth > MAX_FUNCTION_DEPTH)
+      error("Functions are nested more than " +
+            std::to_string(MAX_FUNCTION_DEPTH) + " levels deep");
+    if (f.max_registers > MAX_REGISTERS)
+      error("The function has " + std::to_string(f.max_registers) +
+            " registers but at most " + std::to_string(MAX_REGISTERS) +
+            " are allowed");
+    if (f.bytecode.empty())
+      error("Empty bytecode");
+    std::vector<std::pair<uint32_t, Instruction>> instructions;
+    boundaries.assign(f.bytecode.size(), false);
+    uint32_t offset = 0;
+    while (offset < f.bytecode.size()) {
+      boundaries[offset] = true;
+      instructions.push_back({offset, decode_instruction(f.bytecode, offset)});
+      offset = instructions.back().second.end;
+    }
+    switch (instructions.back().second.op) {
+    case Op::Return:
+    case Op::Throw:
+    case Op::Jump:
+    case Op::JumpConstant:
+    case Op::JumpBack:
+      break;
+    default:
+      error("Execution can continue past the end of the bytecode");
+    }
+    for (auto &pair : instructions) {
+      // These ops rely on the types of registers, which are not verified.
+      // BytecodeWriter replaces them with their checked variants
+      if (pair.second.op == Op::StoreArrayUnchecked ||
+          pair.second.op == Op::ForLoop)
+        error(pair.second, pair.first, "is not allowed in loaded bytecode");
+      check_operands(pair.second, pair.first);
+      check_implicit_registers(pair.second, pair.first);
+    }
+    for (auto handler : f.exception_handlers) {
+      if (handler.try_begin > handler.try_end ||
+          handler.try_end > f.bytecode.size())
+        error("Invalid try block " + std::to_string(handler.try_begin) + '-' +
+              std::to_string(handler.try_end));
+      if (handler.catch_begin >= f.bytecode.size() ||
+          !boundaries[handler.catch_begin])
+        error("Catch block at " + std::to_string(handler.catch_begin) +
+              " is not the start of an instruction");
+      if (handler.error_reg >= f.max_registers)
+        error("Exception handler uses register " +
+              std::to_string(handler.error_reg) + " but the function has " +
+              std::to_string(f.max_registers) + " registers");
+    }
+    // Line numbers are looked up with a binary search
+    for (size_t i = 0; i < f.lines.size(); i++) {
+      if ((i == 0 && f.lines[i].offset != 0) ||
+          (i != 0 && f.lines[i].offset <= f.lines[i - 1].offset) ||
+          f.lines[i].offset >= f.bytecode.size())
+        error("Invalid line information");
+    }
+    for (auto upvalue : f.upvalues) {
+      if (parent == nullptr)
+        error("A function that is run directly cannot have upvalues");
+      auto max = upvalue.is_local ? parent->max_registers
+                                  : static_cast<uint32_t>(
+                                        parent->upvalues.size());
+      if (upvalue.index >= max)
+        error("Invalid upvalue " + std::to_string(upvalue.index));
+    }
+    for (auto constant : f.constants) {
+      if (!constant.is_ptr())
+        continue;
+      if (constant.as_ptr()->is<FunctionInfo>()) {
+        BytecodeVerifier(*constant.as_ptr()->as<FunctionInfo>(), &f, false,
+                         depth + 1, module_variables)
+            .verify();
+      } else if (constant.as_ptr()->is<Class>()) {
+        for (auto pair : constant.as_ptr()->as<Class>()->methods) {
+          if (!pair.second->is<FunctionInfo>())
+            error("Invalid method in class " +
+                  constant.as_ptr()->as<Class>()->name);
+          BytecodeVerifier(*pair.second->as<FunctionInfo>(), &f, true,
+                           depth + 1, module_variables)
+              .verify();
+        }
+      }
+    }
+  }
+};
+
+void verify(const FunctionInfo &f,
+            const std::unordered_set<uint32_t> &module_variables) {
+  BytecodeVerifier(f, nullptr, false, 0, module_variables).verify();
+}
+
+// Constants are tagged with a byte in serialized bytecode
+enum class ConstantTag : uint8_t {
+  Null,
+  True,
+  False,
+  Int,
+  Float,
+  String,
+  Symbol,
+  FunctionInfo,
+  Class
+};
+
+static constexpr char BYTECODE_MAGIC[4] = {'N', 'P', 'B', 'C'};
+static constexpr uint32_t BYTECODE_VERSION = 1;
+
+static bool is_little_endian() {
+  uint16_t n = 1;
+  return *reinterpret_cast<uint8_t *>(&n) == 1;
+}
+
+// Operands are stored in the native byte order by FunctionInfoWriter while
+// serialized bytecode is always little endian
+static void swap_operands(vector<uint8_t> &bytecode) {
+  uint32_t offset = 0;
+  while (offset < bytecode.size()) {
+    auto instruction = decode_instruction(bytecode, offset);
+    for (uint8_t i = 0; i < instruction.num_operands; i++) {
+      auto begin = bytecode.begin() + instruction.offsets[i];
+      std::reverse(begin, begin + operand_width(instruction, i));
+    }
+    offset = instruction.end;
+  }
+}
+
+// Replaces the ops that trust the compiler with variants that check their
+// operands at runtime
+static void use_checked_ops(vector<uint8_t> &bytecode) {
+  uint32_t offset = 0;
+  while (offset < bytecode.size()) {
+    auto instruction = decode_instruction(bytecode, offset);
+    auto op = &bytecode[instruction.width == 1 ? offset : offset + 1];
+    if (instruction.op == Op::StoreArrayUnchecked)
+      *op = static_cast<uint8_t>(Op::StoreArray);
+    else if (instruction.op == Op::ForLoop)
+      *op = static_cast<uint8_t>(Op::ForLoopChecked);
+    offset = instruction.end;
+  }
+}
+
+class BytecodeWriter {
+  rust::Vec<uint8_t> out;
+
+  static void collect_module_variables(const FunctionInfo &f,
+                                       std::vector<uint32_t> &positions) {
+    uint32_t offset = 0;
+    while (offset < f.bytecode.size()) {
+      auto instruction = decode_instruction(f.bytecode, offset);
+      if (instruction.num_operands != 0 &&
+          instruction.types[0] == OperandType::ModuleVariable) {
+        if (instruction.width != 4)
+          throw std::runtime_error(
+              "Only bytecode compiled to be relocatable can be serialized");
+        positions.push_back(instruction.operands[0]);
+      }
+      offset = instruction.end;
+    }
+    for (auto constant : f.constants) {
+      if (!constant.is_ptr())
+        continue;
+      if (constant.as_ptr()->is<FunctionInfo>()) {
+        collect_module_variables(*constant.as_ptr()->as<FunctionInfo>(),
+                                 positions);
+      } else if (constant.as_ptr()->is<Class>()) {
+        for (auto pair : constant.as_ptr()->as<Class>()->methods)
+          collect_module_variables(*pair.second->as<FunctionInfo>(), positions);
+      }
+    }
+  }
+
+  void u8(uint8_t u) { out.push_back(u); }
+
+  void u32(uint32_t u) {
+    for (int i = 0; i < 4; i++)
+      out.push_back(static_cast<uint8_t>(u >> (8 * i)));
+  }
+
+  void u64(uint64_t u) {
+    for (int i = 0; i < 8; i++)
+      out.push_back(static_cast<uint8_t>(u >> (8 * i)));
+  }
+
+  void string(StringSlice s) {
+    u32(static_cast<uint32_t>(s.len));
+    for (size_t i = 0; i < s.len; i++)
+      out.push_back(static_cast<uint8_t>(s.data[i]));
+  }
+
+  void value(Value v) {
+    if (v.is_null()) {
+      u8(static_cast<uint8_t>(ConstantTag::Null));
+    } else if (v.is_true()) {
+      u8(static_cast<uint8_t>(ConstantTag::True));
+    } else if (v.is_false()) {
+      u8(static_cast<uint8_t>(ConstantTag::False));
+    } else if (v.is_int()) {
+      u8(static_cast<uint8_t>(ConstantTag::Int));
+      u32(static_cast<uint32_t>(v.as_int()));
+    } else if (v.is_float()) {
+      u8(static_cast<uint8_t>(ConstantTag::Float));
+      auto d = v.as_float();
+      u64(read_unaligned<uint64_t>(&d));
+    } else if (v.is_ptr() && v.as_ptr()->is<String>()) {
+      u8(static_cast<uint8_t>(ConstantTag::String));
+      string(*v.as_ptr()->as<String>());
+    } else if (v.is_ptr() && v.as_ptr()->is<Symbol>()) {
+      u8(static_cast<uint8_t>(ConstantTag::Symbol));
+      string(*v.as_ptr()->as<Symbol>());
+    } else if (v.is_ptr() && v.as_ptr()->is<FunctionInfo>()) {
+      u8(static_cast<uint8_t>(ConstantTag::FunctionInfo));
+      function(*v.as_ptr()->as<FunctionInfo>());
+    } else if (v.is_ptr() && v.as_ptr()->is<Class>()) {
+      auto class_ = v.as_ptr()->as<Class>();
+      u8(static_cast<uint8_t>(ConstantTag::Class));
+      string(StringSlice(class_->name));
+      u32(static_cast<uint32_t>(class_->methods.size()));
+      for (auto pair : class_->methods) {
+        string(*pair.first);
+        function(*pair.second->as<FunctionInfo>());
+      }
+    } else {
+      throw std::runtime_error("Cannot serialize a constant of type " +
+                               std::string(v.type_string()));
+    }
+  }
+
+public:
+  void header(VM &vm, const FunctionInfo &f) {
+    for (auto c : BYTECODE_MAGIC)
+      u8(static_cast<uint8_t>(c));
+    u32(BYTECODE_VERSION);
+    std::vector<uint32_t> positions;
+    collect_module_variables(f, positions);
+    std::sort(positions.begin(), positions.end());
+    positions.erase(std::unique(positions.begin(), positions.end()),
+                    positions.end());
+    auto module = vm.get_module(StringSlice(f.module));
+    if (module == nullptr)
+      throw std::runtime_error("No such module");
+    u32(static_cast<uint32_t>(positions.size()));
+    for (auto position : positions) {
+      auto found = false;
+      for (auto pair : module->module_variables) {
+        if (pair.second.position == position) {
+          u32(position);
+          string(*pair.first);
+          u8(pair.second.mutable_);
+          u8(pair.second.exported);
+          found = true;
+          break;
+        }
+      }
+      if (!found)
+        throw std::runtime_error("Module variable " + std::to_string(position) +
+                                 " does not belong to module " + f.module);
+    }
+  }
+
+  void function(const FunctionInfo &f) {
+    string(StringSlice(f.name));
+    u8(f.arity);
+    u32(f.max_registers);
+    auto bytecode = f.bytecode;
+    use_checked_ops(bytecode);
+    if (!is_little_endian())
+      swap_operands(bytecode);
+    u32(static_cast<uint32_t>(bytecode.size()));
+    for (auto b : bytecode)
+      u8(b);
+    u32(static_cast<uint32_t>(f.constants.size()));
+    for (auto constant : f.constants)
+      value(constant);
+    u32(static_cast<uint32_t>(f.lines.size()));
+    for (auto line : f.lines) {
+      u32(line.offset);
+      u32(line.line);
+    }
+    u32(static_cast<uint32_t>(f.upvalues.size()));
+    for (auto upvalue : f.upvalues) {
+      u32(upvalue.index);
+      u8(upvalue.is_local);
+    }
+    u32(static_cast<uint32_t>(f.exception_handlers.size()));
+    for (auto handler : f.exception_handlers) {
+      u32(handler.try_begin);
+      u32(handler.try_end);
+      u32(handler.error_reg);
+      u32(handler.catch_begin);
+    }
+    u32(static_cast<uint32_t>(f.jump_tables.size()));
+    // HashTable cannot be iterated through a const reference
+    for (auto &jump_table : const_cast<FunctionInfo &>(f).jump_tables) {
+      u32(static_cast<uint32_t>(jump_table.size()));
+      for (auto pair : jump_table) {
+        value(pair.first);
+        u32(pair.second);
+      }
+    }
+  }
+
+  rust::Vec<uint8_t> finish() { return std::move(out); }
+};
+
+rust::Vec<uint8_t> FunctionInfoWriter::serialize() const {
+  BytecodeWriter writer;
+  writer.header(*vm, *hf->object);
+  writer.function(*hf->object);
+  return writer.finish();
+}
+
+class BytecodeReader {
+  VM &vm;
+  std::string module;
+  const uint8_t *ip;
+  const uint8_t *end;
+  struct HeaderVariable {
+    // The position in the serializing VM
+    uint32_t position;
+    StringSlice name;
+    bool mutable_;
+    bool exported;
+  };
+  // The module variables are only added to the module once the bytecode is
+  // verified so that invalid bytecode does not leave variables behind
+  vector<HeaderVariable> module_variables;
+  // The functions that were read. Their module variables are relocated once
+  // the variables are added
+  vector<FunctionInfo *> functions;
+  // The number of functions being read, checked before the verifier runs so
+  // that deeply nested functions cannot overflow the native stack
+  uint32_t depth;
+
+  void expect(size_t bytes) {
+    if (static_cast<size_t>(end - ip) < bytes)
+      throw std::runtime_error("Unexpected end of bytecode");
+  }
+
+  uint8_t u8() {
+    expect(1);
+    return *ip++;
+  }
+
+  uint32_t u32() {
+    expect(4);
+    uint32_t u = 0;
+    for (int i = 0; i < 4; i++)
+      u |= static_cast<uint32_t>(*ip++) << (8 * i);
+    return u;
+  }
+
+  uint64_t u64() {
+    expect(8);
+    uint64_t u = 0;
+    for (int i = 0; i < 8; i++)
+      u |= static_cast<uint64_t>(*ip++) << (8 * i);
+    return u;
+  }
+
+  StringSlice string() {
+    auto len = u32();
+    expect(len);
+    StringSlice s{reinterpret_cast<const char *>(ip), len};
+    ip += len;
+    return s;
+  }
+
+  // The returned value is not rooted
+  Value value(bool allow_functions) {
+    auto tag = u8();
+    switch (static_cast<ConstantTag>(tag)) {
+    case ConstantTag::Null:
+      return Value::null();
+    case ConstantTag::True:
+      return Value(true);
+    case ConstantTag::False:
+      return Value(false);
+    case ConstantTag::Int:
+      return Value(static_cast<int32_t>(u32()));
+    case ConstantTag::Float: {
+      auto bits = u64();
+      return Value(read_unaligned<double>(&bits));
+    }
+    case ConstantTag::String:
+      return Value(vm.allocate<String>(string()));
+    case ConstantTag::Symbol:
+      return Value(vm.intern(string()));
+    case ConstantTag::FunctionInfo:
+      if (!allow_functions)
+        break;
+      return Value(function());
+    case ConstantTag::Class: {
+      if (!allow_functions)
+        break;
+      auto name = string();
+      auto class_ = vm.allocate<Class>();
+      class_->name = std::string(name.data, name.len);
+      vm.temp_roots.push_back(Value(class_));
+      auto num_methods = u32();
+      for (uint32_t i = 0; i < num_methods; i++) {
+        auto method_name = string();
+        auto method = function();
+        vm.temp_roots.push_back(Value(method));
+        auto symbol = vm.intern(method_name);
+        vm.temp_roots.pop_back();
+        class_->methods.insert({symbol, method});
+      }
+      vm.temp_roots.pop_back();
+      return Value(class_);
+    }
+    }
+    throw std::runtime_error("Invalid constant tag " + std::to_string(tag));
+  }
+
+  // Calls callback with each module variable operand in bytecode
+  template <typename F>
+  static void for_each_module_variable(vector<uint8_t> &bytecode,
+                                       F callback) {
+    uint32_t offset = 0;
+    while (offset < bytecode.size()) {
+      auto instruction = decode_instruction(bytecode, offset);
+      if (instruction.num_operands != 0 &&
+          instruction.types[0] == OperandType::ModuleVariable)
+        callback(instruction, offset);
+      offset = instruction.end;
+    }
+  }
+
+public:
+  BytecodeReader(VM &vm, StringSlice module, rust::Slice<const uint8_t> bytes)
+      : vm(vm), module(module.data, module.len), ip(bytes.data()),
+        end(bytes.data() + bytes.size()), depth(0) {}
+
+  void header() {
+    for (auto c : BYTECODE_MAGIC) {
+      if (u8() != static_cast<uint8_t>(c))
+        throw std::runtime_error("Not Neptune bytecode");
+    }
+    auto version = u32();
+    if (version != BYTECODE_VERSION)
+      throw std::runtime_error("Unsupported bytecode version " +
+                               std::to_string(version));
+    auto num_variables = u32();
+    // The elements of a braced list are evaluated in order
+    for (uint32_t i = 0; i < num_variables; i++)
+      module_variables.push_back(
+          HeaderVariable{u32(), string(), u8() != 0, u8() != 0});
+  }
+
+  // The positions of the module variables in the serializing VM
+  std::unordered_set<uint32_t> module_variable_positions() const {
+    std::unordered_set<uint32_t> positions;
+    for (auto &variable : module_variables)
+      positions.insert(variable.position);
+    return positions;
+  }
+
+  // Adds the module variables to the module and makes the functions use their
+  // positions in this VM. The functions must be rooted and verified
+  void link() {
+    std::unordered_map<uint32_t, uint32_t> positions;
+    for (auto &variable : module_variables) {
+      // Variables that already exist (like the ones copied from the prelude)
+      // are reused
+      vm.add_module_variable(StringSlice(module), variable.name,
+                             variable.mutable_, variable.exported);
+      positions[variable.position] =
+          vm.get_module_variable(StringSlice(module), variable.name).position;
+    }
+    for (auto f : functions) {
+      for_each_module_variable(
+          f->bytecode, [&](const Instruction &instruction, uint32_t) {
+            write_unaligned<uint32_t>(f->bytecode.data() +
+                                          instruction.offsets[0],
+                                      positions.at(instruction.operands[0]));
+          });
+    }
+  }
+
+  // The returned function info is not rooted
+  FunctionInfo *function() {
+    if (depth > MAX_FUNCTION_DEPTH)
+      throw std::runtime_error("Functions are nested more than " +
+                               std::to_string(MAX_FUNCTION_DEPTH) +
+                               " levels deep");
+    depth++;
+    auto name = string();
+    auto arity = u8();
+    auto f = vm.allocate<FunctionInfo>(StringSlice(module), name, arity);
+    vm.temp_roots.push_back(Value(f));
+    f->max_registers = u32();
+    auto len = u32();
+    expect(len);
+    f->bytecode.assign(ip, ip + len);
+    ip += len;
+    if (!is_little_endian())
+      swap_operands(f->bytecode);
+    for_each_module_variable(
+        f->bytecode, [](const Instruction &instruction, uint32_t offset) {
+          if (instruction.width != 4)
+            throw std::runtime_error("Module variable at offset " +
+                                     std::to_string(offset) +
+                                     " is not relocatable");
+        });
+    functions.push_back(f);
+    auto num_constants = u32();
+    for (uint32_t i = 0; i < num_constants; i++)
+      f->constants.push_back(value(true));
+    auto num_lines = u32();
+    for (uint32_t i = 0; i < num_lines; i++) {
+      auto offset = u32();
+      f->lines.push_back(LineInfo{offset, u32()});
+    }
+    auto num_upvalues = u32();
+    for (uint32_t i = 0; i < num_upvalues; i++) {
+      auto index = u32();
+      f->upvalues.push_back(UpvalueInfo{index, u8() != 0});
+    }
+    auto num_handlers = u32();
+    for (uint32_t i = 0; i < num_handlers; i++) {
+      ExceptionHandler handler;
+      handler.try_begin = u32();
+      handler.try_end = u32();
+      handler.error_reg = u32();
+      handler.catch_begin = u32();
+      f->exception_handlers.push_back(handler);
+    }
+    auto num_jump_tables = u32();
+    for (uint32_t i = 0; i < num_jump_tables; i++) {
+      f->jump_tables.push_back({});
+      auto size = u32();
+      for (uint32_t j = 0; j < size; j++) {
+        auto key = value(false);
+        f->jump_tables.back().insert({key, u32()});
+      }
+    }
+    vm.temp_roots.pop_back();
+    depth--;
+    return f;
+  }
+
+  void finish() {
+    if (ip != end)
+      throw std::runtime_error("Unexpected data after bytecode");
+  }
+};
+
+FunctionInfoWriter VM::load_bytecode(StringSlice module,
+                                     rust::Slice<const uint8_t> bytes) const {
+  auto this_ = const_cast<VM *>(this);
+  auto roots = this_->temp_roots.size();
+  try {
+    BytecodeReader reader(*this_, module, bytes);
+    reader.header();
+    auto f = reader.function();
+    reader.finish();
+    verify(*f, reader.module_variable_positions());
+    this_->temp_roots.push_back(Value(f));
+    create_module_with_prelude(module);
+    reader.link();
+    this_->temp_roots.pop_back();
+    return FunctionInfoWriter(this_->make_handle(f), this);
+  } catch (...) {
+    this_->temp_roots.resize(roots);
+    throw;
+  }
+}
+} // namespace neptune_vm
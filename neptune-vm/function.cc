@@ -86,7 +86,7 @@ VMStatus FunctionInfoWriter::run() {
   auto function = vm->make_function(nullptr, hf->object);
   function->num_upvalues = 0;
   vm->temp_roots.push_back(Value(function));
-  auto task = vm->allocate<Task>(function);
+  auto task = vm->allocate<Task>(function, vm->options.initial_frames);
   vm->temp_roots.pop_back();
   vm->tasks_queue.push_back({task, Value::null(), false});
   vm->main_task = task;
@@ -301,6 +301,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
         break;
         CASE(StoreArrayUnchecked) << REG(uint16_t) << ' ' << READ(uint16_t);
         break;
+        CASE(StoreArray) << REG(uint16_t) << ' ' << READ(uint16_t);
+        break;
         CASE(LoadSubscript) << REG(uint16_t);
         break;
         CASE(NewMap) << READ(uint16_t) << ' ' << REG(uint16_t);
@@ -331,6 +333,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
         break;
         CASE(ForLoop) << READ(uint16_t) << ' ' << REG(uint16_t);
         break;
+        CASE(ForLoopChecked) << READ(uint16_t) << ' ' << REG(uint16_t);
+        break;
         CASE(MakeFunction) << f.constants[READ(uint16_t)];
         break;
         CASE(LoadUpvalue) << READ(uint16_t);
@@ -430,6 +434,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
         break;
         CASE(StoreArrayUnchecked) << REG(uint32_t) << ' ' << READ(uint32_t);
         break;
+        CASE(StoreArray) << REG(uint32_t) << ' ' << READ(uint32_t);
+        break;
         CASE(LoadSubscript) << REG(uint32_t);
         break;
         CASE(NewMap) << READ(uint32_t) << ' ' << REG(uint32_t);
@@ -460,6 +466,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
         break;
         CASE(ForLoop) << READ(uint32_t) << ' ' << REG(uint32_t);
         break;
+        CASE(ForLoopChecked) << READ(uint32_t) << ' ' << REG(uint32_t);
+        break;
         CASE(MakeFunction) << f.constants[READ(uint32_t)];
         break;
         CASE(LoadUpvalue) << READ(uint32_t);
@@ -567,6 +575,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
       break;
       CASE(StoreArrayUnchecked) << REG(uint8_t) << ' ' << READ(uint8_t);
       break;
+      CASE(StoreArray) << REG(uint8_t) << ' ' << READ(uint8_t);
+      break;
       CASE(LoadSubscript) << REG(uint8_t);
       break;
       CASE(NewMap) << READ(uint8_t) << ' ' << REG(uint8_t);
@@ -596,6 +606,8 @@ void disassemble(std::ostream &os, const FunctionInfo &f) {
       break;
       CASE(ForLoop) << READ(uint8_t) << ' ' << REG(uint8_t);
       break;
+      CASE(ForLoopChecked) << READ(uint8_t) << ' ' << REG(uint8_t);
+      break;
       CASE(MakeFunction) << f.constants[READ(uint8_t)];
       break;
       CASE(LoadUpvalue) << READ(uint8_t);
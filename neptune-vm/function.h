@@ -3,6 +3,7 @@
 #include "op.h"
 #include <cstdint>
 #include <memory>
+#include <unordered_set>
 #include <vector>
 
 namespace neptune_vm {
@@ -87,6 +88,7 @@ public:
                              uint32_t error_reg, uint32_t catch_begin);
   uint32_t jump_table();
   bool insert_in_jump_table(uint32_t jump_table, uint32_t offset);
+  rust::Vec<uint8_t> serialize() const;
   friend struct EFuncContext;
 };
 
@@ -110,4 +112,43 @@ public:
   Function() = delete;
 };
 void disassemble(std::ostream &os, const FunctionInfo &f);
+
+enum class OperandType : uint8_t {
+  Register,
+  // The first register of the arguments of a call
+  Arguments,
+  Constant,
+  Unsigned,
+  Signed,
+  SmallInt,
+  ModuleVariable,
+  Upvalue,
+  JumpOffset,
+  JumpTable,
+  ArgCount
+};
+
+struct Instruction {
+  Op op;
+  // 1, 2 or 4 depending on the Wide/ExtraWide prefix
+  uint8_t width;
+  uint8_t num_operands;
+  OperandType types[4];
+  // Offsets of the operands in the bytecode
+  uint32_t offsets[4];
+  // Signed operands are sign extended
+  uint32_t operands[4];
+  // Offset of the next instruction
+  uint32_t end;
+};
+
+const char *op_name(Op op);
+// Throws std::runtime_error if there is no valid instruction at offset
+Instruction decode_instruction(const vector<uint8_t> &bytecode,
+                               uint32_t offset);
+// Throws std::runtime_error describing the problem if running f could
+// lead to undefined behaviour. module_variables are the positions of the
+// module variables that f can use
+void verify(const FunctionInfo &f,
+            const std::unordered_set<uint32_t> &module_variables);
 } // namespace neptune_vm
@@ -59,10 +59,13 @@ handler(Not, {
 });
 handler(Return, {
   CLOSE(0);
+  TRACE(trace_return(task));
   task->frames.pop_back();
   if (unlikely(task->frames.empty())) {
     task->stack_top = task->stack.get();
     task->status = VMStatus::Success;
+    if (task == main_task || task == called_task)
+      return_value = accumulator;
     goto end;
   } else {
     auto frame = task->frames.back();
@@ -29,6 +29,14 @@ namespace native_builtins {
     return VMStatus::Error;                                                    \
   } while (0)
 
+// Thrown when check_heap_limit refuses an allocation. The error is thrown only
+// once
+#define THROW_OUT_OF_MEMORY(message)                                           \
+  do {                                                                         \
+    vm->out_of_memory = false;                                                 \
+    THROW("OutOfMemoryError", message);                                        \
+  } while (0)
+
 static VMStatus object_tostring(VM *vm, Value *args) {
   vm->return_value = vm->to_string(args[0]);
   return VMStatus::Success;
@@ -65,7 +73,10 @@ static VMStatus array_pop(VM *vm, Value *args) {
 }
 
 static VMStatus array_push(VM *vm, Value *args) {
-  args[0].as_ptr()->as<Array>()->inner.push_back(args[1]);
+  auto &arr = args[0].as_ptr()->as<Array>()->inner;
+  auto capacity = arr.capacity();
+  arr.push_back(args[1]);
+  vm->count_allocation((arr.capacity() - capacity) * sizeof(Value));
   vm->return_value = Value::null();
   return VMStatus::Success;
 }
@@ -82,7 +93,9 @@ static VMStatus array_insert(VM *vm, Value *args) {
     auto index = args[1].as_int();
     if (index < 0 || static_cast<size_t>(index) > arr.size())
       THROW("IndexError", "Array index out of range");
+    auto capacity = arr.capacity();
     arr.insert(arr.begin() + index, args[2]);
+    vm->count_allocation((arr.capacity() - capacity) * sizeof(Value));
     vm->return_value = Value::null();
     return VMStatus::Success;
   } else
@@ -153,8 +166,11 @@ static VMStatus string_find(VM *vm, Value *args) {
 static VMStatus string_replace(VM *vm, Value *args) {
   if (args[1].is_ptr() && args[1].as_ptr()->is<String>() && args[2].is_ptr() &&
       args[2].as_ptr()->is<String>()) {
-    vm->return_value = Value(args[0].as_ptr()->as<String>()->replace(
-        vm, args[1].as_ptr()->as<String>(), args[2].as_ptr()->as<String>()));
+    auto result = args[0].as_ptr()->as<String>()->replace(
+        vm, args[1].as_ptr()->as<String>(), args[2].as_ptr()->as<String>());
+    if (result == nullptr)
+      THROW_OUT_OF_MEMORY("The result of replace would exceed the heap limit");
+    vm->return_value = Value(result);
     return VMStatus::Success;
   } else
     THROW("TypeError",
@@ -166,6 +182,9 @@ static VMStatus array_construct(VM *vm, Value *args) {
   if (args[1].is_int()) {
     if (args[1].as_int() < 0)
       THROW("Error", "The array size must be non negative");
+    if (!vm->check_heap_limit(args[1].as_int() * sizeof(Value)))
+      THROW_OUT_OF_MEMORY("Cannot allocate an array of size "
+                          << args[1].as_int());
     vm->return_value = Value(
         vm->allocate<Array>(static_cast<uint32_t>(args[1].as_int()), args[2]));
     return VMStatus::Success;
@@ -387,7 +406,10 @@ static VMStatus gc(VM *vm, Value *) {
 
 static VMStatus _getModule(VM *vm, Value *args) {
   if (args[0].is_ptr() && args[0].as_ptr()->is<String>()) {
-    auto module = vm->get_module(StringSlice(*args[0].as_ptr()->as<String>()));
+    auto name = StringSlice(*args[0].as_ptr()->as<String>());
+    if (vm->is_module_hidden(name))
+      THROW("PermissionError", "Cannot import module " << name);
+    auto module = vm->get_module(name);
     if (module == nullptr)
       vm->return_value = Value::null();
     else
@@ -410,7 +432,8 @@ static VMStatus _getCallerModule(VM *vm, Value *) {
   }
 }
 
-static VMStatus ecall(VM *vm, Value *args) {
+// Used by the prelude, it ignores the efunc allow list
+static VMStatus _ecall(VM *vm, Value *args) {
   if (args[0].is_ptr() && args[0].as_ptr()->is<Symbol>()) {
     auto efunc_iter = vm->efuncs.find(args[0].as_ptr()->as<Symbol>());
     if (efunc_iter == vm->efuncs.end()) {
@@ -442,6 +465,15 @@ static VMStatus ecall(VM *vm, Value *args) {
   }
 }
 
+static VMStatus ecall(VM *vm, Value *args) {
+  if (args[0].is_ptr() && args[0].as_ptr()->is<Symbol>()) {
+    auto name = args[0].as_ptr()->as<Symbol>();
+    if (!vm->is_efunc_allowed(name))
+      THROW("PermissionError", "Cannot call EFunc " << StringSlice(*name));
+  }
+  return _ecall(vm, args);
+}
+
 static VMStatus generateStackTrace(VM *vm, Value *args) {
   if (args[0].is_int()) {
     if (args[0].as_int() < 0)
@@ -455,6 +487,19 @@ static VMStatus generateStackTrace(VM *vm, Value *args) {
   }
 }
 
+static VMStatus generateStackFrames(VM *vm, Value *args) {
+  if (args[0].is_int()) {
+    if (args[0].as_int() < 0)
+      THROW("Error", "The first argument must not be negative");
+    vm->return_value = vm->generate_stack_frames(
+        false, static_cast<uint32_t>(args[0].as_int()));
+    return VMStatus::Success;
+  } else {
+    THROW("TypeError",
+          "The first argument must be a Int, not " << args[0].type_string());
+  }
+}
+
 static VMStatus _extendClass(VM *vm, Value *args) {
   if (args[0].is_ptr() && args[0].as_ptr()->is<Class>() && args[1].is_ptr() &&
       args[1].as_ptr()->is<Class>()) {
@@ -586,7 +631,8 @@ static VMStatus suspendCurrentTask(VM *vm, Value *) {
 
 static VMStatus spawn(VM *vm, Value *args) {
   if (args[0].is_ptr() && args[0].as_ptr()->is<Function>()) {
-    Task *t = vm->allocate<Task>(args[0].as_ptr()->as<Function>());
+    Task *t = vm->allocate<Task>(args[0].as_ptr()->as<Function>(),
+                                 vm->options.initial_frames);
     vm->return_value = Value(t);
     vm->tasks_queue.push_back({t, Value::null(), false});
     vm->main_task->links.insert(t);
@@ -723,8 +769,15 @@ static VMStatus object_toDebugString(VM *vm, Value *args) {
   return VMStatus::Success;
 }
 #undef THROW
+#undef THROW_OUT_OF_MEMORY
 } // namespace native_builtins
 
+NativeFunction::NativeFunction(EFunc efunc, Class *class_, std::string name,
+                               std::string module_name, uint8_t arity)
+    : arity(arity),
+      inner(class_ == nullptr ? VM::efunc_function : VM::efunc_method),
+      name(name), module_name(module_name), efunc(efunc), class_(class_) {}
+
 void VM::declare_native_builtins() {
 #define DEFCLASS(Name)                                                         \
   builtin_classes.Name = allocate<Class>();                                    \
@@ -841,6 +894,8 @@ void VM::declare_native_builtins() {
   declare_native_function("vm", "ecall", true, 2, native_builtins::ecall);
   declare_native_function("vm", "generateStackTrace", true, 1,
                           native_builtins::generateStackTrace);
+  declare_native_function("vm", "generateStackFrames", true, 1,
+                          native_builtins::generateStackFrames);
   declare_native_function("vm", "suspendCurrentTask", true, 0,
                           native_builtins::suspendCurrentTask);
   declare_native_function("vm", "currentTask", true, 0,
@@ -859,6 +914,8 @@ void VM::declare_native_builtins() {
 
   declare_native_function("<prelude>", "_getModule", false, 1,
                           native_builtins::_getModule);
+  declare_native_function("<prelude>", "_ecall", false, 2,
+                          native_builtins::_ecall);
   declare_native_function("<prelude>", "_getCallerModule", false, 0,
                           native_builtins::_getCallerModule);
   declare_native_function("<prelude>", "_extendClass", false, 2,
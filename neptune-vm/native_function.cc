@@ -5,8 +5,8 @@
   return EFuncStatus::Underflow
 
 namespace neptune_vm {
-EFuncContext::EFuncContext(VM *vm, Value *arg, Task *task)
-    : vm(vm), task(task), arg(arg) {}
+EFuncContext::EFuncContext(VM *vm, Value *arg, Task *task, Resource *receiver)
+    : vm(vm), task(task), arg(arg), receiver(receiver) {}
 void EFuncContext::push(Value v) {
   if (task->stack_top == task->stack.get() + task->stack_size)
     arg = task->grow_stack(arg, 1);
@@ -50,7 +50,9 @@ EFuncStatus EFuncContext::push_to_array() {
   auto v = peek();
   if (v.is_ptr() && v.as_ptr()->is<Array>()) {
     auto &array = v.as_ptr()->as<Array>()->inner;
+    auto capacity = array.capacity();
     array.push_back(elem);
+    vm->count_allocation((array.capacity() - capacity) * sizeof(Value));
     return EFuncStatus::Ok;
   } else
     return EFuncStatus::TypeError;
@@ -175,6 +177,9 @@ bool EFuncContext::pop() {
     return true;
   }
 }
+size_t EFuncContext::stack_len() const {
+  return static_cast<size_t>(task->stack_top - arg);
+}
 EFuncStatus EFuncContext::get_array_length(size_t &len) const {
   CHECK_STACK_UNDERFLOW;
   auto v = peek();
@@ -218,6 +223,160 @@ EFuncStatus EFuncContext::get_object_property(StringSlice prop) {
     return EFuncStatus::TypeError;
 }
 
+EFuncStatus EFuncContext::get_entries() {
+  CHECK_STACK_UNDERFLOW;
+  auto v = peek();
+  if (v.is_ptr() && v.as_ptr()->is<Map>()) {
+    auto entries = vm->allocate<Array>();
+    push(Value(entries));
+    for (auto pair : v.as_ptr()->as<Map>()->inner) {
+      auto entry = vm->allocate<Array>();
+      entries->inner.push_back(Value(entry));
+      entry->inner.push_back(pair.first);
+      entry->inner.push_back(pair.second);
+    }
+    return EFuncStatus::Ok;
+  } else if (v.is_ptr() && v.as_ptr()->is<Instance>()) {
+    auto entries = vm->allocate<Array>();
+    push(Value(entries));
+    for (auto pair : v.as_ptr()->as<Instance>()->properties) {
+      auto entry = vm->allocate<Array>();
+      entries->inner.push_back(Value(entry));
+      entry->inner.push_back(
+          Value(vm->allocate<String>(StringSlice(*pair.first))));
+      entry->inner.push_back(pair.second);
+    }
+    return EFuncStatus::Ok;
+  } else
+    return EFuncStatus::TypeError;
+}
+
+EFuncStatus
+EFuncContext::get_object_keys(rust::Vec<rust::String> &keys) const {
+  CHECK_STACK_UNDERFLOW;
+  auto v = peek();
+  if (v.is_ptr() && v.as_ptr()->is<Instance>()) {
+    for (auto pair : v.as_ptr()->as<Instance>()->properties) {
+      StringSlice key = *pair.first;
+      keys.push_back(rust::String(key.data, key.len));
+    }
+    return EFuncStatus::Ok;
+  } else
+    return EFuncStatus::TypeError;
+}
+
+EFuncStatus EFuncContext::as_range(int32_t &start, int32_t &end) {
+  CHECK_STACK_UNDERFLOW;
+  Value v = pop_value();
+  if (v.is_ptr() && v.as_ptr()->is<Range>()) {
+    start = v.as_ptr()->as<Range>()->start;
+    end = v.as_ptr()->as<Range>()->end;
+    return EFuncStatus::Ok;
+  } else
+    return EFuncStatus::TypeError;
+}
+
+EFuncStatus EFuncContext::get_class_name(StringSlice &name) const {
+  CHECK_STACK_UNDERFLOW;
+  name = StringSlice(vm->get_class(peek())->name);
+  return EFuncStatus::Ok;
+}
+
+// Calls the function at the top of the stack with the arguments pushed by
+// push_args. The function is replaced by the value it returns or the exception
+// it throws
+EFuncStatus EFuncContext::call(EFuncCallback *push_args, Data *data,
+                               VMStatus &status) {
+  CHECK_STACK_UNDERFLOW;
+  auto callee_task =
+      vm->make_call_task(peek(), StringSlice("<call>"), push_args, data);
+  if (callee_task == nullptr) {
+    status = VMStatus::Error;
+  } else {
+    vm->temp_roots.push_back(Value(callee_task));
+    status = vm->run_nested(callee_task);
+    vm->temp_roots.pop_back();
+  }
+  pop_value();
+  push(vm->return_value);
+  vm->return_value = Value::null();
+  return EFuncStatus::Ok;
+}
+
+EFuncStatus EFuncContext::peek_is_null() const {
+  CHECK_STACK_UNDERFLOW;
+  if (peek().is_null())
+    return EFuncStatus::Ok;
+  else
+    return EFuncStatus::TypeError;
+}
+
+EFuncStatus EFuncContext::peek_type(ValueType &t) const {
+  CHECK_STACK_UNDERFLOW;
+  auto v = peek();
+  if (v.is_int())
+    t = ValueType::Int;
+  else if (v.is_float())
+    t = ValueType::Float;
+  else if (v.is_bool())
+    t = ValueType::Bool;
+  else if (v.is_null())
+    t = ValueType::Null;
+  else {
+    switch (v.as_ptr()->type) {
+    case Type::String:
+      t = ValueType::String;
+      break;
+    case Type::Symbol:
+      t = ValueType::Symbol;
+      break;
+    case Type::Array:
+      t = ValueType::Array;
+      break;
+    case Type::Map:
+      t = ValueType::Map;
+      break;
+    case Type::Instance:
+      t = ValueType::Object;
+      break;
+    case Type::Function:
+    case Type::NativeFunction:
+      t = ValueType::Function;
+      break;
+    case Type::Class:
+      t = ValueType::Class;
+      break;
+    case Type::Module:
+      t = ValueType::Module;
+      break;
+    case Type::Task:
+      t = ValueType::Task;
+      break;
+    case Type::Range:
+      t = ValueType::Range;
+      break;
+    case Type::ArrayIterator:
+      t = ValueType::ArrayIterator;
+      break;
+    case Type::MapIterator:
+      t = ValueType::MapIterator;
+      break;
+    case Type::StringIterator:
+      t = ValueType::StringIterator;
+      break;
+    case Type::Channel:
+      t = ValueType::Channel;
+      break;
+    case Type::Resource:
+      t = ValueType::Resource;
+      break;
+    default:
+      unreachable();
+    }
+  }
+  return EFuncStatus::Ok;
+}
+
 void EFuncContext::push_resource(Data *data, FreeDataCallback *free_data) {
   push(Value(vm->allocate<Resource>(data, free_data)));
 }
@@ -236,4 +395,26 @@ Data *EFuncContext::as_resource(EFuncStatus &status) {
     return nullptr;
   }
 }
+
+Data *EFuncContext::as_receiver(EFuncStatus &status) const {
+  if (receiver == nullptr) {
+    status = EFuncStatus::TypeError;
+    return nullptr;
+  }
+  status = EFuncStatus::Ok;
+  return receiver->data;
+}
+
+std::unique_ptr<ValueHandle> EFuncContext::as_value_handle(EFuncStatus &status) {
+  if (task->stack_top == arg) {
+    status = EFuncStatus::Underflow;
+    return nullptr;
+  }
+  status = EFuncStatus::Ok;
+  return std::unique_ptr<ValueHandle>(new ValueHandle(vm, pop_value()));
+}
+
+void EFuncContext::push_value_handle(const ValueHandle &handle) {
+  push(handle.value);
+}
 }; // namespace neptune_vm
@@ -1,25 +1,12 @@
 #pragma once
 #include "object.h"
+#include <memory>
 
 namespace neptune_vm {
 class VM;
 
 using NativeFunctionCallback = VMStatus(VM *vm, Value *args);
 
-class NativeFunction : public Object {
-  uint8_t arity;
-  NativeFunctionCallback *inner;
-
-public:
-  NativeFunction(NativeFunctionCallback *function, std::string name,
-                 std::string module_name, uint8_t arity)
-      : arity(arity), inner(function), name(name), module_name(module_name) {}
-  static constexpr Type type = Type::NativeFunction;
-  std::string name;
-  std::string module_name;
-  friend class VM;
-};
-
 enum class EFuncStatus : uint8_t {
   Ok,
   TypeError,
@@ -28,17 +15,45 @@ enum class EFuncStatus : uint8_t {
   PropertyError
 };
 
+enum class ValueType : uint8_t {
+  Int,
+  Float,
+  Bool,
+  Null,
+  String,
+  Symbol,
+  Array,
+  Map,
+  Object,
+  Function,
+  Class,
+  Module,
+  Task,
+  Range,
+  ArrayIterator,
+  MapIterator,
+  StringIterator,
+  Channel,
+  Resource
+};
+
 class Task;
 class TaskHandle;
+class ValueHandle;
+class Resource;
 
 using Data = void; // Can be any type
 using FreeDataCallback = void(Data *data);
 
+struct EFuncContext;
+using EFuncCallback = VMStatus(EFuncContext cx, Data *data);
+
 struct EFuncContext {
   VM *vm;
   Task *task;
   Value *arg;
-  EFuncContext(VM *vm, Value *arg, Task *task);
+  Resource *receiver; // The object a method was called on
+  EFuncContext(VM *vm, Value *arg, Task *task, Resource *receiver = nullptr);
   void push(Value v);
   void push_int(int32_t i);
   void push_float(double d);
@@ -63,19 +78,54 @@ struct EFuncContext {
   EFuncStatus get_array_length(size_t &len) const;
   EFuncStatus get_array_element(size_t pos);
   EFuncStatus get_object_property(StringSlice prop);
+  EFuncStatus get_entries();
+  EFuncStatus get_object_keys(rust::Vec<rust::String> &keys) const;
+  EFuncStatus as_range(int32_t &start, int32_t &end);
+  EFuncStatus get_class_name(StringSlice &name) const;
+  EFuncStatus call(EFuncCallback *push_args, Data *data, VMStatus &status);
+  EFuncStatus peek_is_null() const;
+  EFuncStatus peek_type(ValueType &t) const;
   void push_function(FunctionInfoWriter fw);
   bool pop();
+  size_t stack_len() const;
   Value pop_value();
   Value peek() const;
   const VM &get_vm() const { return *vm; }
   void push_resource(Data *data, FreeDataCallback *free_data);
   Data *as_resource(EFuncStatus &status);
+  Data *as_receiver(EFuncStatus &status) const;
+  std::unique_ptr<ValueHandle> as_value_handle(EFuncStatus &status);
+  void push_value_handle(const ValueHandle &handle);
 };
 
-using EFuncCallback = VMStatus(EFuncContext cx, Data *data);
 struct EFunc {
   EFuncCallback *callback;
   Data *data;
   FreeDataCallback *free_data;
 };
+
+class NativeFunction : public Object {
+  uint8_t arity;
+  NativeFunctionCallback *inner;
+
+public:
+  NativeFunction(NativeFunctionCallback *function, std::string name,
+                 std::string module_name, uint8_t arity)
+      : arity(arity), inner(function), name(name), module_name(module_name) {}
+  NativeFunction(EFunc efunc, Class *class_, std::string name,
+                 std::string module_name, uint8_t arity);
+  ~NativeFunction() {
+    if (efunc.free_data != nullptr)
+      efunc.free_data(efunc.data);
+  }
+  static constexpr Type type = Type::NativeFunction;
+  std::string name;
+  std::string module_name;
+  // Set for the methods of classes created by the embedder
+  EFunc efunc{nullptr, nullptr, nullptr};
+  Class *class_ = nullptr;
+  // Set for the construct method of classes created by the embedder
+  bool is_constructor = false;
+  friend class VM;
+};
 }; // namespace neptune_vm
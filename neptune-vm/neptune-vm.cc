@@ -1,3 +1,4 @@
+#include "bytecode.cc"
 #include "function.cc"
 #include "native_builtins.cc"
 #include "native_function.cc"
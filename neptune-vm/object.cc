@@ -5,6 +5,12 @@
 namespace neptune_vm {
 template <> size_t size(String *s) { return sizeof(String) + s->len; }
 template <> size_t size(Symbol *s) { return sizeof(Symbol) + s->len; }
+template <> size_t size(Array *a) {
+  return sizeof(Array) + a->inner.capacity() * sizeof(Value);
+}
+template <> size_t size(Map *m) {
+  return sizeof(Map) + m->inner.size() * 2 * sizeof(Value);
+}
 template <> size_t size(Function *f) {
   return sizeof(Function) + f->num_upvalues * sizeof(UpValue *);
 }
@@ -252,7 +258,10 @@ void operator<<(ValueFormatter vf, Object *obj) {
     vf.os << "<Channel>";
     break;
   case Type::Resource:
-    vf.os << "<Resource>";
+    if (obj->as<Resource>()->class_ != nullptr)
+      vf.os << '<' << obj->as<Resource>()->class_->name << '>';
+    else
+      vf.os << "<Resource>";
     break;
   default:
     unreachable();
@@ -326,11 +335,21 @@ size_t String::find(String *haystack, String *needle, size_t start) {
   return haystack->len;
 }
 
+// Returns nullptr if the result would exceed the heap limit
 String *String::replace(VM *vm, String *from, String *to) {
   if (from->len == 0)
     return this;
+  size_t offset = 0, pos, matches = 0;
+  while ((pos = String::find(this, from, offset)) != len) {
+    matches++;
+    offset = pos + from->len;
+  }
+  auto result_len = len - matches * from->len + matches * to->len;
+  if (!vm->check_heap_limit(sizeof(String) + result_len))
+    return nullptr;
   std::string result;
-  size_t offset = 0, pos;
+  result.reserve(result_len);
+  offset = 0;
   while (1) {
     pos = String::find(this, from, offset);
     result.insert(result.end(), data + offset, data + pos);
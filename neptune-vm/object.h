@@ -138,6 +138,8 @@ public:
   static constexpr Type type = Type::Array;
 };
 
+template <> size_t size(Array *a);
+
 struct ValueEmpty {
   bool is_empty(Value v);
   Value empty();
@@ -159,6 +161,8 @@ public:
   static constexpr Type type = Type::Map;
 };
 
+template <> size_t size(Map *m);
+
 struct ModuleVariable {
   uint32_t position;
   bool mutable_;
@@ -173,6 +177,8 @@ public:
   explicit Module(const std::string &name) : name(name) {}
   static constexpr Type type = Type::Module;
   friend class VM;
+  friend class BytecodeWriter;
+  friend class BytecodeVerifier;
 };
 class FunctionInfoWriter;
 class VM;
@@ -189,6 +195,9 @@ public:
   static constexpr Type type = Type::Class;
   friend class VM;
   friend class FunctionInfoWriter;
+  friend class BytecodeWriter;
+  friend class BytecodeReader;
+  friend class BytecodeVerifier;
 };
 
 class Instance : public Object {
@@ -244,9 +253,11 @@ struct BuiltinClasses {
   }
 };
 struct BuiltinSymbols {
-  Symbol *construct, *message, *stack, *task, *finished, *running, *killed;
+  Symbol *construct, *message, *stack, *frames, *task, *finished, *running,
+      *killed;
   BuiltinSymbols() {
-    construct = message = stack = task = finished = running = killed = nullptr;
+    construct = message = stack = frames = task = finished = running = killed =
+        nullptr;
   }
 };
 } // namespace neptune_vm
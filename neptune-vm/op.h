@@ -51,6 +51,7 @@
   OP(StoreUpvalue)                                                             \
   OP(LoadSubscript)                                                            \
   OP(StoreArrayUnchecked)                                                      \
+  OP(StoreArray)                                                               \
   OP(StoreSubscript)                                                           \
   OP(AddRegister)                                                              \
   OP(SubtractRegister)                                                         \
@@ -84,6 +85,7 @@
   OP(MakeClass)                                                                \
   OP(Range)                                                                    \
   OP(ForLoop)                                                                  \
+  OP(ForLoopChecked)                                                           \
   OP(Jump)                                                                     \
   OP(JumpIfFalseOrNull)                                                        \
   OP(JumpIfNotFalseOrNull)                                                     \
@@ -1,5 +1,6 @@
 #include "checked_arithmetic.cc"
 #include "neptune-vm.h"
+#include <algorithm>
 #include <cstring>
 
 #if defined(__GNUC__) || defined(__clang__)
@@ -13,13 +14,25 @@ constexpr uint32_t EXTRAWIDE_OFFSET = 2 * WIDE_OFFSET;
 #define WIDE(x) (static_cast<uint32_t>(x) + WIDE_OFFSET)
 #define EXTRAWIDE(x) (static_cast<uint32_t>(x) + EXTRAWIDE_OFFSET)
 
+// The trace hooks are only compiled with the tracing feature so that normal
+// builds do not check for them at every instruction
+#ifdef NEPTUNE_TRACING
+#define TRACE(hook)                                                            \
+  if (unlikely(tracing))                                                       \
+  hook
+#else
+#define TRACE(hook)
+#endif
+
 #ifdef COMPUTED_GOTO
 
 #define HANDLER(x) x##_handler
 #define WIDE_HANDLER(x) x##_wide_handler
 #define EXTRAWIDE_HANDLER(x) x##_extrawide_handler
 
-#define DISPATCH() goto *dispatch_table[READ(uint8_t)]
+#define DISPATCH()                                                             \
+  TRACE(trace_line(task->frames.back().f->function_info, ip));                 \
+  goto *dispatch_table[READ(uint8_t)]
 #define DISPATCH_WIDE() goto *dispatch_table[WIDE(READ(uint8_t))]
 #define DISPATCH_EXTRAWIDE() goto *dispatch_table[EXTRAWIDE(READ(uint8_t))]
 #define INTERPRET_LOOP DISPATCH();
@@ -35,6 +48,7 @@ constexpr uint32_t EXTRAWIDE_OFFSET = 2 * WIDE_OFFSET;
   switch (__op)
 
 #define DISPATCH()                                                             \
+  TRACE(trace_line(task->frames.back().f->function_info, ip));                 \
   __op = READ(uint8_t);                                                        \
   goto loop
 
@@ -53,15 +67,22 @@ constexpr uint32_t EXTRAWIDE_OFFSET = 2 * WIDE_OFFSET;
 
 namespace neptune_vm {
 
-VM::VM(rust::Box<UserData> user_data)
+VM::VM(rust::Box<UserData> user_data, VMOptions options)
     : user_data(std::move(user_data)), bytes_allocated(0), first_obj(nullptr),
-      threshhold(INITIAL_HEAP_SIZE), handles(nullptr),
-      last_native_function(nullptr), is_running(false), current_task(nullptr),
-      main_task(nullptr), return_value(Value::null()),
-      rng(std::random_device()()) {
+      first_task(nullptr), reloads_in_progress(0),
+      threshhold(options.initial_heap_size), handles(nullptr),
+      last_native_function(nullptr), interrupt(std::make_shared<Interrupt>()),
+      has_instruction_budget(false), instruction_budget(0),
+      max_heap_bytes(0), has_efunc_allow_list(false), options(options),
+      is_running(false), out_of_memory(false), has_cancelled_futures(false),
+      has_queued_channel_values(false), call_depth(0), called_task(nullptr),
+      tracing(false), traced_function(nullptr), traced_line(0),
+      current_task(nullptr), main_task(nullptr), return_value(Value::null()),
+      rng(options.has_seed ? options.seed : std::random_device()()) {
   builtin_symbols.construct = intern("construct");
   builtin_symbols.message = intern("message");
   builtin_symbols.stack = intern("stack");
+  builtin_symbols.frames = intern("frames");
   builtin_symbols.task = intern("task");
   builtin_symbols.finished = intern("finished");
   builtin_symbols.running = intern("running");
@@ -83,6 +104,22 @@ VM::VM(rust::Box<UserData> user_data)
     }                                                                          \
   } while (0)
 
+// Called at backward jumps and calls. It throws a pending OutOfMemoryError and
+// consumes one unit of the instruction budget
+#define CHECK_LIMITS()                                                         \
+  do {                                                                         \
+    if (unlikely(out_of_memory))                                               \
+      THROW("OutOfMemoryError",                                                \
+            "The heap limit of " << max_heap_bytes << " bytes was exceeded");  \
+    if (unlikely(interrupt->is_requested()))                                   \
+      THROW("TimeoutError", "The VM was interrupted");                         \
+    if (has_instruction_budget) {                                              \
+      if (unlikely(instruction_budget == 0))                                   \
+        THROW("TimeoutError", "The instruction budget was exhausted");         \
+      instruction_budget--;                                                    \
+    }                                                                          \
+  } while (0)
+
 VMStatus VM::run() {
   // return if main task finished/rust needs to resume/error
   if (is_running)
@@ -98,6 +135,8 @@ VMStatus VM::run() {
       return_value = main_task->uncaught_exception;
       main_task = nullptr;
       is_running = false;
+      interrupt->clear();
+      out_of_memory = false;
       return VMStatus::Error;
     } else if (main_task->status == VMStatus::Success) {
       for (auto link : main_task->links) {
@@ -106,10 +145,15 @@ VMStatus VM::run() {
       }
       main_task = nullptr;
       is_running = false;
+      interrupt->clear();
+      out_of_memory = false;
       return VMStatus::Success;
     }
   }
   is_running = false;
+  // An interrupt requested while the main task is suspended is kept so that it
+  // throws once the main task is resumed
+  out_of_memory = false;
   return VMStatus::Suspend;
 }
 
@@ -138,6 +182,7 @@ void VM::run(TaskQueueEntry entry) {
   const uint8_t *ip = frame.ip;
   Value *bp = frame.bp;
   Value *constants = frame.f->function_info->constants.data();
+  TRACE(trace_task_switch(task));
   if (entry.uncaught_exception) {
     if ((ip = throw_(accumulator)) != nullptr) {
       bp = task->frames.back().bp;
@@ -226,11 +271,13 @@ bool VM::add_module_variable(StringSlice module, StringSlice name,
     return false;
   else {
     auto module = module_iter->second;
-    if (!module->module_variables.insert(
-            {const_cast<VM *>(this)->intern(name),
-             ModuleVariable{static_cast<uint32_t>(module_variables.size()),
-                            mutable_, exported}}))
+    // insert() would overwrite the position of an existing variable
+    if (module->module_variables.find(name) != module->module_variables.end())
       return false;
+    module->module_variables.insert(
+        {const_cast<VM *>(this)->intern(name),
+         ModuleVariable{static_cast<uint32_t>(module_variables.size()),
+                        mutable_, exported}});
     module_variables.push_back(Value::null());
     return true;
   }
@@ -247,8 +294,8 @@ ModuleVariable VM::get_module_variable(StringSlice module_name,
     throw std::runtime_error("No such module variable");
   return it->second;
 }
-std::unique_ptr<VM> new_vm(rust::Box<UserData> user_data) {
-  return std::unique_ptr<VM>{new VM(std::move(user_data))};
+std::unique_ptr<VM> new_vm(rust::Box<UserData> user_data, VMOptions options) {
+  return std::unique_ptr<VM>{new VM(std::move(user_data), options)};
 }
 
 FunctionInfoWriter VM::new_function_info(StringSlice module, StringSlice name,
@@ -281,16 +328,36 @@ template <> String *VM::allocate<String, const char *>(const char *s) {
 }
 
 template <typename O> O *VM::manage(O *t) {
-  if (STRESS_GC || bytes_allocated > threshhold)
+  if (options.stress_gc || bytes_allocated > threshhold)
     collect();
   static_assert(std::is_base_of<Object, O>::value,
                 "O must be a descendant of Object");
+  check_heap_limit(size(t));
   bytes_allocated += size(t);
   auto o = reinterpret_cast<Object *>(t);
   o->type = O::type;
   o->is_dark = false;
   o->next = first_obj;
   first_obj = o;
+  switch (o->type) {
+  case Type::Task: {
+    auto task = o->as<Task>();
+    task->previous_task = nullptr;
+    task->next_task = first_task;
+    if (first_task != nullptr)
+      first_task->previous_task = task;
+    first_task = task;
+    break;
+  }
+  case Type::Instance:
+  case Type::Class:
+  case Type::Function:
+    if (reloads_in_progress != 0)
+      reload_objects.push_back(o);
+    break;
+  default:
+    break;
+  }
   return t;
 }
 
@@ -338,7 +405,7 @@ Symbol *VM::intern(StringSlice s) {
 }
 
 void VM::release(Object *o) {
-  if (DEBUG_GC)
+  if (options.debug_gc)
     std::cout << "Freeing: " << *o << std::endl;
   // todo change this when more types are added
   switch (o->type) {
@@ -377,7 +444,14 @@ void VM::release(Object *o) {
     break;
   }
   case Type::Task: {
-    delete o->as<Task>();
+    auto task = o->as<Task>();
+    if (task->previous_task != nullptr)
+      task->previous_task->next_task = task->next_task;
+    else
+      first_task = task->next_task;
+    if (task->next_task != nullptr)
+      task->next_task->previous_task = task->previous_task;
+    delete task;
     break;
   }
   case Type::Instance: {
@@ -415,14 +489,8 @@ void VM::release(Object *o) {
 }
 
 VM::~VM() {
-  if (DEBUG_GC)
+  if (options.debug_gc)
     std::cout << "VM destructor:" << std::endl;
-  while (handles != nullptr) {
-    auto old = handles;
-    handles = handles->next;
-    delete old;
-  }
-
   while (first_obj != nullptr) {
     auto old = first_obj;
     first_obj = first_obj->next;
@@ -431,6 +499,13 @@ VM::~VM() {
 
   for (auto efunc : efuncs)
     efunc.second.free_data(efunc.second.data);
+
+  // Handles are deleted last because resources and efuncs may release them
+  while (handles != nullptr) {
+    auto old = handles;
+    handles = handles->next;
+    delete old;
+  }
 }
 
 Value VM::to_string(Value val) {
@@ -476,7 +551,7 @@ Value VM::to_string(Value val) {
 }
 
 void VM::collect() {
-  if (DEBUG_GC)
+  if (options.debug_gc)
     std::cout << "Starting GC\nBytes allocated before: " << bytes_allocated
               << std::endl;
   bytes_allocated = 0;
@@ -505,6 +580,7 @@ void VM::collect() {
     mark(builtin_symbols.construct);
     mark(builtin_symbols.message);
     mark(builtin_symbols.stack);
+    mark(builtin_symbols.frames);
     mark(builtin_symbols.task);
     mark(builtin_symbols.finished);
     mark(builtin_symbols.running);
@@ -519,6 +595,8 @@ void VM::collect() {
   for (auto root : temp_roots)
     if (root.is_ptr())
       mark(root.as_ptr());
+  for (auto o : reload_objects)
+    mark(o);
   for (auto v : module_variables) {
     if (v.is_ptr())
       mark(v.as_ptr());
@@ -532,8 +610,15 @@ void VM::collect() {
   mark(last_native_function);
   mark(current_task);
   mark(main_task);
+  mark(called_task);
   for (auto efunc : efuncs)
     mark(efunc.first);
+  for (auto module : hidden_modules)
+    mark(module);
+  for (auto efunc : allowed_efuncs)
+    mark(efunc);
+  for (auto efunc : internal_efuncs)
+    mark(efunc);
   for (auto entry : tasks_queue) {
     mark(entry.task);
     if (entry.accumulator.is_ptr())
@@ -545,7 +630,7 @@ void VM::collect() {
     trace(o);
   }
 
-  threshhold = bytes_allocated * HEAP_GROWTH_FACTOR;
+  threshhold = bytes_allocated * options.heap_growth_factor;
   // Sweep white objects
   Object **obj = &first_obj;
   while (*obj != nullptr) {
@@ -558,7 +643,7 @@ void VM::collect() {
       obj = &(*obj)->next;
     }
   }
-  if (DEBUG_GC)
+  if (options.debug_gc)
     std::cout << "Bytes allocated after: " << bytes_allocated << std::endl;
 }
 
@@ -578,7 +663,7 @@ void VM::trace(Object *o) {
       if (v.is_ptr())
         mark(v.as_ptr());
     }
-    bytes_allocated += sizeof(Array);
+    bytes_allocated += size(o->as<Array>());
     break;
   case Type::Map:
     for (auto pair : o->as<Map>()->inner) {
@@ -587,7 +672,7 @@ void VM::trace(Object *o) {
       if (pair.second.is_ptr())
         mark(pair.second.as_ptr());
     }
-    bytes_allocated += sizeof(Map);
+    bytes_allocated += size(o->as<Map>());
     break;
   case Type::FunctionInfo:
     for (auto constant : o->as<FunctionInfo>()->constants) {
@@ -622,6 +707,8 @@ void VM::trace(Object *o) {
     break;
   case Type::NativeFunction:
     bytes_allocated += sizeof(NativeFunction);
+    if (o->as<NativeFunction>()->class_ != nullptr)
+      mark(o->as<NativeFunction>()->class_);
     break;
   case Type::Module:
     bytes_allocated += sizeof(Module);
@@ -696,6 +783,8 @@ void VM::trace(Object *o) {
     break;
   case Type::Resource:
     bytes_allocated += sizeof(Resource);
+    if (o->as<Resource>()->class_ != nullptr)
+      mark(o->as<Resource>()->class_);
     break;
   default:
     unreachable();
@@ -720,37 +809,121 @@ static uint32_t get_line_number(FunctionInfo *f, const uint8_t *ip) {
   }
 }
 
-std::string VM::generate_stack_trace(bool include_native_function,
-                                     uint32_t depth) {
-  std::ostringstream os;
+vector<StackTraceFrame>
+VM::capture_stack_trace(bool include_native_function, uint32_t depth) {
+  vector<StackTraceFrame> frames;
   if (include_native_function && last_native_function != nullptr) {
-    os << "at " << last_native_function->name << " ("
-       << last_native_function->module_name << ")\n";
+    frames.push_back(StackTraceFrame{last_native_function->name,
+                                     last_native_function->module_name, 0});
     last_native_function = nullptr;
   }
   if (depth > current_task->frames.size())
-    return "";
+    return {};
   for (auto frame = current_task->frames.rbegin() + depth;
        frame != current_task->frames.rend(); frame++) {
-    os << "at " << frame->f->function_info->name << " ("
-       << frame->f->function_info->module << ':'
-       << get_line_number(frame->f->function_info, frame->ip - 1) << ")\n";
+    // Functions without line information like the one created by call() are
+    // not shown
+    if (frame->f->function_info->lines.empty())
+      continue;
+    frames.push_back(StackTraceFrame{
+        frame->f->function_info->name, frame->f->function_info->module,
+        get_line_number(frame->f->function_info, frame->ip - 1)});
   }
-  auto s = os.str();
-  if (s.back() == '\n')
-    s.pop_back();
-  return s;
+  return frames;
+}
+
+static std::string format_stack_trace(const vector<StackTraceFrame> &frames) {
+  std::ostringstream os;
+  for (auto &frame : frames) {
+    if (&frame != &frames.front())
+      os << '\n';
+    os << "at " << frame.function << " (" << frame.module;
+    if (frame.line != 0)
+      os << ':' << frame.line;
+    os << ')';
+  }
+  return os.str();
+}
+
+// Converts frames to an array of objects with the properties module, function
+// and line so that they can be read without parsing the stack trace
+Value VM::stack_frames_array(const vector<StackTraceFrame> &frames) {
+  auto array = allocate<Array>();
+  temp_roots.push_back(Value(array));
+  auto module = intern("module");
+  temp_roots.push_back(Value(module));
+  auto function = intern("function");
+  temp_roots.push_back(Value(function));
+  auto line = intern("line");
+  temp_roots.push_back(Value(line));
+  for (auto &frame : frames) {
+    auto object = allocate<Instance>();
+    object->class_ = builtin_classes.Object;
+    array->inner.push_back(Value(object));
+    object->properties.insert(
+        {module, Value(allocate<String>(frame.module))});
+    object->properties.insert(
+        {function, Value(allocate<String>(frame.function))});
+    object->properties.insert(
+        {line, frame.line == 0 ? Value::null()
+                               : Value(static_cast<int32_t>(frame.line))});
+  }
+  temp_roots.resize(temp_roots.size() - 4);
+  return Value(array);
+}
+
+// Checks that v has the shape of the arrays created by stack_frames_array as
+// the frames property of an error can be replaced by the script
+bool VM::is_stack_frames_array(Value v) {
+  if (!v.is_ptr() || !v.as_ptr()->is<Array>())
+    return false;
+  auto is_string = [](Value v) {
+    return v.is_ptr() && v.as_ptr()->is<String>();
+  };
+  for (auto frame : v.as_ptr()->as<Array>()->inner) {
+    if (!frame.is_ptr() || !frame.as_ptr()->is<Instance>())
+      return false;
+    auto &properties = frame.as_ptr()->as<Instance>()->properties;
+    auto module = properties.find(intern("module"));
+    auto function = properties.find(intern("function"));
+    auto line = properties.find(intern("line"));
+    if (module == properties.end() || !is_string(module->second) ||
+        function == properties.end() || !is_string(function->second) ||
+        line == properties.end() ||
+        !(line->second.is_null() ||
+          (line->second.is_int() && line->second.as_int() > 0)))
+      return false;
+  }
+  return true;
+}
+
+std::string VM::generate_stack_trace(bool include_native_function,
+                                     uint32_t depth) {
+  return format_stack_trace(
+      capture_stack_trace(include_native_function, depth));
+}
+
+Value VM::generate_stack_frames(bool include_native_function, uint32_t depth) {
+  return stack_frames_array(
+      capture_stack_trace(include_native_function, depth));
 }
 
 const uint8_t *VM::throw_(const uint8_t *ip, const char *type) {
   auto message = throw_message.str();
   throw_message.str("");
   current_task->frames.back().ip = ip;
-  return throw_(create_error(type, message));
+  auto error = create_error(type, message);
+  // The objects that filled the heap are usually unreachable once the error is
+  // caught, so it is thrown only once. A pending OutOfMemoryError is kept if
+  // another error is thrown first
+  if (strcmp(type, "OutOfMemoryError") == 0)
+    out_of_memory = false;
+  return throw_(error);
 }
 
 const uint8_t *VM::throw_(Value v) {
   auto task = current_task;
+  TRACE(trace_exception(v));
   do {
     auto frame = task->frames.back();
     auto bytecode = frame.f->function_info->bytecode.data();
@@ -763,10 +936,13 @@ const uint8_t *VM::throw_(Value v) {
         CLOSE(handler.error_reg);
         bp[handler.error_reg] = v;
         task->stack_top = frame.f->function_info->max_registers + bp;
+        // The line of the catch block is reported
+        traced_function = nullptr;
         return bytecode + handler.catch_begin;
       }
     }
     CLOSE(0);
+    TRACE(trace_return(task));
     task->frames.pop_back();
   } while (!task->frames.empty());
   task->stack_top = task->stack.get();
@@ -902,6 +1078,8 @@ Class *VM::get_class(Value v) const {
     case Type::Channel:
       return builtin_classes.Channel;
     case Type::Resource:
+      if (o->as<Resource>()->class_ != nullptr)
+        return o->as<Resource>()->class_;
       return builtin_classes.Resource;
     case Type::Instance:
       return o->as<Instance>()->class_;
@@ -968,10 +1146,12 @@ Value VM::create_error(StringSlice module, StringSlice type,
       temp_roots.push_back(Value(error));
       error->properties.insert(
           {builtin_symbols.message, Value(allocate<String>(message))});
-      auto stack_trace = generate_stack_trace(true, 0);
+      auto frames = capture_stack_trace(true, 0);
       error->properties.insert(
           {builtin_symbols.stack,
-           Value(allocate<String>(std::move(stack_trace)))});
+           Value(allocate<String>(format_stack_trace(frames)))});
+      error->properties.insert(
+          {builtin_symbols.frames, stack_frames_array(frames)});
       error->properties.insert({builtin_symbols.task, Value(current_task)});
       temp_roots.pop_back();
       return Value(error);
@@ -991,70 +1171,107 @@ static bool is_descendant(Class *base, Class *c) {
     return is_descendant(base, c->super);
 }
 
-std::string VM::report_error(Value error) {
+// Calls callback with an object describing the uncaught exception in
+// return_value. return_value is reset to null
+void VM::with_uncaught_exception(EFuncCallback *callback, Data *data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto error = return_value;
+  this_->return_value = Value::null();
   auto error_class_val =
       module_variables[get_module_variable("<prelude>", "Error").position];
-  if (error_class_val.is_ptr() && error_class_val.as_ptr()->is<Class>()) {
-    auto error_class = error_class_val.as_ptr()->as<Class>();
-    if (error_class->is_native)
-      throw std::runtime_error("Expect Error class to not be native");
-    auto class_ = get_class(error);
-    if (is_descendant(error_class, class_)) {
-      std::ostringstream os;
-      auto error_object = error.as_ptr()->as<Instance>();
-      auto task_iter = error_object->properties.find(builtin_symbols.task);
-      if (task_iter != error_object->properties.end()) {
-        os << "In " << task_iter->second << " ";
-      }
-      os << class_->name << ": ";
-      auto message_iter =
-          error_object->properties.find(builtin_symbols.message);
-      if (message_iter != error_object->properties.end()) {
-        auto message = message_iter->second;
-        if (message.is_ptr() && message.as_ptr()->is<String>())
-          os << StringSlice(*message.as_ptr()->as<String>());
-        else
-          os << message;
-      }
-      os << '\n';
-      auto stack_iter = error_object->properties.find(builtin_symbols.stack);
-      if (stack_iter != error_object->properties.end()) {
-        auto stack = stack_iter->second;
-        if (stack.is_ptr() && stack.as_ptr()->is<String>())
-          os << StringSlice(*stack.as_ptr()->as<String>());
-        else
-          os << stack;
-      }
-      return os.str();
-    } else {
-      std::ostringstream os;
-      os << error;
-      return os.str();
+  if (!error_class_val.is_ptr() || !error_class_val.as_ptr()->is<Class>())
+    throw std::runtime_error("Expect Error to be a class");
+  auto error_class = error_class_val.as_ptr()->as<Class>();
+  if (error_class->is_native)
+    throw std::runtime_error("Expect Error class to not be native");
+  auto class_ = get_class(error);
+  auto is_error = is_descendant(error_class, class_);
+
+  this_->temp_roots.push_back(error);
+  auto info = this_->allocate<Instance>();
+  info->class_ = builtin_classes.Object;
+  this_->temp_roots.push_back(Value(info));
+  auto set = [&](const char *property, Value v) {
+    this_->temp_roots.push_back(v);
+    info->properties.insert({this_->intern(property), v});
+    this_->temp_roots.pop_back();
+  };
+  // Strings are used as they are and other values are converted
+  auto as_string = [&](Value v) {
+    if (v.is_ptr() && v.as_ptr()->is<String>())
+      return v;
+    std::ostringstream os;
+    os << v;
+    return Value(this_->allocate<String>(os.str()));
+  };
+
+  set("value", error);
+  set("isError", Value(is_error));
+  set("class", Value(this_->allocate<String>(class_->name)));
+  set("task", Value::null());
+  if (is_error) {
+    auto &properties = error.as_ptr()->as<Instance>()->properties;
+    auto message_iter = properties.find(builtin_symbols.message);
+    if (message_iter != properties.end())
+      set("message", as_string(message_iter->second));
+    else
+      set("message", Value(this_->allocate<String>("")));
+    auto frames_iter = properties.find(builtin_symbols.frames);
+    if (frames_iter != properties.end() &&
+        this_->is_stack_frames_array(frames_iter->second))
+      set("frames", frames_iter->second);
+    else
+      set("frames", Value(this_->allocate<Array>()));
+    auto task_iter = properties.find(builtin_symbols.task);
+    if (task_iter != properties.end() && task_iter->second.is_ptr() &&
+        task_iter->second.as_ptr()->is<Task>()) {
+      auto name = task_iter->second.as_ptr()->as<Task>()->name;
+      if (name != nullptr)
+        set("task", Value(name));
     }
   } else {
-    throw std::runtime_error("Expect Error to be a class");
+    std::ostringstream os;
+    os << error;
+    set("message", Value(this_->allocate<String>(os.str())));
+    set("frames", Value(this_->allocate<Array>()));
   }
+  this_->temp_roots.pop_back();
+  this_->temp_roots.pop_back();
+  this_->with_value(Value(info), callback, data);
 }
 
-Task::Task(Function *f)
+Task::Task(Function *f, size_t initial_frames)
     : status(VMStatus::Suspend), uncaught_exception(Value(nullptr)),
       open_upvalues(nullptr), name(nullptr) {
+  frames.reserve(initial_frames);
   stack_size = f->function_info->max_registers;
   if (stack_size == 0)
     stack_size = 1;
   stack = std::unique_ptr<Value[]>(new Value[stack_size]);
-  stack_top = stack.get();
+  stack_top = stack.get() + f->function_info->max_registers;
   for (size_t i = 0; i < stack_size; i++)
-    stack[i] = Value(nullptr);
+    stack[i] = Value::null();
   frames.push_back(Frame{&stack[0], f, f->function_info->bytecode.data()});
 }
 
+// A task without any frames which is never run. Its stack is used to pass
+// values to rust outside an efunc
+Task::Task()
+    : status(VMStatus::Suspend), uncaught_exception(Value(nullptr)),
+      waiting_for_rust_future(false), open_upvalues(nullptr), stack_size(1),
+      name(nullptr) {
+  stack = std::unique_ptr<Value[]>(new Value[stack_size]);
+  stack_top = stack.get();
+  stack[0] = Value(nullptr);
+}
+
 void Channel::send(Value v, VM *vm) {
-  if (wait_list.empty())
+  while (!wait_list.empty() && wait_list.back()->status == VMStatus::Error)
+    wait_list.pop_back();
+  if (wait_list.empty()) {
     queue.push_back(v);
-  else {
-    while (wait_list.back()->status == VMStatus::Error)
-      wait_list.pop_back();
+    vm->has_queued_channel_values = true;
+  } else {
     vm->tasks_queue.push_back({wait_list.back(), v, false});
     wait_list.pop_back();
   }
@@ -1065,6 +1282,10 @@ void VM::kill(Task *task, Value uncaught_exception) {
     return;
   task->status = VMStatus::Error;
   task->uncaught_exception = uncaught_exception;
+  if (task->waiting_for_rust_future) {
+    task->waiting_for_rust_future = false;
+    has_cancelled_futures = true;
+  }
   for (auto link : task->links)
     kill(link, uncaught_exception);
   for (auto chan : task->monitors)
@@ -1072,7 +1293,8 @@ void VM::kill(Task *task, Value uncaught_exception) {
   main_task->links.erase(task);
 }
 
-rust::String VM::kill_main_task(StringSlice error, StringSlice message) const {
+// The error is stored in return_value
+void VM::kill_main_task(StringSlice error, StringSlice message) const {
   auto this_ = const_cast<VM *>(this);
   if (main_task == nullptr) {
     throw std::runtime_error("No main task is there");
@@ -1082,8 +1304,572 @@ rust::String VM::kill_main_task(StringSlice error, StringSlice message) const {
   this_->current_task = nullptr;
   this_->kill(main_task, err_val);
   this_->main_task = nullptr;
-  auto s = this_->report_error(err_val);
-  return rust::String(std::move(s));
+  this_->return_value = err_val;
+}
+
+// Returns whether a task waiting for a rust future was killed since the last
+// call
+bool VM::take_cancelled_futures() const {
+  auto this_ = const_cast<VM *>(this);
+  bool cancelled = has_cancelled_futures;
+  this_->has_cancelled_futures = false;
+  return cancelled;
+}
+
+// Returns whether a value was added to the queue of a channel since the last
+// call
+bool VM::take_queued_channel_values() const {
+  auto this_ = const_cast<VM *>(this);
+  bool queued = has_queued_channel_values;
+  this_->has_queued_channel_values = false;
+  return queued;
+}
+
+std::unique_ptr<ValueHandle> VM::create_channel() const {
+  auto this_ = const_cast<VM *>(this);
+  return std::unique_ptr<ValueHandle>(
+      new ValueHandle(this_, Value(this_->allocate<Channel>())));
+}
+
+// Whether a task that was not killed is waiting for a value from the channel
+bool VM::has_waiting_tasks(const ValueHandle &channel) const {
+  for (auto task : channel.value.as_ptr()->as<Channel>()->wait_list) {
+    if (task->status != VMStatus::Error)
+      return true;
+  }
+  return false;
+}
+
+// Sends the value pushed by push_value to the channel. Returns whether a task
+// was woken while no task is running, in which case run_tasks must be called.
+// Nothing is sent if push_value fails
+bool VM::send_to_channel(const ValueHandle &channel, EFuncCallback *push_value,
+                         Data *data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto task = this_->allocate<Task>();
+  this_->temp_roots.push_back(Value(task));
+  if (push_value(EFuncContext(this_, task->stack_top, task), data) ==
+      VMStatus::Error) {
+    this_->temp_roots.pop_back();
+    return false;
+  }
+  auto value = Value::null();
+  if (task->stack_top != task->stack.get())
+    value = *(task->stack_top - 1);
+  auto queued_tasks = tasks_queue.size();
+  channel.value.as_ptr()->as<Channel>()->send(value, this_);
+  this_->temp_roots.pop_back();
+  return current_task == nullptr && tasks_queue.size() != queued_tasks;
+}
+
+// Calls callback with the first value in the queue of the channel. Returns
+// false without calling it if the queue is empty
+bool VM::receive_from_channel(const ValueHandle &channel,
+                              EFuncCallback *callback, Data *data) const {
+  auto chan = channel.value.as_ptr()->as<Channel>();
+  if (chan->queue.empty())
+    return false;
+  auto value = chan->queue.front();
+  chan->queue.pop_front();
+  const_cast<VM *>(this)->with_value(value, callback, data);
+  return true;
+}
+
+// Runs the tasks woken by send_to_channel. The result is that of the main task
+VMStatus VM::run_tasks() const {
+  if (current_task != nullptr)
+    throw std::runtime_error("Cannot call run() while VM is already running");
+  if (main_task == nullptr)
+    return VMStatus::Suspend;
+  return const_cast<VM *>(this)->run();
+}
+
+VMStatus VM::call(StringSlice module, StringSlice name,
+                  EFuncCallback *push_args, Data *data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto callee = module_variables[get_module_variable(module, name).position];
+  return this_->call(callee, module, push_args, data);
+}
+
+VMStatus VM::call_value(const ValueHandle &callee, EFuncCallback *push_args,
+                        Data *data) const {
+  return const_cast<VM *>(this)->call(callee.value, StringSlice("<call>"),
+                                      push_args, data);
+}
+
+VMStatus VM::call(Value callee, StringSlice module, EFuncCallback *push_args,
+                  Data *data) {
+  if (is_running)
+    throw std::runtime_error("Cannot call run() while VM is already running");
+  auto task = make_call_task(callee, module, push_args, data);
+  if (task == nullptr)
+    return VMStatus::Error;
+  tasks_queue.push_back({task, Value::null(), false});
+  main_task = task;
+  return run();
+}
+
+// Returns a task that calls callee with the arguments pushed by push_args. If
+// there are too many arguments it returns nullptr and the error is stored in
+// return_value. It also returns nullptr if push_args fails
+Task *VM::make_call_task(Value callee, StringSlice module,
+                         EFuncCallback *push_args, Data *data) {
+  // The task runs a function which calls callee with the arguments pushed by
+  // push_args and returns its result. The number of arguments is patched
+  // after they are pushed
+  temp_roots.push_back(callee);
+  auto function_info = allocate<FunctionInfo>(module, StringSlice("<call>"), 0);
+  temp_roots.push_back(Value(function_info));
+  function_info->constants.push_back(callee);
+  function_info->bytecode = {
+      static_cast<uint8_t>(Op::LoadConstant), 0,
+      static_cast<uint8_t>(Op::Call),         0,
+      0,                                      static_cast<uint8_t>(Op::Return)};
+  function_info->max_registers = 0;
+  auto function = make_function(nullptr, function_info);
+  function->num_upvalues = 0;
+  temp_roots.push_back(Value(function));
+  auto task = allocate<Task>(function, options.initial_frames);
+  temp_roots.push_back(Value(task));
+  auto status = push_args(EFuncContext(this, task->stack_top, task), data);
+  temp_roots.resize(temp_roots.size() - 4);
+  if (status == VMStatus::Error)
+    return nullptr;
+  auto nargs = task->stack_top - task->stack.get();
+  if (nargs > std::numeric_limits<uint8_t>::max()) {
+    auto old_task = current_task;
+    current_task = task;
+    return_value = create_error("ArgumentError", "Too many arguments");
+    current_task = old_task;
+    return nullptr;
+  }
+  function_info->bytecode[4] = static_cast<uint8_t>(nargs);
+  function_info->max_registers = static_cast<uint32_t>(nargs);
+  return task;
+}
+
+// Runs task until it finishes while the current task is waiting for an efunc.
+// The value it returns or its uncaught exception is stored in return_value
+VMStatus VM::run_nested(Task *task) {
+  auto caller = current_task;
+  if (caller == nullptr || main_task == nullptr ||
+      call_depth == max_call_depth) {
+    current_task = task;
+    return_value = create_error(
+        "Error", call_depth == max_call_depth
+                     ? "Too many nested calls from efuncs"
+                     : "Functions can only be called from inside an efunc");
+    current_task = caller;
+    return VMStatus::Error;
+  }
+  temp_roots.push_back(Value(caller));
+  auto old_called_task = called_task;
+  called_task = task;
+  call_depth++;
+  run(TaskQueueEntry{task, Value::null(), false});
+  call_depth--;
+  called_task = old_called_task;
+  current_task = caller;
+  TRACE(trace_task_switch(caller));
+  if (task->status == VMStatus::Suspend) {
+    current_task = task;
+    kill(task, create_error("Error", "A function called by an efunc cannot "
+                                     "suspend its task"));
+    current_task = caller;
+  }
+  if (task->status == VMStatus::Error)
+    return_value = task->uncaught_exception;
+  temp_roots.pop_back();
+  return task->status;
+}
+
+void VM::with_return_value(EFuncCallback *callback, Data *data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto value = return_value;
+  this_->return_value = Value::null();
+  this_->with_value(value, callback, data);
+}
+
+void VM::with_value(Value v, EFuncCallback *callback, Data *data) {
+  temp_roots.push_back(v);
+  auto task = allocate<Task>();
+  temp_roots.push_back(Value(task));
+  EFuncContext cx(this, task->stack_top, task);
+  cx.push(v);
+  callback(cx, data);
+  temp_roots.resize(temp_roots.size() - 2);
+}
+
+void VM::with_module_variable(uint32_t position, EFuncCallback *callback,
+                              Data *data) const {
+  const_cast<VM *>(this)->with_value(module_variables[position], callback,
+                                     data);
+}
+
+void VM::set_module_variable(uint32_t position, EFuncCallback *push_value,
+                             Data *data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto task = this_->allocate<Task>();
+  this_->temp_roots.push_back(Value(task));
+  if (push_value(EFuncContext(this_, task->stack_top, task), data) !=
+          VMStatus::Error &&
+      task->stack_top != task->stack.get())
+    module_variables[position] = *(task->stack_top - 1);
+  this_->temp_roots.pop_back();
+}
+
+// Returns the names of the variables of module in the order they were
+// declared. Only the exported ones are returned if exported_only is set
+static rust::Vec<rust::String>
+variable_names(SymbolMap<ModuleVariable> &module_variables,
+               bool exported_only) {
+  std::vector<std::pair<uint32_t, Symbol *>> variables;
+  for (auto &pair : module_variables)
+    if (pair.second.exported || !exported_only)
+      variables.push_back({pair.second.position, pair.first});
+  std::sort(variables.begin(), variables.end());
+  rust::Vec<rust::String> names;
+  for (auto &pair : variables) {
+    StringSlice name = *pair.second;
+    names.push_back(rust::String(name.data, name.len));
+  }
+  return names;
+}
+
+rust::Vec<rust::String> VM::module_exports(StringSlice module_name) const {
+  auto module_iter = modules.find(module_name);
+  if (module_iter == modules.end())
+    throw std::runtime_error("No such module");
+  return variable_names(module_iter->second->module_variables, true);
+}
+
+rust::Vec<rust::String>
+VM::module_variable_names(StringSlice module_name) const {
+  auto module_iter = modules.find(module_name);
+  if (module_iter == modules.end())
+    throw std::runtime_error("No such module");
+  return variable_names(module_iter->second->module_variables, false);
+}
+
+// Declares a module variable or changes the flags of an existing one, keeping
+// its position
+void VM::redeclare_module_variable(StringSlice module, StringSlice name,
+                                   bool mutable_, bool exported) const {
+  if (add_module_variable(module, name, mutable_, exported))
+    return;
+  auto module_iter = modules.find(module);
+  if (module_iter == modules.end())
+    throw std::runtime_error("No such module");
+  auto &variable = module_iter->second->module_variables.find(name)->second;
+  variable.mutable_ = mutable_;
+  variable.exported = exported;
+}
+
+// Starts recording the objects that can refer to the classes declared while a
+// module is reloaded
+void VM::begin_reload() const {
+  const_cast<VM *>(this)->reloads_in_progress++;
+}
+
+void VM::end_reload() const {
+  auto this_ = const_cast<VM *>(this);
+  if (--this_->reloads_in_progress == 0)
+    this_->reload_objects.clear();
+}
+
+// Makes the function or class old behave like new_, so that existing
+// references to old use the new definition. Returns false if they are not
+// both functions without upvalues or both classes declared in Neptune
+bool VM::replace_definition(const ValueHandle &old,
+                            const ValueHandle &new_) const {
+  auto this_ = const_cast<VM *>(this);
+  if (!old.value.is_ptr() || !new_.value.is_ptr())
+    return false;
+  auto old_obj = old.value.as_ptr(), new_obj = new_.value.as_ptr();
+  if (old_obj == new_obj)
+    return false;
+  if (old_obj->is<Function>() && new_obj->is<Function>()) {
+    auto old_f = old_obj->as<Function>(), new_f = new_obj->as<Function>();
+    if (old_f->num_upvalues != 0 || new_f->num_upvalues != 0)
+      return false;
+    // The frames of a suspended task executing the function keep using its
+    // bytecode
+    for (auto task = this_->first_task; task != nullptr;
+         task = task->next_task) {
+      for (auto &frame : task->frames) {
+        if (frame.f == old_f)
+          return false;
+      }
+    }
+    old_f->function_info = new_f->function_info;
+    old_f->super_class = new_f->super_class;
+    return true;
+  } else if (old_obj->is<Class>() && new_obj->is<Class>()) {
+    auto old_class = old_obj->as<Class>(), new_class = new_obj->as<Class>();
+    if (old_class->is_native || new_class->is_native)
+      return false;
+    old_class->methods = new_class->methods;
+    old_class->super = new_class->super;
+    old_class->name = new_class->name;
+    // Objects created by the new source refer to the new class
+    for (auto o : this_->reload_objects) {
+      if (o->is<Instance>() && o->as<Instance>()->class_ == new_class)
+        o->as<Instance>()->class_ = old_class;
+      else if (o->is<Class>() && o->as<Class>()->super == new_class)
+        o->as<Class>()->super = old_class;
+      else if (o->is<Function>() && o->as<Function>()->super_class == new_class)
+        o->as<Function>()->super_class = old_class;
+    }
+    return true;
+  } else
+    return false;
+}
+
+bool VM::strict_equals(const ValueHandle &a, const ValueHandle &b) const {
+  return ValueStrictEquality{}(a.value, b.value);
+}
+
+// Calls the efunc of a method of a class created by the embedder. The
+// receiver is not part of the stack of the context
+VMStatus VM::efunc_method(VM *vm, Value *args) {
+  auto f = vm->last_native_function;
+  auto task = vm->current_task;
+  Resource *receiver = nullptr;
+  if (args[0].is_ptr() && args[0].as_ptr()->is<Resource>())
+    receiver = args[0].as_ptr()->as<Resource>();
+  task->stack_top = args + f->arity + 1;
+  VMStatus result = f->efunc.callback(
+      EFuncContext(vm, args + 1, task, receiver), f->efunc.data);
+  if (task->stack_top == args + 1)
+    vm->return_value = Value::null();
+  else
+    vm->return_value = *(task->stack_top - 1);
+  auto frame = task->frames.back();
+  task->stack_top = frame.bp + frame.f->function_info->max_registers;
+  // Constructors return a resource which becomes an instance of the class
+  if (result == VMStatus::Success && f->is_constructor &&
+      vm->return_value.is_ptr() && vm->return_value.as_ptr()->is<Resource>() &&
+      vm->return_value.as_ptr()->as<Resource>()->class_ == nullptr)
+    vm->return_value.as_ptr()->as<Resource>()->class_ = f->class_;
+  return result;
+}
+
+VMStatus VM::efunc_function(VM *vm, Value *args) {
+  auto f = vm->last_native_function;
+  auto task = vm->current_task;
+  task->stack_top = args + f->arity;
+  VMStatus result =
+      f->efunc.callback(EFuncContext(vm, args, task), f->efunc.data);
+  if (result == VMStatus::Suspend) {
+    task->waiting_for_rust_future = true;
+    return VMStatus::Suspend;
+  }
+  if (task->stack_top == args)
+    vm->return_value = Value::null();
+  else
+    vm->return_value = *(task->stack_top - 1);
+  auto frame = task->frames.back();
+  task->stack_top = frame.bp + frame.f->function_info->max_registers;
+  return result;
+}
+
+bool VM::create_function(StringSlice module, StringSlice name, uint8_t arity,
+                         EFuncCallback *callback, Data *data,
+                         FreeDataCallback *free_data) const {
+  if (!add_module_variable(module, name, false, true)) {
+    free_data(data);
+    return false;
+  }
+  auto f = const_cast<VM *>(this)->allocate<NativeFunction>(
+      EFunc{callback, data, free_data}, nullptr,
+      std::string(name.data, name.len), std::string(module.data, module.len),
+      arity);
+  module_variables[module_variables.size() - 1] = Value(f);
+  return true;
+}
+
+void VM::set_instruction_budget(uint64_t budget) const {
+  auto this_ = const_cast<VM *>(this);
+  this_->has_instruction_budget = true;
+  this_->instruction_budget = budget;
+}
+
+void VM::remove_instruction_budget() const {
+  const_cast<VM *>(this)->has_instruction_budget = false;
+}
+
+bool VM::get_instruction_budget(uint64_t &budget) const {
+  budget = instruction_budget;
+  return has_instruction_budget;
+}
+
+void VM::set_tracing(bool enabled) const {
+  auto this_ = const_cast<VM *>(this);
+  this_->tracing = enabled;
+  this_->traced_function = nullptr;
+}
+
+// Functions without line information like the one created by call() are not
+// traced
+void VM::trace_call(FunctionInfo *f) {
+  if (f->lines.empty())
+    return;
+  traced_function = nullptr;
+  trace_event(*user_data, TraceEventKind::Call, f->module, f->name, 0,
+              StringSlice(nullptr, 0));
+}
+
+// Called before the last frame of the task is popped
+void VM::trace_return(Task *task) {
+  auto f = task->frames.back().f->function_info;
+  if (f->lines.empty())
+    return;
+  trace_event(*user_data, TraceEventKind::Return, f->module, f->name, 0,
+              StringSlice(nullptr, 0));
+  // The caller continues on the line of the call, which is not reported again
+  if (task->frames.size() > 1) {
+    auto caller = task->frames[task->frames.size() - 2];
+    if (!caller.f->function_info->lines.empty()) {
+      traced_function = caller.f->function_info;
+      traced_line = get_line_number(traced_function, caller.ip - 1);
+    }
+  }
+}
+
+void VM::trace_line(FunctionInfo *f, const uint8_t *ip) {
+  if (f->lines.empty())
+    return;
+  auto line = get_line_number(f, ip);
+  if (f != traced_function || line != traced_line) {
+    traced_function = f;
+    traced_line = line;
+    trace_event(*user_data, TraceEventKind::Line, f->module, f->name, line,
+                StringSlice(nullptr, 0));
+  }
+}
+
+void VM::trace_exception(Value v) {
+  auto frame = current_task->frames.back();
+  auto f = frame.f->function_info;
+  auto line = f->lines.empty() ? 0 : get_line_number(f, frame.ip - 1);
+  trace_event(*user_data, TraceEventKind::Exception, f->module, f->name, line,
+              get_class(v)->name);
+}
+
+void VM::trace_task_switch(Task *task) {
+  auto frame = task->frames.back();
+  auto f = frame.f->function_info;
+  traced_function = nullptr;
+  trace_event(*user_data, TraceEventKind::TaskSwitch, f->module, f->name, 0,
+              task->name == nullptr ? StringSlice(nullptr, 0)
+                                    : StringSlice(*task->name));
+  // A task that has not started yet calls its function
+  if (task->frames.size() == 1 && frame.ip == f->bytecode.data())
+    trace_call(f);
+}
+
+void VM::set_max_heap_bytes(size_t bytes) const {
+  const_cast<VM *>(this)->max_heap_bytes = bytes;
+}
+
+// Returns false and sets out_of_memory if allocating extra more bytes would
+// exceed the heap limit even after a collection. Until the OutOfMemoryError is
+// thrown allocations do not collect again
+bool VM::check_heap_limit(size_t extra) {
+  if (max_heap_bytes == 0 || bytes_allocated + extra <= max_heap_bytes)
+    return true;
+  if (out_of_memory)
+    return false;
+  collect();
+  if (bytes_allocated + extra <= max_heap_bytes)
+    return true;
+  out_of_memory = true;
+  return false;
+}
+
+// Used for memory that is allocated by an existing object, like when an array
+// grows
+void VM::count_allocation(size_t bytes) {
+  check_heap_limit(bytes);
+  bytes_allocated += bytes;
+}
+
+void VM::hide_module(StringSlice module_name) const {
+  auto this_ = const_cast<VM *>(this);
+  if (hidden_modules.find(module_name) == hidden_modules.end())
+    this_->hidden_modules.insert(this_->allocate<String>(module_name));
+}
+
+bool VM::is_module_hidden(StringSlice module_name) const {
+  return hidden_modules.find(module_name) != hidden_modules.end();
+}
+
+// After this only the efuncs passed to allow_efunc can be called by ecall
+void VM::restrict_efuncs() const {
+  const_cast<VM *>(this)->has_efunc_allow_list = true;
+}
+
+void VM::allow_efunc(StringSlice name) const {
+  auto this_ = const_cast<VM *>(this);
+  this_->allowed_efuncs.insert(this_->intern(name));
+}
+
+// Internal efuncs can only be called by the prelude using _ecall
+void VM::make_efunc_internal(StringSlice name) const {
+  auto this_ = const_cast<VM *>(this);
+  this_->internal_efuncs.insert(this_->intern(name));
+}
+
+bool VM::is_efunc_allowed(Symbol *name) const {
+  if (internal_efuncs.find(name) != internal_efuncs.end())
+    return false;
+  return !has_efunc_allow_list ||
+         allowed_efuncs.find(name) != allowed_efuncs.end();
+}
+
+bool VM::create_class(StringSlice module, StringSlice name) const {
+  auto this_ = const_cast<VM *>(this);
+  if (!add_module_variable(module, name, false, true))
+    return false;
+  auto class_ = this_->allocate<Class>();
+  class_->name = std::string(name.data, name.len);
+  class_->is_native = true;
+  class_->super = builtin_classes.Resource;
+  module_variables[module_variables.size() - 1] = Value(class_);
+  return true;
+}
+
+void VM::add_class_method(uint32_t position, StringSlice method, uint8_t arity,
+                          EFuncCallback *callback, Data *data,
+                          FreeDataCallback *free_data) const {
+  auto this_ = const_cast<VM *>(this);
+  auto class_ = module_variables[position].as_ptr()->as<Class>();
+  auto method_sym = this_->intern(method);
+  this_->temp_roots.push_back(Value(method_sym));
+  auto f = this_->allocate<NativeFunction>(
+      EFunc{callback, data, free_data}, class_,
+      std::string(method.data, method.len), class_->name, arity);
+  f->is_constructor = method_sym == builtin_symbols.construct;
+  class_->methods.insert({method_sym, f});
+  this_->temp_roots.pop_back();
+}
+
+ValueHandle::ValueHandle(VM *vm, Value value) : value(value), vm(vm) {
+  if (value.is_ptr())
+    handle = vm->make_handle(value.as_ptr());
+  else
+    handle = nullptr;
+}
+
+void ValueHandle::release() {
+  if (handle != nullptr) {
+    vm->release(handle);
+    handle = nullptr;
+  }
+}
+
+std::unique_ptr<ValueHandle> ValueHandle::copy() const {
+  return std::unique_ptr<ValueHandle>(new ValueHandle(vm, value));
 }
 
 TaskHandle::TaskHandle(VM *vm, Task *task) {
@@ -1096,10 +1882,17 @@ void TaskHandle::release() {
   handle = nullptr;
 }
 
+bool TaskHandle::is_alive() const {
+  return handle->object->status == VMStatus::Suspend;
+}
+
 VMStatus TaskHandle::resume(EFuncCallback *callback, Data *data) {
   if (vm->is_running)
     throw std::runtime_error("Cannot call run() while VM is already running");
   auto task = handle->object;
+  // The future completed after the task was killed so nothing changes
+  if (task->status == VMStatus::Error)
+    return VMStatus::Suspend;
   if (!task->waiting_for_rust_future)
     return task->status;
   auto old_stack_top = task->stack_top - task->stack.get();
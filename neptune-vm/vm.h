@@ -2,6 +2,7 @@
 #include "hash_table.h"
 #include "native_function.h"
 #include "util.h"
+#include <atomic>
 #include <deque>
 #include <functional>
 #include <memory>
@@ -9,19 +10,44 @@
 #include <sstream>
 #include <string>
 
-constexpr size_t INITIAL_FRAMES = 4;
-constexpr unsigned int HEAP_GROWTH_FACTOR = 2;
-constexpr size_t INITIAL_HEAP_SIZE = 10 * 1024 * 1024;
-constexpr bool STRESS_GC = false;
-constexpr bool DEBUG_GC = false;
-
 namespace neptune_vm {
+// Passed by the embedder when the VM is created
+struct VMOptions {
+  size_t initial_heap_size;
+  uint32_t heap_growth_factor;
+  size_t initial_frames;
+  bool stress_gc;
+  bool debug_gc;
+  bool has_seed;
+  uint64_t seed;
+};
+
+enum class TraceEventKind : uint8_t {
+  Call,
+  Return,
+  Line,
+  Exception,
+  TaskSwitch
+};
+
+// Defined in rust. It passes the event to the trace hook of the VM
+void trace_event(const UserData &user_data, TraceEventKind kind,
+                 StringSlice module, StringSlice function, uint32_t line,
+                 StringSlice name) noexcept;
+
 struct Frame {
   Value *bp;
   Function *f;
   const uint8_t *ip;
 };
 
+// A function call in a stack trace. line is 0 for native functions
+struct StackTraceFrame {
+  StringSlice function;
+  StringSlice module;
+  uint32_t line;
+};
+
 class Task;
 
 class Channel : public Object {
@@ -46,11 +72,15 @@ public:
   String *name;
   HashSet<Task *, PointerHash<Task>, std::equal_to<Task *>, NullptrEmpty<Task>>
       links;
+  // Linked list of all tasks
+  Task *previous_task;
+  Task *next_task;
 
   static constexpr Type type = Type::Task;
   void close(Value *last);
   Value *grow_stack(Value *bp, size_t extra_needed);
-  explicit Task(Function *f);
+  Task(Function *f, size_t initial_frames);
+  Task();
 };
 
 struct TaskQueueEntry {
@@ -66,9 +96,38 @@ class TaskHandle {
 public:
   TaskHandle(VM *vm, Task *task);
   void release();
+  bool is_alive() const;
   VMStatus resume(EFuncCallback *callback, Data *data);
 };
 
+// A value that is rooted until it is released. Only objects need a handle
+class ValueHandle {
+  Handle<Object> *handle;
+  Value value;
+  VM *vm;
+
+public:
+  ValueHandle(VM *vm, Value value);
+  void release();
+  std::unique_ptr<ValueHandle> copy() const;
+  const VM &get_vm() const { return *vm; }
+  friend struct EFuncContext;
+  friend class VM;
+};
+
+// Set from another thread to stop the running code with a TimeoutError
+class Interrupt {
+  mutable std::atomic<bool> requested;
+
+public:
+  Interrupt() : requested(false) {}
+  void interrupt() const { requested.store(true, std::memory_order_relaxed); }
+  bool is_requested() const {
+    return requested.load(std::memory_order_relaxed);
+  }
+  void clear() const { requested.store(false, std::memory_order_relaxed); }
+};
+
 class Resource : public Object {
   Data *data;
   FreeDataCallback *free_data;
@@ -82,6 +141,8 @@ public:
       data = nullptr;
     }
   }
+  // Set if the resource is an instance of a class created by the embedder
+  Class *class_ = nullptr;
   static constexpr Type type = Type::Resource;
   friend class EFuncContext;
 };
@@ -96,16 +157,60 @@ private:
   size_t bytes_allocated;
   // Linked list of all objects
   Object *first_obj;
+  Task *first_task;
+  // The number of modules being reloaded
+  uint32_t reloads_in_progress;
+  // Instances, classes and functions allocated while a module is reloaded. They
+  // are the only objects that can refer to the classes declared by the new
+  // source
+  vector<Object *> reload_objects;
   size_t threshhold;
   HashSet<Symbol *, StringHasher, StringEquality, NullptrEmpty<Symbol>> symbols;
   Handle<Object> *handles;
   vector<Object *> greyobjects;
   std::ostringstream throw_message;
   NativeFunction *last_native_function;
+  std::shared_ptr<Interrupt> interrupt;
+  bool has_instruction_budget;
+  uint64_t instruction_budget;
+  // 0 if there is no limit
+  size_t max_heap_bytes;
+  HashSet<String *, StringHasher, StringEquality, NullptrEmpty<String>>
+      hidden_modules;
+  bool has_efunc_allow_list;
+  HashSet<Symbol *, StringHasher, StringEquality, NullptrEmpty<Symbol>>
+      allowed_efuncs;
+  // Efuncs used by the prelude that ecall cannot call even without an allow
+  // list
+  HashSet<Symbol *, StringHasher, StringEquality, NullptrEmpty<Symbol>>
+      internal_efuncs;
   template <typename O> O *manage(O *object);
 
 public:
+  const VMOptions options;
   bool is_running;
+  // Set when an allocation exceeded max_heap_bytes even after a collection.
+  // The error is thrown at the next safe point
+  bool out_of_memory;
+  // Set when a task waiting for a rust future is killed so that rust can drop
+  // the future
+  bool has_cancelled_futures;
+  // Set when a value is added to the queue of a channel so that rust can wake
+  // the channel receivers
+  bool has_queued_channel_values;
+  // The number of functions being called by efuncs using EFuncContext::call
+  uint32_t call_depth;
+  static constexpr uint32_t max_call_depth = 32;
+  // The innermost task run by EFuncContext::call. Its result is kept in
+  // return_value like the result of the main task
+  Task *called_task;
+  // Set when the embedder has a trace hook. It is only checked if the VM is
+  // built with NEPTUNE_TRACING
+  bool tracing;
+  // The function and line of the last line event, so that it is reported only
+  // when the line changes
+  FunctionInfo *traced_function;
+  uint32_t traced_line;
   Task *current_task;
   Task *main_task;
   BuiltinClasses builtin_classes;
@@ -132,8 +237,13 @@ public:
   void collect();
   void trace(Object *o);
   void mark(Object *o);
+  vector<StackTraceFrame> capture_stack_trace(bool include_native_function,
+                                              uint32_t depth);
+  Value stack_frames_array(const vector<StackTraceFrame> &frames);
+  bool is_stack_frames_array(Value v);
   std::string generate_stack_trace(bool include_native_function,
                                    uint32_t depth);
+  Value generate_stack_frames(bool include_native_function, uint32_t depth);
   const uint8_t *throw_(Value v);
   const uint8_t *throw_(const uint8_t *ip, const char *type);
   bool declare_native_function(std::string module, std::string name,
@@ -141,12 +251,7 @@ public:
                                NativeFunctionCallback *callback) const;
   void declare_native_builtins();
   Function *make_function(Value *bp, FunctionInfo *function_info);
-  rust::String get_result() const {
-    auto vm = const_cast<VM *>(this);
-    auto s = vm->report_error(return_value);
-    vm->return_value = Value::null();
-    return rust::String(std::move(s));
-  }
+  void with_uncaught_exception(EFuncCallback *callback, Data *data) const;
   bool module_exists(StringSlice module_name) const;
   void create_module(StringSlice module_name) const;
   void create_module_with_prelude(StringSlice module_name) const;
@@ -157,18 +262,80 @@ public:
   String *concat(String *s1, String *s2);
   Value create_error(StringSlice type, StringSlice message);
   Value create_error(StringSlice module, StringSlice type, StringSlice message);
-  std::string report_error(Value error);
   void kill(Task *task, Value uncaught_exception);
-  rust::String kill_main_task(StringSlice error, StringSlice message) const;
+  void kill_main_task(StringSlice error, StringSlice message) const;
+  bool take_cancelled_futures() const;
+  bool take_queued_channel_values() const;
+  std::unique_ptr<ValueHandle> create_channel() const;
+  bool has_waiting_tasks(const ValueHandle &channel) const;
+  bool send_to_channel(const ValueHandle &channel, EFuncCallback *push_value,
+                       Data *data) const;
+  bool receive_from_channel(const ValueHandle &channel, EFuncCallback *callback,
+                            Data *data) const;
+  VMStatus run_tasks() const;
+  VMStatus call(StringSlice module, StringSlice name, EFuncCallback *push_args,
+                Data *data) const;
+  VMStatus call_value(const ValueHandle &callee, EFuncCallback *push_args,
+                      Data *data) const;
+  VMStatus call(Value callee, StringSlice module, EFuncCallback *push_args,
+                Data *data);
+  Task *make_call_task(Value callee, StringSlice module,
+                       EFuncCallback *push_args, Data *data);
+  VMStatus run_nested(Task *task);
+  void with_return_value(EFuncCallback *callback, Data *data) const;
+  void with_value(Value v, EFuncCallback *callback, Data *data);
+  void with_module_variable(uint32_t position, EFuncCallback *callback,
+                            Data *data) const;
+  void set_module_variable(uint32_t position, EFuncCallback *push_value,
+                           Data *data) const;
+  rust::Vec<rust::String> module_exports(StringSlice module_name) const;
+  rust::Vec<rust::String> module_variable_names(StringSlice module_name) const;
+  void redeclare_module_variable(StringSlice module, StringSlice name,
+                                 bool mutable_, bool exported) const;
+  void begin_reload() const;
+  void end_reload() const;
+  bool replace_definition(const ValueHandle &old,
+                          const ValueHandle &new_) const;
+  bool strict_equals(const ValueHandle &a, const ValueHandle &b) const;
+  FunctionInfoWriter load_bytecode(StringSlice module,
+                                   rust::Slice<const uint8_t> bytes) const;
+  std::shared_ptr<Interrupt> get_interrupt() const { return interrupt; }
+  void set_instruction_budget(uint64_t budget) const;
+  void remove_instruction_budget() const;
+  bool get_instruction_budget(uint64_t &budget) const;
+  void set_tracing(bool enabled) const;
+  void trace_call(FunctionInfo *f);
+  void trace_return(Task *task);
+  void trace_line(FunctionInfo *f, const uint8_t *ip);
+  void trace_exception(Value v);
+  void trace_task_switch(Task *task);
+  void set_max_heap_bytes(size_t bytes) const;
+  bool check_heap_limit(size_t extra);
+  void count_allocation(size_t bytes);
+  void hide_module(StringSlice module_name) const;
+  bool is_module_hidden(StringSlice module_name) const;
+  void restrict_efuncs() const;
+  void allow_efunc(StringSlice name) const;
+  void make_efunc_internal(StringSlice name) const;
+  bool is_efunc_allowed(Symbol *name) const;
+  static VMStatus efunc_method(VM *vm, Value *args);
+  static VMStatus efunc_function(VM *vm, Value *args);
+  bool create_function(StringSlice module, StringSlice name, uint8_t arity,
+                       EFuncCallback *callback, Data *data,
+                       FreeDataCallback *free_data) const;
+  bool create_class(StringSlice module, StringSlice name) const;
+  void add_class_method(uint32_t position, StringSlice method, uint8_t arity,
+                        EFuncCallback *callback, Data *data,
+                        FreeDataCallback *free_data) const;
   TaskHandle get_current_task() const {
     return TaskHandle(const_cast<VM *>(this), current_task);
   }
   const UserData &get_user_data() const { return *user_data; }
-  VM(rust::Box<UserData> user_data);
+  VM(rust::Box<UserData> user_data, VMOptions options);
   ~VM();
 };
 
-std::unique_ptr<VM> new_vm(rust::Box<UserData> user_data);
+std::unique_ptr<VM> new_vm(rust::Box<UserData> user_data, VMOptions options);
 template <> String *VM::allocate<String, StringSlice>(StringSlice s);
 
 template <> String *VM::allocate<String, std::string>(std::string s);
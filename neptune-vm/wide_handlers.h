@@ -78,6 +78,11 @@ handler(ConcatRegister, {
   auto reg = READ(utype);
   if (likely(accumulator.is_ptr() && accumulator.as_ptr()->is<String>() &&
              bp[reg].is_ptr() && bp[reg].as_ptr()->is<String>())) {
+    auto len = bp[reg].as_ptr()->as<String>()->get_len() +
+               accumulator.as_ptr()->as<String>()->get_len();
+    if (unlikely(!check_heap_limit(sizeof(String) + len)))
+      THROW("OutOfMemoryError",
+            "Cannot allocate a string of " << len << " bytes");
     accumulator = Value(concat(bp[reg].as_ptr()->as<String>(),
                                accumulator.as_ptr()->as<String>()));
   } else {
@@ -98,6 +103,7 @@ handler(LesserThanOrEqual, COMPARE_OP_REGISTER(<=););
 uint8_t callop_actual_nargs, callop_nargs;
 uint32_t callop_offset;
 callop : {
+  CHECK_LIMITS();
   if (likely(accumulator.is_ptr())) {
     if (accumulator.as_ptr()->is<Function>()) {
       auto f = accumulator.as_ptr()->as<Function>();
@@ -116,10 +122,13 @@ callop : {
         bp = task->grow_stack(bp, f->function_info->max_registers);
       task->stack_top = bp + f->function_info->max_registers;
       ip = f->function_info->bytecode.data();
+      // Registers are null until they are stored so that loaded bytecode
+      // cannot read uninitialized values
       for (size_t i = callop_actual_nargs; i < f->function_info->max_registers;
            i++)
-        bp[i] = Value(nullptr);
+        bp[i] = Value::null();
       task->frames.push_back(Frame{bp, f, ip});
+      TRACE(trace_call(f->function_info));
     } else if (accumulator.as_ptr()->is<NativeFunction>()) {
       auto f = accumulator.as_ptr()->as<NativeFunction>();
       auto arity = f->arity;
@@ -380,6 +389,18 @@ handler(StoreArrayUnchecked, {
   array[index] = accumulator;
 });
 
+// Used instead of StoreArrayUnchecked by loaded bytecode because the verifier
+// does not know the types of registers
+handler(StoreArray, {
+  auto array = bp[READ(utype)];
+  auto index = READ(utype);
+  if (unlikely(!array.is_ptr() || !array.as_ptr()->is<Array>() ||
+               index >= array.as_ptr()->as<Array>()->inner.size()))
+    THROW("TypeError", "Expected an Array with more than " << index
+                                                           << " elements");
+  array.as_ptr()->as<Array>()->inner[index] = accumulator;
+});
+
 handler(StoreSubscript, {
   auto obj = bp[READ(utype)];
   auto subscript = bp[READ(utype)];
@@ -398,7 +419,9 @@ handler(StoreSubscript, {
       }
     } else if (obj.as_ptr()->is<Map>()) {
       auto m = obj.as_ptr()->as<Map>();
+      auto size = m->inner.size();
       m->inner.insert({subscript, accumulator});
+      count_allocation((m->inner.size() - size) * 2 * sizeof(Value));
     } else if (obj.as_ptr()->is<Instance>()) {
       if (subscript.is_ptr() && subscript.as_ptr()->is<Symbol>()) {
         obj.as_ptr()->as<Instance>()->properties.insert(
@@ -637,6 +660,7 @@ handler(ModInt, {
 
 handler(JumpBack, {
   auto offset = READ(utype);
+  CHECK_LIMITS();
   ip -= (offset + 1 + sizeof(utype) + header_size<utype>());
 });
 
@@ -646,6 +670,25 @@ handler(ForLoop, {
   uint32_t end = static_cast<uint32_t>(iter) + 1;
   bp[iter].inc();
   if (bp[iter].as_int() < bp[end].as_int()) {
+    CHECK_LIMITS();
+    ip -= (offset + 1 + 2 * sizeof(utype) + header_size<utype>());
+  }
+});
+
+// Used instead of ForLoop by loaded bytecode. BeginForLoop checks the types
+// but loaded bytecode can store anything in the registers afterwards
+handler(ForLoopChecked, {
+  auto offset = READ(utype);
+  auto iter = READ(utype);
+  uint32_t end = static_cast<uint32_t>(iter) + 1;
+  if (unlikely(!bp[iter].is_int() || !bp[end].is_int()))
+    THROW("TypeError",
+          "Expected Int and Int for the start and end of the range got "
+              << bp[iter].type_string() << " and " << bp[end].type_string()
+              << " instead");
+  if (static_cast<int64_t>(bp[iter].as_int()) + 1 < bp[end].as_int()) {
+    bp[iter].inc();
+    CHECK_LIMITS();
     ip -= (offset + 1 + 2 * sizeof(utype) + header_size<utype>());
   }
 });
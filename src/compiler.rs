@@ -10,13 +10,16 @@ use crate::vm::VM;
 use crate::CompileError;
 use crate::CompileResult;
 use crate::{parser::Expr, scanner::TokenType};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 
 pub struct Compiler<'vm> {
     module_name: String,
     errors: Vec<CompileError>,
     vm: &'vm VM,
+    relocatable: bool,
+    // The names declared so far when reloading a module
+    reloaded: Option<HashSet<String>>,
 }
 
 impl<'vm> Compiler<'vm> {
@@ -25,9 +28,24 @@ impl<'vm> Compiler<'vm> {
             vm,
             module_name,
             errors: vec![],
+            relocatable: false,
+            reloaded: None,
         }
     }
 
+    /// Allows redeclaring the existing variables of the module, which keep their positions
+    pub fn reload(mut self) -> Self {
+        self.reloaded = Some(HashSet::new());
+        self
+    }
+
+    /// Always encodes module variable positions in 4 bytes so that they can be
+    /// patched when the bytecode is loaded into another VM
+    pub fn relocatable(mut self) -> Self {
+        self.relocatable = true;
+        self
+    }
+
     pub fn exec(
         mut self,
         ast: Vec<Statement>,
@@ -89,12 +107,27 @@ impl<'vm> Compiler<'vm> {
                 line,
             })
         }
-        if !self.vm.add_module_variable(
-            self.module_name.as_str().into(),
-            name.into(),
-            mutable,
-            exported,
-        ) {
+        let declared = match &mut self.reloaded {
+            Some(reloaded) => {
+                reloaded.insert(name.to_string())
+                    && self
+                        .vm
+                        .redeclare_module_variable(
+                            self.module_name.as_str().into(),
+                            name.into(),
+                            mutable,
+                            exported,
+                        )
+                        .is_ok()
+            }
+            None => self.vm.add_module_variable(
+                self.module_name.as_str().into(),
+                name.into(),
+                mutable,
+                exported,
+            ),
+        };
+        if !declared {
             self.errors.push(CompileError {
                 message: format!("Cannot redeclare module variable {}", name),
                 line,
@@ -259,6 +292,16 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
         }
     }
 
+    fn write_module_variable(&mut self, op: Op, position: u32, line: u32) {
+        if self.compiler.as_ref().unwrap().relocatable {
+            self.write0(Op::ExtraWide, line);
+            self.bc_writer.write_u8(op.repr);
+            self.bc_writer.write_u32(position);
+        } else {
+            self.write1(op, position, line);
+        }
+    }
+
     fn write1_signed(&mut self, op: Op, i: i32, line: u32) {
         if let Ok(i) = i8::try_from(i) {
             self.write0(op, line);
@@ -630,7 +673,7 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
             let g = self.get_global(name).unwrap();
             let res = self.evaluate_expr(expr)?;
             self.store_in_accumulator(res, line);
-            self.write1(Op::StoreModuleVariable, g.position, line);
+            self.write_module_variable(Op::StoreModuleVariable, g.position, line);
         } else {
             if self.locals.last().unwrap().contains_key(name) {
                 return Err(CompileError {
@@ -655,7 +698,7 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
     ) -> CompileResult<()> {
         if self.bctype == BytecodeType::Script && self.locals.is_empty() {
             let g = self.get_global(name).unwrap();
-            self.write1(Op::StoreModuleVariable, g.position, line);
+            self.write_module_variable(Op::StoreModuleVariable, g.position, line);
         } else {
             if self.locals.last().unwrap().contains_key(name) {
                 return Err(CompileError {
@@ -1165,7 +1208,7 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
                             self.error(e);
                         }
                     } else {
-                        self.write1(
+                        self.write_module_variable(
                             Op::LoadModuleVariable,
                             self.get_global("Object").unwrap().position,
                             *line,
@@ -1519,7 +1562,7 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
                             message: format!("{} is not defined", name),
                             line: *line,
                         })?;
-                        self.write1(Op::LoadModuleVariable, global.position, *line);
+                        self.write_module_variable(Op::LoadModuleVariable, global.position, *line);
                         Ok(ExprResult::Accumulator)
                     }
                 },
@@ -2025,7 +2068,7 @@ impl<'c, 'vm> BytecodeCompiler<'c, 'vm> {
                             line: *line,
                         });
                     } else {
-                        self.write1(Op::StoreModuleVariable, global.position, *line);
+                        self.write_module_variable(Op::StoreModuleVariable, global.position, *line);
                     }
                 }
             }
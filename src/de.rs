@@ -0,0 +1,228 @@
+use crate::vm::ValueType;
+use crate::{EFuncContext, SerdeError};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+
+impl<'a> EFuncContext<'a> {
+    /// Pops the value at the top of the stack and deserializes it.
+    /// Objects and maps can be deserialized as structs or maps, arrays as sequences and
+    /// tuples and strings or symbols as unit variants. Other enum variants are deserialized
+    /// from an object with a single property named after the variant.
+    pub fn deserialize<T: DeserializeOwned>(&mut self) -> Result<T, SerdeError> {
+        T::deserialize(&mut Deserializer { cx: self })
+    }
+}
+
+pub struct Deserializer<'a, 'b> {
+    cx: &'b mut EFuncContext<'a>,
+}
+
+impl<'de, 'a, 'b, 'c> de::Deserializer<'de> for &'c mut Deserializer<'a, 'b> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.cx.value_type()? {
+            ValueType::Int => visitor.visit_i32(self.cx.as_int()?),
+            ValueType::Float => visitor.visit_f64(self.cx.as_float()?),
+            ValueType::Bool => visitor.visit_bool(self.cx.as_bool()?),
+            ValueType::Null => {
+                self.cx.pop()?;
+                visitor.visit_unit()
+            }
+            ValueType::String => visitor.visit_str(self.cx.as_string()?),
+            ValueType::Symbol => visitor.visit_str(self.cx.as_symbol()?),
+            ValueType::Array => {
+                let len = self.cx.array_length()?;
+                let value = visitor.visit_seq(SeqAccess {
+                    de: &mut *self,
+                    index: 0,
+                    len,
+                })?;
+                self.cx.pop()?;
+                Ok(value)
+            }
+            ValueType::Map | ValueType::Object => {
+                self.cx.entries()?;
+                let len = self.cx.array_length()?;
+                let value = visitor.visit_map(MapAccess {
+                    de: &mut *self,
+                    index: 0,
+                    len,
+                })?;
+                self.cx.pop()?;
+                self.cx.pop()?;
+                Ok(value)
+            }
+            t => Err(SerdeError::Custom(format!("Cannot deserialize a {:?}", t))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.cx.peek_is_null()? {
+            self.cx.pop()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.cx.value_type()? {
+            ValueType::String => visitor.visit_enum(self.cx.as_string()?.into_deserializer()),
+            ValueType::Symbol => visitor.visit_enum(self.cx.as_symbol()?.into_deserializer()),
+            ValueType::Map | ValueType::Object => {
+                self.cx.entries()?;
+                if self.cx.array_length()? != 1 {
+                    return Err(SerdeError::Custom(
+                        "Expected an object with a single property for an enum variant".into(),
+                    ));
+                }
+                self.cx.get_element(0)?;
+                let value = visitor.visit_enum(EnumAccess { de: &mut *self })?;
+                self.cx.pop()?;
+                self.cx.pop()?;
+                self.cx.pop()?;
+                Ok(value)
+            }
+            t => Err(SerdeError::Custom(format!(
+                "Cannot deserialize an enum from a {:?}",
+                t
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct SeqAccess<'a, 'b, 'c> {
+    de: &'c mut Deserializer<'a, 'b>,
+    index: usize,
+    len: usize,
+}
+
+impl<'de, 'a, 'b, 'c> de::SeqAccess<'de> for SeqAccess<'a, 'b, 'c> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, SerdeError> {
+        if self.index == self.len {
+            Ok(None)
+        } else {
+            self.de.cx.get_element(self.index)?;
+            self.index += 1;
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+// The stack contains the array of [key, value] entries
+struct MapAccess<'a, 'b, 'c> {
+    de: &'c mut Deserializer<'a, 'b>,
+    index: usize,
+    len: usize,
+}
+
+impl<'de, 'a, 'b, 'c> de::MapAccess<'de> for MapAccess<'a, 'b, 'c> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        if self.index == self.len {
+            Ok(None)
+        } else {
+            self.de.cx.get_element(self.index)?;
+            self.index += 1;
+            self.de.cx.get_element(0)?;
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.de.cx.get_element(1)?;
+        let value = seed.deserialize(&mut *self.de)?;
+        self.de.cx.pop()?;
+        Ok(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+// The stack contains the [variant, value] entry
+struct EnumAccess<'a, 'b, 'c> {
+    de: &'c mut Deserializer<'a, 'b>,
+}
+
+impl<'de, 'a, 'b, 'c> de::EnumAccess<'de> for EnumAccess<'a, 'b, 'c> {
+    type Error = SerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), SerdeError> {
+        self.de.cx.get_element(0)?;
+        let variant = seed.deserialize(&mut *self.de)?;
+        self.de.cx.get_element(1)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, 'b, 'c> de::VariantAccess<'de> for EnumAccess<'a, 'b, 'c> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
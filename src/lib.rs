@@ -6,22 +6,35 @@
 //! ```
 use crate::vm::VMStatus;
 use compiler::Compiler;
-use cxx::UniquePtr;
+use cxx::{SharedPtr, UniquePtr};
 use futures::stream::FuturesUnordered;
 use futures::Future;
 use futures::StreamExt;
+pub use loaders::{ChainModuleLoader, FileSystemModuleLoader, InMemoryModuleLoader};
 use parser::Parser;
 use scanner::Scanner;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::task::Poll;
+#[cfg(feature = "tracing")]
+pub use vm::TraceEvent;
 use vm::UserData;
-use vm::{new_vm, FunctionInfoWriter, VM as VMInner};
-pub use vm::{EFuncContext, EFuncError, Resource, ToNeptuneValue};
+use vm::{new_vm, FunctionInfoWriter, Interrupt, ModuleVariable, VMOptions, VM as VMInner};
+pub use vm::{
+    ChannelReceiver, ChannelSender, EFuncContext, EFuncError, FromNeptuneValue, NeptuneValue,
+    Resource, ToNeptuneArgs, ToNeptuneValue, ValueType,
+};
 mod compiler;
+mod de;
+mod loaders;
 mod parser;
 mod scanner;
+mod ser;
 mod vm;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -39,9 +52,124 @@ pub struct CompileErrorList {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum InterpretError {
     CompileError(CompileErrorList),
-    UncaughtException(String),
+    UncaughtException(UncaughtException),
+    /// The bytes passed to [`VM::load_bytecode`] could not be loaded
+    InvalidBytecode(String),
+    /// The value of the expression passed to [`VM::eval`] cannot be converted to the
+    /// requested type
+    ConversionError(EFuncError),
 }
 
+/// A function call in the stack trace of an uncaught exception
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackFrame {
+    pub module: String,
+    pub function: String,
+    /// `None` for native functions
+    pub line: Option<u32>,
+}
+
+impl FromNeptuneValue for StackFrame {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.get_property("module")?;
+        let module = String::from_neptune_value(cx)?;
+        cx.get_property("function")?;
+        let function = String::from_neptune_value(cx)?;
+        cx.get_property("line")?;
+        let line = Option::<u32>::from_neptune_value(cx)?;
+        cx.pop()?;
+        Ok(Self {
+            module,
+            function,
+            line,
+        })
+    }
+}
+
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "at {} ({}:{})", self.function, self.module, line),
+            None => write!(f, "at {} ({})", self.function, self.module),
+        }
+    }
+}
+
+/// An exception that was thrown and not caught
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UncaughtException {
+    /// The name of the class of the thrown value
+    pub class: String,
+    /// The message of the error or the thrown value converted to a string if it is not an
+    /// instance of `Error`
+    pub message: String,
+    /// The name of the task in which the error was created if the task is named
+    pub task: Option<String>,
+    /// The stack trace of the error. It is empty if the thrown value is not an instance of
+    /// `Error`
+    pub stack: Vec<StackFrame>,
+    /// The thrown value if it is not an instance of `Error`. It is not serialized
+    #[serde(skip)]
+    pub value: Option<NeptuneValue>,
+}
+
+impl UncaughtException {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.get_property("isError")?;
+        let is_error = cx.as_bool()?;
+        cx.get_property("value")?;
+        let value = cx.as_value()?;
+        cx.get_property("class")?;
+        let class = cx.as_string()?.to_string();
+        cx.get_property("message")?;
+        let message = cx.as_string()?.to_string();
+        cx.get_property("task")?;
+        let task = Option::<String>::from_neptune_value(cx)?;
+        cx.get_property("frames")?;
+        let stack = Vec::<StackFrame>::from_neptune_value(cx)?;
+        Ok(Self {
+            class,
+            message,
+            task,
+            stack,
+            value: if is_error { None } else { Some(value) },
+        })
+    }
+}
+
+impl Display for UncaughtException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.value.is_some() {
+            return f.write_str(&self.message);
+        }
+        match &self.task {
+            Some(name) => {
+                f.write_str("In <Task '")?;
+                for c in name.chars() {
+                    match c {
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\'' => f.write_str("\\'")?,
+                        '\0' => f.write_str("\\0")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("'> ")?;
+            }
+            None => f.write_str("In <Task> ")?,
+        }
+        write!(f, "{}: {}", self.class, self.message)?;
+        for frame in &self.stack {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UncaughtException {}
+
 impl Display for CompileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "line {}: {}", self.line, self.message)
@@ -60,6 +188,12 @@ impl Display for InterpretError {
             InterpretError::UncaughtException(error) => {
                 write!(f, "Uncaught Exception:\n{}", error)?;
             }
+            InterpretError::InvalidBytecode(message) => {
+                write!(f, "Invalid bytecode: {}", message)?;
+            }
+            InterpretError::ConversionError(e) => {
+                write!(f, "Cannot convert the value of the expression: {}", e)?;
+            }
         }
         Ok(())
     }
@@ -75,6 +209,7 @@ pub enum Error {
     ModuleNotFound,
     ModuleAlreadyExists,
     EFuncAlreadyExists,
+    VariableAlreadyExists,
 }
 
 impl Display for Error {
@@ -86,12 +221,50 @@ impl Display for Error {
             Error::ModuleNotFound => f.write_str("The module cannot be found"),
             Error::ModuleAlreadyExists => f.write_str("A module with the same name already exists"),
             Error::EFuncAlreadyExists => f.write_str("An EFunc with the same name already exists"),
+            Error::VariableAlreadyExists => {
+                f.write_str("A module variable with the same name already exists")
+            }
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+/// Errors returned by `VM::call` and the functions that access module variables
+#[derive(Debug)]
+pub enum CallError {
+    /// The module does not exist
+    ModuleNotFound,
+    /// The module does not export a variable with the given name
+    ExportNotFound,
+    /// The exported variable is declared with `const`
+    ImmutableVariable,
+    /// The value returned by the function cannot be converted to the requested type
+    ConversionError(EFuncError),
+    /// An exception was thrown and not caught
+    UncaughtException(UncaughtException),
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::ModuleNotFound => f.write_str("The module cannot be found"),
+            CallError::ExportNotFound => {
+                f.write_str("The module does not export a variable with the given name")
+            }
+            CallError::ImmutableVariable => f.write_str("The variable cannot be reassigned"),
+            CallError::ConversionError(e) => {
+                write!(f, "Cannot convert the return value: {}", e)
+            }
+            CallError::UncaughtException(error) => {
+                write!(f, "Uncaught Exception:\n{}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
 /// This enum can be used to represent errors that can either be an EFuncError or another error type.
 /// `EFuncError`s can be converted to EFuncErrorOr using the ? operator.
 pub enum EFuncErrorOr<T: ToNeptuneValue> {
@@ -143,6 +316,92 @@ impl ToNeptuneValue for NeptuneError {
     }
 }
 
+/// The error returned by `EFuncContext::serialize` and `EFuncContext::deserialize`
+#[derive(Debug)]
+pub enum SerdeError {
+    EFuncError(EFuncError),
+    Custom(String),
+}
+
+impl Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerdeError::EFuncError(e) => write!(f, "{}", e),
+            SerdeError::Custom(s) => f.write_str(s),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+impl From<EFuncError> for SerdeError {
+    fn from(e: EFuncError) -> Self {
+        SerdeError::EFuncError(e)
+    }
+}
+
+impl ToNeptuneValue for SerdeError {
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        match self {
+            SerdeError::EFuncError(e) => e.to_neptune_value(cx),
+            SerdeError::Custom(s) => cx.error("<prelude>", "TypeError", &s).unwrap(),
+        }
+    }
+}
+
+/// A wrapper that converts values to and from Neptune values using serde.
+/// It can be used as the argument of `create_efunc_typed` or returned from an efunc.
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// let n = VM::new(NoopModuleLoader);
+/// n.create_efunc_typed("flip", |Serde(p): Serde<Point>| -> Result<Serde<Point>, ()> {
+///     Ok(Serde(Point { x: p.y, y: p.x }))
+/// }).unwrap();
+/// //This can now be called by ecall(@flip, {x: 1, y: 2})
+/// ```
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> ToNeptuneValue for Serde<T> {
+    /// Pushes a TypeError instead if the value cannot be serialized. An efunc that returns
+    /// such a value throws the error
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        if let Err(e) = cx.serialize(&self.0) {
+            cx.conversion_error(&e.to_string())
+        }
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> FromNeptuneValue for Serde<T> {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        match cx.deserialize() {
+            Ok(t) => Ok(Serde(t)),
+            Err(SerdeError::EFuncError(e)) => Err(e),
+            Err(SerdeError::Custom(_)) => Err(EFuncError::TypeError),
+        }
+    }
+}
+
 struct ModuleNotFound {
     module: String,
 }
@@ -158,9 +417,49 @@ impl ToNeptuneValue for ModuleNotFound {
     }
 }
 
+/// Whether [`VM::reload_module`] keeps the values of the variables of a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadPolicy {
+    /// Variables that existed before the reload keep their values
+    KeepState,
+    /// Variables are set to the values assigned by the new source
+    ResetState,
+}
+
+/// The exports of a module that changed when it was reloaded using [`VM::reload_module`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleChanges {
+    /// Exports declared for the first time
+    pub added: Vec<String>,
+    /// Exports that are not declared by the new source. They are no longer exported
+    pub removed: Vec<String>,
+    /// Exports whose value was changed by the new source. Functions and classes declared
+    /// by the new source are always replaced
+    pub replaced: Vec<String>,
+    /// Replaced functions and classes that could not be updated in place, because a task
+    /// is executing the function or it has upvalues. The export refers to the new
+    /// definition, but other references to the old one are unchanged
+    pub rebound: Vec<String>,
+}
+
+// The name, declaration and value of each variable of a module before it is reloaded
+type ModuleSnapshot = Vec<(String, ModuleVariable, NeptuneValue)>;
+
 /// Instance of a Neptune VM
 pub struct VM {
     vm: UniquePtr<VMInner>,
+    stdout: OutputSink,
+    stderr: OutputSink,
+}
+
+type OutputSink = Rc<RefCell<Box<dyn Write>>>;
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        // The futures of suspended tasks hold task handles, which must be released before
+        // the VM is destroyed
+        self.vm.get_user_data().futures.borrow_mut().clear();
+    }
 }
 
 /// The embedder needs to implement this trait to specify how to resolve import paths
@@ -173,6 +472,20 @@ pub trait ModuleLoader: Clone {
     fn load(&self, module: &str) -> Option<String>;
 }
 
+/// A module loader whose methods are asynchronous. While a module is resolved or loaded
+/// only the importing task is suspended and other tasks keep running.
+/// It is passed to `VMBuilder::new_async`
+// The futures are run on the thread of the VM so they do not need to be Send
+#[allow(async_fn_in_trait)]
+pub trait AsyncModuleLoader: Clone {
+    /// Returns the name of the module where
+    /// * `caller_module` is the module calling import
+    /// * `module` is  the argument passed to `import`
+    async fn resolve(&self, caller_module: &str, module: &str) -> Option<String>;
+    /// Returns the source of the module
+    async fn load(&self, module: &str) -> Option<String>;
+}
+
 #[derive(Clone, Copy)]
 pub struct NoopModuleLoader;
 
@@ -187,95 +500,301 @@ impl ModuleLoader for NoopModuleLoader {
     }
 }
 
-impl VM {
-    pub fn new<M: ModuleLoader + 'static>(module_loader: M) -> Self {
-        let n = Self {
-            vm: new_vm(Box::new(UserData {
-                futures: RefCell::new(FuturesUnordered::new()),
-            })),
+/// Restrictions applied to the scripts run by a VM created with `VM::new_sandboxed`
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// let vm = VM::new_sandboxed(
+///     NoopModuleLoader,
+///     Sandbox {
+///         hidden_modules: vec!["vm".into()],
+///         allowed_efuncs: Some(vec![]),
+///         disable_compilation: true,
+///     },
+/// );
+/// assert!(vm.exec_sync("<script>", "import('vm')").is_err());
+/// assert!(vm.exec_sync("<script>", "eval('1')").is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    /// Builtin modules like `vm` that cannot be imported
+    pub hidden_modules: Vec<String>,
+    /// If set, `ecall` can only call the efuncs in this list. The efuncs used by `import`,
+    /// `eval` and `exec` cannot be called using `ecall` in any VM
+    pub allowed_efuncs: Option<Vec<String>>,
+    /// Makes `eval` and `exec` throw
+    pub disable_compilation: bool,
+}
+
+/// Creates a VM with custom garbage collector and runtime settings
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// let vm = VMBuilder::new(NoopModuleLoader)
+///     .initial_heap_size(1024 * 1024)
+///     .heap_growth_factor(4)
+///     .seed(42)
+///     .build();
+/// vm.exec_sync("<script>", "1 + 1").unwrap();
+/// ```
+pub struct VMBuilder<M: ModuleLoader + 'static> {
+    module_loader: M,
+    options: VMOptions,
+    sandbox: Sandbox,
+    abort_on_panic: bool,
+    async_module_loader: Option<InstallModuleLoader>,
+}
+
+// Creates the efuncs used by import
+type InstallModuleLoader = Box<dyn FnOnce(&VM)>;
+
+impl VMBuilder<NoopModuleLoader> {
+    /// Creates a builder for a VM that resolves and loads the modules imported by scripts
+    /// using `module_loader`. The VM must be run using the asynchronous methods like `exec`
+    /// when modules are imported
+    pub fn new_async<A: AsyncModuleLoader + 'static>(module_loader: A) -> Self {
+        Self::new(NoopModuleLoader).async_module_loader(module_loader)
+    }
+}
+
+impl<M: ModuleLoader + 'static> VMBuilder<M> {
+    pub fn new(module_loader: M) -> Self {
+        Self {
+            module_loader,
+            options: VMOptions {
+                initial_heap_size: 10 * 1024 * 1024,
+                heap_growth_factor: 2,
+                initial_frames: 4,
+                stress_gc: false,
+                debug_gc: false,
+                has_seed: false,
+                seed: 0,
+            },
+            sandbox: Sandbox::default(),
+            abort_on_panic: false,
+            async_module_loader: None,
+        }
+    }
+
+    /// The number of bytes that can be allocated before the first garbage collection.
+    /// The default is 10MB
+    pub fn initial_heap_size(mut self, bytes: usize) -> Self {
+        self.options.initial_heap_size = bytes;
+        self
+    }
+
+    /// After a garbage collection the next one happens when the heap is `factor` times
+    /// larger. The default is 2
+    pub fn heap_growth_factor(mut self, factor: u32) -> Self {
+        self.options.heap_growth_factor = factor;
+        self
+    }
+
+    /// The number of call frames a task reserves space for when it is created. The default
+    /// is 4
+    pub fn initial_frames(mut self, frames: usize) -> Self {
+        self.options.initial_frames = frames;
+        self
+    }
+
+    /// Runs the garbage collector on every allocation. This is useful for finding GC bugs
+    pub fn stress_gc(mut self, stress_gc: bool) -> Self {
+        self.options.stress_gc = stress_gc;
+        self
+    }
+
+    /// Prints every garbage collection and freed object to stdout
+    pub fn debug_gc(mut self, debug_gc: bool) -> Self {
+        self.options.debug_gc = debug_gc;
+        self
+    }
+
+    /// Seeds the random number generator used by the `random` module. It is seeded from
+    /// the OS by default
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.options.has_seed = true;
+        self.options.seed = seed;
+        self
+    }
+
+    /// Restricts the scripts run by the VM. See `Sandbox`
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Uses `module_loader` to resolve and load the modules imported by scripts instead of
+    /// the module loader passed to `new`, which is then unused. `VMBuilder::new_async` can be
+    /// used instead to not pass a synchronous module loader. The VM must then be run using
+    /// the asynchronous methods like `exec` when modules are imported
+    pub fn async_module_loader<A: AsyncModuleLoader + 'static>(mut self, module_loader: A) -> Self {
+        self.async_module_loader = Some(Box::new(move |vm: &VM| {
+            install_async_module_loader(vm, module_loader)
+        }));
+        self
+    }
+
+    /// Aborts the process when an efunc panics. By default the panic is caught and a
+    /// `PanicError` with the panic message is thrown in the task that called the efunc
+    pub fn abort_on_panic(mut self, abort_on_panic: bool) -> Self {
+        self.abort_on_panic = abort_on_panic;
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let VMBuilder {
+            module_loader,
+            options,
+            sandbox,
+            abort_on_panic,
+            async_module_loader,
+        } = self;
+        let n = VM {
+            vm: new_vm(
+                Box::new(UserData {
+                    alive: Rc::new(()),
+                    futures: RefCell::new(FuturesUnordered::new()),
+                    abort_on_panic,
+                    senders: RefCell::new(Vec::new()),
+                    tasks_woken: Cell::new(false),
+                    conversion_failed: Cell::new(false),
+                    scheduler: Cell::new(None),
+                    receivers: RefCell::new(Vec::new()),
+                    #[cfg(feature = "tracing")]
+                    trace_hook: RefCell::new(None),
+                }),
+                options,
+            ),
+            stdout: Rc::new(RefCell::new(Box::new(std::io::stdout()))),
+            stderr: Rc::new(RefCell::new(Box::new(std::io::stderr()))),
         };
 
-        n.vm.create_efunc_safe("compile", |mut cx| -> bool {
-            let mut eval = false;
-            let vm = cx.vm();
-            let mut module = None;
-            match || -> Result<Result<(FunctionInfoWriter, bool), Vec<CompileError>>, EFuncError> {
-                cx.get_property("source")?;
-                let source = cx.as_string()?.to_string();
-                cx.get_property("eval")?;
-                eval = cx.as_bool()?;
-                cx.get_property("moduleName")?;
-                module = Some(cx.as_string()?.to_string());
-                cx.pop().unwrap();
-                Ok(compile(vm, module.as_ref().unwrap().clone(), &source, eval))
-            }() {
-                Err(e) => {
-                    e.to_neptune_value(&mut cx);
-                    false
-                }
-                Ok(res) => match res {
-                    Ok((fw, is_expr)) => {
-                        if eval {
-                            cx.object();
-                            unsafe { cx.function(fw) };
-                            cx.set_object_property("function").unwrap();
-                            cx.bool(is_expr);
-                            cx.set_object_property("isExpr").unwrap();
-                            true
-                        } else {
-                            unsafe { cx.function(fw) };
-                            true
-                        }
+        for (efunc, sink) in [("print", n.stdout.clone()), ("eprint", n.stderr.clone())] {
+            n.create_efunc(efunc, move |cx| -> Result<(), EFuncErrorOr<NeptuneError>> {
+                let s = cx.as_string()?;
+                writeln!(sink.borrow_mut(), "{}", s)
+                    .map_err(|e| EFuncErrorOr::Other(NeptuneError(e.to_string())))
+            })
+            .unwrap();
+        }
+
+        let disable_compilation = sandbox.disable_compilation;
+        // compile is used by eval and exec while compileModule is used by import
+        for (efunc, runtime) in [("compile", true), ("compileModule", false)] {
+            n.vm.create_efunc_safe(efunc, move |mut cx| -> bool {
+                let mut eval = false;
+                let vm = cx.vm();
+                let mut module = None;
+                // None if runtime compilation is disabled
+                match || -> Result<Option<CompiledFunction>, EFuncError> {
+                    cx.get_property("source")?;
+                    let source = cx.as_string()?.to_string();
+                    cx.get_property("eval")?;
+                    eval = cx.as_bool()?;
+                    cx.get_property("moduleName")?;
+                    module = Some(cx.as_string()?.to_string());
+                    cx.pop().unwrap();
+                    if runtime && disable_compilation {
+                        return Ok(None);
                     }
-                    Err(errors) => {
-                        CompileErrorList {
-                            module: module.unwrap(),
-                            errors,
-                        }
-                        .to_neptune_value(&mut cx);
+                    Ok(Some(compile(
+                        vm,
+                        module.as_ref().unwrap().clone(),
+                        &source,
+                        eval,
+                        false,
+                        false,
+                    )))
+                }() {
+                    Err(e) => {
+                        e.to_neptune_value(&mut cx);
                         false
                     }
-                },
-            }
-        });
-
-        n.create_efunc("resolveModule", {
-            let module_loader = module_loader.clone();
-            move |cx| -> Result<String, EFuncErrorOr<ModuleNotFound>> {
-                cx.get_property("callerModule")?;
-                let caller_module = cx.as_string()?.to_string();
-                cx.get_property("moduleName")?;
-                let module_name = cx.as_string()?.to_string();
-                cx.pop().unwrap();
-                match module_loader.resolve(&caller_module, &module_name) {
-                    Some(s) => Ok(s),
-                    None => Err(EFuncErrorOr::Other(ModuleNotFound {
-                        module: module_name,
-                    })),
+                    Ok(None) => {
+                        cx.error(
+                            "<prelude>",
+                            "PermissionError",
+                            "Runtime compilation is disabled",
+                        )
+                        .unwrap();
+                        false
+                    }
+                    Ok(Some(res)) => match res {
+                        Ok((fw, is_expr)) => {
+                            if eval {
+                                cx.object();
+                                unsafe { cx.function(fw) };
+                                cx.set_object_property("function").unwrap();
+                                cx.bool(is_expr);
+                                cx.set_object_property("isExpr").unwrap();
+                                true
+                            } else {
+                                unsafe { cx.function(fw) };
+                                true
+                            }
+                        }
+                        Err(errors) => {
+                            CompileErrorList {
+                                module: module.unwrap(),
+                                errors,
+                            }
+                            .to_neptune_value(&mut cx);
+                            false
+                        }
+                    },
                 }
-            }
-        })
-        .unwrap();
+            });
+        }
 
-        n.create_efunc(
-            "fetchModule",
-            move |cx| -> Result<String, EFuncErrorOr<NeptuneError>> {
-                let module = cx.as_string()?;
-                match module_loader.load(module) {
-                    Some(src) => Ok(src),
-                    None => Err(EFuncErrorOr::Other(NeptuneError(format!(
-                        "Cannot get source of module {}",
-                        module
-                    )))),
-                }
-            },
-        )
-        .unwrap();
+        match async_module_loader {
+            Some(install) => install(&n),
+            None => install_module_loader(&n, module_loader),
+        }
+        for efunc in ["compile", "compileModule", "resolveModule", "fetchModule"] {
+            n.vm.make_efunc_internal(efunc.into());
+        }
 
         n.exec_sync("<prelude>", include_str!("prelude.np"))
             .unwrap();
+        for module in &sandbox.hidden_modules {
+            n.vm.hide_module(module.as_str().into());
+        }
+        if let Some(efuncs) = &sandbox.allowed_efuncs {
+            n.vm.restrict_efuncs();
+            for efunc in efuncs {
+                n.vm.allow_efunc(efunc.as_str().into());
+            }
+        }
         n
     }
+}
+
+impl VM {
+    pub fn new<M: ModuleLoader + 'static>(module_loader: M) -> Self {
+        VMBuilder::new(module_loader).build()
+    }
+
+    /// Creates a VM whose scripts are restricted by `sandbox`. A `PermissionError` is thrown
+    /// when a script tries to do something the sandbox denies
+    pub fn new_sandboxed<M: ModuleLoader + 'static>(module_loader: M, sandbox: Sandbox) -> Self {
+        VMBuilder::new(module_loader).sandbox(sandbox).build()
+    }
+
+    /// Sets where `print` writes to. It is stdout by default
+    pub fn set_stdout<W: Write + 'static>(&self, sink: W) {
+        *self.stdout.borrow_mut() = Box::new(sink);
+    }
+
+    /// Sets where `eprint` writes to. It is stderr by default
+    pub fn set_stderr<W: Write + 'static>(&self, sink: W) {
+        *self.stderr.borrow_mut() = Box::new(sink);
+    }
+
+    /// Creates a Neptune `Channel` and returns a sender and a receiver connected to it.
+    /// The values received are converted to `T`. See `ChannelSender` and `ChannelReceiver`
+    pub fn channel<T: FromNeptuneValue>(&self) -> (ChannelSender, ChannelReceiver<T>) {
+        self.vm.create_channel_safe()
+    }
 
     /// Executes source with module `module`
     pub async fn exec<S: Into<String>>(
@@ -284,39 +803,11 @@ impl VM {
         source: &str,
     ) -> Result<(), InterpretError> {
         let module = module.into();
-        match compile(&self.vm, module.clone(), source, false) {
-            Ok((mut f, _)) => {
-                let mut result = unsafe { f.run() };
-                loop {
-                    match result {
-                        VMStatus::Success => return Ok(()),
-                        VMStatus::Error => {
-                            return Err(InterpretError::UncaughtException(self.vm.get_result()))
-                        }
-                        VMStatus::Suspend => {
-                            if self.vm.get_user_data().futures.borrow().is_empty() {
-                                return Err(InterpretError::UncaughtException(
-                                    self.vm.kill_main_task(
-                                        "DeadlockError".into(),
-                                        "All tasks were asleep".into(),
-                                    ),
-                                ));
-                            } else {
-                                let (closure, mut task) = self
-                                    .vm
-                                    .get_user_data()
-                                    .futures
-                                    .borrow_mut()
-                                    .next()
-                                    .await
-                                    .unwrap();
-                                result = task.resume_safe(closure);
-                            }
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-            }
+        match compile(&self.vm, module.clone(), source, false, false, false) {
+            Ok((mut f, _)) => self
+                .wait(unsafe { f.run() })
+                .await
+                .map_err(InterpretError::UncaughtException),
             Err(errors) => Err(InterpretError::CompileError(CompileErrorList {
                 errors,
                 module,
@@ -332,22 +823,10 @@ impl VM {
         source: &str,
     ) -> Result<(), InterpretError> {
         let module = module.into();
-        match compile(&self.vm, module.clone(), source, false) {
-            Ok((mut f, _)) => match unsafe { f.run() } {
-                VMStatus::Success => Ok(()),
-                VMStatus::Error => Err(InterpretError::UncaughtException(self.vm.get_result())),
-                VMStatus::Suspend => {
-                    if self.vm.get_user_data().futures.borrow().is_empty() {
-                        Err(InterpretError::UncaughtException(self.vm.kill_main_task(
-                            "DeadlockError".into(),
-                            "All tasks were asleep".into(),
-                        )))
-                    } else {
-                        panic!("Waiting on future in exec_sync");
-                    }
-                }
-                _ => unreachable!(),
-            },
+        match compile(&self.vm, module.clone(), source, false, false, false) {
+            Ok((mut f, _)) => self
+                .wait_sync(unsafe { f.run() })
+                .map_err(InterpretError::UncaughtException),
             Err(errors) => Err(InterpretError::CompileError(CompileErrorList {
                 errors,
                 module,
@@ -355,58 +834,703 @@ impl VM {
         }
     }
 
-    /// Creates a module named  `name`.
-    /// It returns `Err(ModuleAlreadyExists)` if an existing module is named `name`
-    pub fn create_module(&self, name: &str) -> Result<(), Error> {
-        if self.vm.module_exists(name.into()) {
-            Err(Error::ModuleAlreadyExists)
-        } else {
-            self.vm.create_module(name.into());
-            Ok(())
+    /// Evaluates source with module `module` and converts its value to `T`. If source is
+    /// not a single expression it is executed and its value is `null`
+    pub async fn eval<T: FromNeptuneValue, S: Into<String>>(
+        &self,
+        module: S,
+        source: &str,
+    ) -> Result<T, InterpretError> {
+        let module = module.into();
+        match compile(&self.vm, module.clone(), source, true, false, false) {
+            Ok((mut f, is_expr)) => {
+                self.wait(unsafe { f.run() })
+                    .await
+                    .map_err(InterpretError::UncaughtException)?;
+                self.eval_result(is_expr)
+            }
+            Err(errors) => Err(InterpretError::CompileError(CompileErrorList {
+                errors,
+                module,
+            })),
         }
     }
 
-    /// Creates an synchronous efunc.
-    /// Returns Err(EFuncAlreadyExists) if an existing efunc is named `name`
+    /// Evaluates source with module `module` and converts its value to `T`. If source is
+    /// not a single expression it is executed and its value is `null`.
+    /// It panics if a asynchronous efunc is executed
     /// Example:
     /// ```
     /// use neptune_lang::*;
-    /// let n = VM::new(NoopModuleLoader);
-    /// // Methods of EFuncContext usually return EFuncError on error. To return NeptuneError(the
-    /// // Error class of Neptune) or EFuncError we can use the EFuncErrorOr enum.
-    /// n.create_efunc("inverse", |cx /*: &mut EFuncContext*/ | -> Result<f64,EFuncErrorOr<NeptuneError>> {
-    ///     // pop an int from the stack
-    ///     let i = cx.as_int()?;
-    ///     if i == 0 {
-    ///         // It would be better to create our own Error type and implement ToNeptuneValue for it.
-    ///         Err(EFuncErrorOr::Other(NeptuneError("Cannot divide by zero".into())))
-    ///     }else{
-    ///         Ok(1.0 / (i as f64))
-    ///     }
-    /// }).unwrap();
-    /// //This can now be called by ecall(@inverse, 6)
+    /// let vm = VM::new(NoopModuleLoader);
+    /// let x: i32 = vm.eval_sync("<script>", "6 * 7").unwrap();
+    /// assert_eq!(x, 42);
     /// ```
-    pub fn create_efunc<F, T1, T2>(&self, name: &str, mut callback: F) -> Result<(), Error>
-    where
-        F: FnMut(&mut EFuncContext) -> Result<T1, T2> + 'static,
-        T1: ToNeptuneValue,
-        T2: ToNeptuneValue,
-    {
-        let callback = move |mut cx: EFuncContext| match callback(&mut cx) {
-            Ok(t1) => {
-                t1.to_neptune_value(&mut cx);
-                true
-            }
-            Err(t2) => {
-                t2.to_neptune_value(&mut cx);
-                false
+    pub fn eval_sync<T: FromNeptuneValue, S: Into<String>>(
+        &self,
+        module: S,
+        source: &str,
+    ) -> Result<T, InterpretError> {
+        let module = module.into();
+        match compile(&self.vm, module.clone(), source, true, false, false) {
+            Ok((mut f, is_expr)) => {
+                self.wait_sync(unsafe { f.run() })
+                    .map_err(InterpretError::UncaughtException)?;
+                self.eval_result(is_expr)
             }
-        };
-        if self.vm.create_efunc_safe(name, callback) {
-            Ok(())
-        } else {
-            Err(Error::EFuncAlreadyExists)
-        }
+            Err(errors) => Err(InterpretError::CompileError(CompileErrorList {
+                errors,
+                module,
+            })),
+        }
+    }
+
+    /// Compiles `source` and executes it in the existing module `module`, which is created
+    /// if it does not exist. Functions and classes declared by the source replace the old
+    /// ones in place, so modules that imported them call the new definitions from then on.
+    /// Instances of a replaced class use the new methods. `policy` decides whether other
+    /// variables keep their values. Exports that are not declared by the new source are no
+    /// longer exported. If the source cannot be compiled or throws an exception, the module
+    /// keeps its old values and exports
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.exec_sync("counter", "export let count = 0\nexport fun incr() { count += 1 }")
+    ///     .unwrap();
+    /// vm.exec_sync("<script>", "const {incr} = import('counter')\nincr()").unwrap();
+    /// let changes = vm
+    ///     .reload_module_sync(
+    ///         "counter",
+    ///         "export let count = 0\nexport fun incr() { count += 10 }",
+    ///         ReloadPolicy::KeepState,
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(changes.replaced, vec!["incr"]);
+    /// vm.exec_sync("<script>", "incr()").unwrap();
+    /// assert_eq!(vm.get_global::<i32>("counter", "count").unwrap(), 11);
+    /// ```
+    pub async fn reload_module(
+        &self,
+        module: &str,
+        source: &str,
+        policy: ReloadPolicy,
+    ) -> Result<ModuleChanges, InterpretError> {
+        let snapshot = self.snapshot_module(module);
+        match compile(&self.vm, module.into(), source, false, false, true) {
+            Ok((mut f, _)) => match self.wait(unsafe { f.run() }).await {
+                Ok(()) => Ok(self.commit_reload(module, snapshot, policy)),
+                Err(e) => {
+                    self.rollback_reload(module, snapshot);
+                    Err(InterpretError::UncaughtException(e))
+                }
+            },
+            Err(errors) => {
+                self.rollback_reload(module, snapshot);
+                Err(InterpretError::CompileError(CompileErrorList {
+                    errors,
+                    module: module.into(),
+                }))
+            }
+        }
+    }
+
+    /// Reloads a module like `VM::reload_module`.
+    /// It panics if a asynchronous efunc is executed
+    pub fn reload_module_sync(
+        &self,
+        module: &str,
+        source: &str,
+        policy: ReloadPolicy,
+    ) -> Result<ModuleChanges, InterpretError> {
+        let snapshot = self.snapshot_module(module);
+        match compile(&self.vm, module.into(), source, false, false, true) {
+            Ok((mut f, _)) => match self.wait_sync(unsafe { f.run() }) {
+                Ok(()) => Ok(self.commit_reload(module, snapshot, policy)),
+                Err(e) => {
+                    self.rollback_reload(module, snapshot);
+                    Err(InterpretError::UncaughtException(e))
+                }
+            },
+            Err(errors) => {
+                self.rollback_reload(module, snapshot);
+                Err(InterpretError::CompileError(CompileErrorList {
+                    errors,
+                    module: module.into(),
+                }))
+            }
+        }
+    }
+
+    // Saves the variables of a module before it is reloaded and stops exporting them, so
+    // that only the exports declared by the new source are exported afterwards
+    fn snapshot_module(&self, module: &str) -> ModuleSnapshot {
+        self.vm.begin_reload();
+        let names = self
+            .vm
+            .module_variable_names(module.into())
+            .unwrap_or_default();
+        let variables: ModuleSnapshot = names
+            .into_iter()
+            .map(|name| {
+                let var = self
+                    .vm
+                    .get_module_variable(module.into(), name.as_str().into())
+                    .unwrap();
+                let value = self
+                    .vm
+                    .with_module_variable_safe(var, |cx| cx.as_value().unwrap());
+                (name, var, value)
+            })
+            .collect();
+        for (name, var, _) in &variables {
+            self.vm
+                .redeclare_module_variable(module.into(), name.as_str().into(), var.mutable, false)
+                .unwrap();
+        }
+        variables
+    }
+
+    fn commit_reload(
+        &self,
+        module: &str,
+        snapshot: ModuleSnapshot,
+        policy: ReloadPolicy,
+    ) -> ModuleChanges {
+        let mut changes = ModuleChanges::default();
+        let mut old_exports = vec![];
+        for (name, var, old) in snapshot {
+            let new_var = self
+                .vm
+                .get_module_variable(module.into(), name.as_str().into())
+                .unwrap();
+            let new = self
+                .vm
+                .with_module_variable_safe(var, |cx| cx.as_value().unwrap());
+            let is_definition = matches!(
+                self.vm
+                    .with_module_variable_safe(var, |cx| cx.value_type().unwrap()),
+                ValueType::Function | ValueType::Class
+            );
+            let mut rebound = false;
+            let replaced = if self.vm.replace_definition_safe(&old, &new) {
+                self.vm.set_module_variable_safe(var, |cx| cx.value(&old));
+                true
+            } else if is_definition && !self.vm.strict_equals_safe(&old, &new) {
+                // The new definition is kept
+                rebound = true;
+                true
+            } else if policy == ReloadPolicy::KeepState {
+                self.vm.set_module_variable_safe(var, |cx| cx.value(&old));
+                false
+            } else {
+                // Variables that the new source does not declare or assigns the same value
+                // are unchanged
+                !self.vm.strict_equals_safe(&old, &new)
+            };
+            if var.exported {
+                if !new_var.exported {
+                    changes.removed.push(name.clone());
+                } else if replaced {
+                    changes.replaced.push(name.clone());
+                    if rebound {
+                        changes.rebound.push(name.clone());
+                    }
+                }
+                old_exports.push(name);
+            }
+        }
+        changes.added = self
+            .vm
+            .module_exports(module.into())
+            .unwrap()
+            .into_iter()
+            .filter(|name| !old_exports.contains(name))
+            .collect();
+        self.vm.end_reload();
+        changes
+    }
+
+    // Restores the values and exports of a module after a failed reload
+    fn rollback_reload(&self, module: &str, snapshot: ModuleSnapshot) {
+        let names = self
+            .vm
+            .module_variable_names(module.into())
+            .unwrap_or_default();
+        for name in names {
+            let var = self
+                .vm
+                .get_module_variable(module.into(), name.as_str().into())
+                .unwrap();
+            match snapshot.iter().find(|(old_name, _, _)| *old_name == name) {
+                Some((_, old_var, old)) => {
+                    self.vm
+                        .redeclare_module_variable(
+                            module.into(),
+                            name.as_str().into(),
+                            old_var.mutable,
+                            old_var.exported,
+                        )
+                        .unwrap();
+                    self.vm.set_module_variable_safe(var, |cx| cx.value(old));
+                }
+                None => self
+                    .vm
+                    .redeclare_module_variable(
+                        module.into(),
+                        name.as_str().into(),
+                        var.mutable,
+                        false,
+                    )
+                    .unwrap(),
+            }
+        }
+        self.vm.end_reload();
+    }
+
+    /// Compiles source with module `module` to bytecode that [`VM::load_bytecode`] can run
+    /// without scanning, parsing or compiling it again. The module variables declared by
+    /// the source are added to `module` in this VM but the source is not executed
+    pub fn compile_to_bytecode<S: Into<String>>(
+        &self,
+        module: S,
+        source: &str,
+    ) -> Result<Vec<u8>, CompileErrorList> {
+        let module = module.into();
+        match compile(&self.vm, module.clone(), source, false, true, false) {
+            Ok((f, _)) => Ok(f
+                .serialize()
+                .expect("Relocatable bytecode should be serializable")),
+            Err(errors) => Err(CompileErrorList { errors, module }),
+        }
+    }
+
+    /// Executes bytecode produced by [`VM::compile_to_bytecode`] with module `module`.
+    /// The bytecode may have been compiled by another VM. It is verified before it is run,
+    /// so invalid bytecode results in [`InterpretError::InvalidBytecode`]
+    pub async fn load_bytecode<S: Into<String>>(
+        &self,
+        module: S,
+        bytes: &[u8],
+    ) -> Result<(), InterpretError> {
+        let module = module.into();
+        match self.vm.load_bytecode(module.as_str().into(), bytes) {
+            Ok(mut f) => self
+                .wait(unsafe { f.run() })
+                .await
+                .map_err(InterpretError::UncaughtException),
+            Err(e) => Err(InterpretError::InvalidBytecode(e.what().into())),
+        }
+    }
+
+    /// Executes bytecode produced by [`VM::compile_to_bytecode`] with module `module`.
+    /// It is verified before it is run, so invalid bytecode results in
+    /// [`InterpretError::InvalidBytecode`].
+    /// It panics if a asynchronous efunc is executed
+    pub fn load_bytecode_sync<S: Into<String>>(
+        &self,
+        module: S,
+        bytes: &[u8],
+    ) -> Result<(), InterpretError> {
+        let module = module.into();
+        match self.vm.load_bytecode(module.as_str().into(), bytes) {
+            Ok(mut f) => self
+                .wait_sync(unsafe { f.run() })
+                .map_err(InterpretError::UncaughtException),
+            Err(e) => Err(InterpretError::InvalidBytecode(e.what().into())),
+        }
+    }
+
+    /// Calls the function exported as `function` by `module` with `args` and converts its
+    /// return value to `R`. The function is run as the main task.
+    /// It panics if a asynchronous efunc is executed
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.exec_sync("<script>", "export fun add(a, b) { return a + b }").unwrap();
+    /// let sum: i32 = vm.call("<script>", "add", (1, 2)).unwrap();
+    /// assert_eq!(sum, 3);
+    /// ```
+    pub fn call<A, R>(&self, module: &str, function: &str, args: A) -> Result<R, CallError>
+    where
+        A: ToNeptuneArgs,
+        R: FromNeptuneValue,
+    {
+        self.check_export(module, function)?;
+        let result = self
+            .vm
+            .call_safe(module, function, |cx| args.to_neptune_args(cx));
+        self.wait_sync(result)
+            .map_err(CallError::UncaughtException)?;
+        self.return_value()
+    }
+
+    /// Calls the function exported as `function` by `module` with `args` and converts its
+    /// return value to `R`. The function is run as the main task.
+    pub async fn call_async<A, R>(
+        &self,
+        module: &str,
+        function: &str,
+        args: A,
+    ) -> Result<R, CallError>
+    where
+        A: ToNeptuneArgs,
+        R: FromNeptuneValue,
+    {
+        self.check_export(module, function)?;
+        let result = self
+            .vm
+            .call_safe(module, function, |cx| args.to_neptune_args(cx));
+        self.wait(result)
+            .await
+            .map_err(CallError::UncaughtException)?;
+        self.return_value()
+    }
+
+    /// Calls `callee` with `args` and converts its return value to `R`.
+    /// It panics if a asynchronous efunc is executed or if `callee` belongs to another VM
+    pub fn call_value<A, R>(&self, callee: &NeptuneValue, args: A) -> Result<R, CallError>
+    where
+        A: ToNeptuneArgs,
+        R: FromNeptuneValue,
+    {
+        let result = self
+            .vm
+            .call_value_safe(callee, |cx| args.to_neptune_args(cx));
+        self.wait_sync(result)
+            .map_err(CallError::UncaughtException)?;
+        self.return_value()
+    }
+
+    /// Calls `callee` with `args` and converts its return value to `R`.
+    /// It panics if `callee` belongs to another VM
+    pub async fn call_value_async<A, R>(
+        &self,
+        callee: &NeptuneValue,
+        args: A,
+    ) -> Result<R, CallError>
+    where
+        A: ToNeptuneArgs,
+        R: FromNeptuneValue,
+    {
+        let result = self
+            .vm
+            .call_value_safe(callee, |cx| args.to_neptune_args(cx));
+        self.wait(result)
+            .await
+            .map_err(CallError::UncaughtException)?;
+        self.return_value()
+    }
+
+    /// Converts the value of the variable exported as `name` by `module` to `T`
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.exec_sync("<script>", "export let result = 6 * 7").unwrap();
+    /// let result: i32 = vm.get_global("<script>", "result").unwrap();
+    /// assert_eq!(result, 42);
+    /// ```
+    pub fn get_global<T: FromNeptuneValue>(
+        &self,
+        module: &str,
+        name: &str,
+    ) -> Result<T, CallError> {
+        let var = self.check_export(module, name)?;
+        self.vm
+            .with_module_variable_safe(var, |cx| T::from_neptune_value(cx))
+            .map_err(CallError::ConversionError)
+    }
+
+    /// Sets the variable exported as `name` by `module` to `value`. The variable must
+    /// be declared using `let`
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.exec_sync("<script>", "export let config = null").unwrap();
+    /// vm.set_global("<script>", "config", 21).unwrap();
+    /// vm.exec_sync("<script>", "export const doubled = config * 2").unwrap();
+    /// assert_eq!(vm.get_global::<i32>("<script>", "doubled").unwrap(), 42);
+    /// ```
+    pub fn set_global<T: ToNeptuneValue>(
+        &self,
+        module: &str,
+        name: &str,
+        value: T,
+    ) -> Result<(), CallError> {
+        let var = self.check_export(module, name)?;
+        if !var.mutable {
+            return Err(CallError::ImmutableVariable);
+        }
+        self.vm
+            .set_module_variable_safe(var, |cx| value.to_neptune_value(cx));
+        Ok(())
+    }
+
+    /// Returns the names of the variables exported by `module` in the order they were declared
+    pub fn module_exports(&self, module: &str) -> Result<Vec<String>, CallError> {
+        self.vm
+            .module_exports(module.into())
+            .or(Err(CallError::ModuleNotFound))
+    }
+
+    fn check_export(&self, module: &str, name: &str) -> Result<ModuleVariable, CallError> {
+        if !self.vm.module_exists(module.into()) {
+            return Err(CallError::ModuleNotFound);
+        }
+        match self.vm.get_module_variable(module.into(), name.into()) {
+            Ok(var) if var.exported => Ok(var),
+            _ => Err(CallError::ExportNotFound),
+        }
+    }
+
+    fn return_value<R: FromNeptuneValue>(&self) -> Result<R, CallError> {
+        self.vm
+            .with_return_value_safe(|cx| R::from_neptune_value(cx))
+            .map_err(CallError::ConversionError)
+    }
+
+    fn eval_result<T: FromNeptuneValue>(&self, is_expr: bool) -> Result<T, InterpretError> {
+        self.vm
+            .with_return_value_safe(|cx| {
+                if !is_expr {
+                    cx.pop().unwrap();
+                    cx.null();
+                }
+                T::from_neptune_value(cx)
+            })
+            .map_err(InterpretError::ConversionError)
+    }
+
+    fn uncaught_exception(&self) -> UncaughtException {
+        self.vm
+            .with_uncaught_exception_safe(UncaughtException::from_neptune_value)
+            .unwrap()
+    }
+
+    // Drops the futures of the tasks that were killed while waiting for them, which
+    // cancels them
+    fn drop_cancelled_futures(&self) {
+        if self.vm.take_cancelled_futures() {
+            let futures = &self.vm.get_user_data().futures;
+            let pending = std::mem::take(&mut *futures.borrow_mut());
+            let pending = pending.into_iter().filter(|f| f.is_alive()).collect();
+            *futures.borrow_mut() = pending;
+        }
+    }
+
+    // Resumes the main task whenever a future it waits on completes until it finishes.
+    // The uncaught exception is returned on error
+    async fn wait(&self, mut result: VMStatus) -> Result<(), UncaughtException> {
+        let user_data = self.vm.get_user_data();
+        loop {
+            self.drop_cancelled_futures();
+            self.vm.wake_channel_receivers();
+            match result {
+                VMStatus::Success => return Ok(()),
+                VMStatus::Error => return Err(self.uncaught_exception()),
+                VMStatus::Suspend => {
+                    if user_data.futures.borrow().is_empty()
+                        && !self.vm.has_tasks_waiting_for_senders()
+                        && !user_data.tasks_woken.get()
+                    {
+                        self.vm
+                            .kill_main_task("DeadlockError".into(), "All tasks were asleep".into());
+                        return Err(self.uncaught_exception());
+                    } else {
+                        // None if a channel sender woke a task
+                        let completed = futures::future::poll_fn(|cx| {
+                            if user_data.tasks_woken.take() {
+                                return Poll::Ready(None);
+                            }
+                            user_data.scheduler.set(Some(cx.waker().clone()));
+                            match user_data.futures.borrow_mut().poll_next_unpin(cx) {
+                                Poll::Ready(Some(completed)) => Poll::Ready(Some(completed)),
+                                _ => Poll::Pending,
+                            }
+                        })
+                        .await;
+                        result = match completed {
+                            Some((closure, mut task)) => task.resume_safe(closure),
+                            None => self.vm.run_tasks(),
+                        };
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn wait_sync(&self, result: VMStatus) -> Result<(), UncaughtException> {
+        self.drop_cancelled_futures();
+        self.vm.wake_channel_receivers();
+        match result {
+            VMStatus::Success => Ok(()),
+            VMStatus::Error => Err(self.uncaught_exception()),
+            VMStatus::Suspend => {
+                if self.vm.get_user_data().futures.borrow().is_empty() {
+                    self.vm
+                        .kill_main_task("DeadlockError".into(), "All tasks were asleep".into());
+                    Err(self.uncaught_exception())
+                } else {
+                    panic!("Waiting on future in a synchronous call");
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Limits the number of backward jumps and calls the VM can execute. A `TimeoutError` is
+    /// thrown once the budget is exhausted and every later backward jump or call throws it
+    /// again until a new budget is set. `None` removes the limit
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.set_instruction_budget(Some(1000));
+    /// assert!(vm.exec_sync("<script>", "while true {}").is_err());
+    /// ```
+    pub fn set_instruction_budget(&self, budget: Option<u64>) {
+        match budget {
+            Some(budget) => self.vm.set_instruction_budget(budget),
+            None => self.vm.remove_instruction_budget(),
+        }
+    }
+
+    /// Returns the remaining instruction budget or `None` if there is no limit
+    pub fn instruction_budget(&self) -> Option<u64> {
+        let mut budget = 0;
+        if self.vm.get_instruction_budget(&mut budget) {
+            Some(budget)
+        } else {
+            None
+        }
+    }
+
+    /// Calls `hook` for every [`TraceEvent`] while scripts run, which can be used to build
+    /// coverage, debugging or auditing tools. The hook cannot use the VM and a panic in the
+    /// hook aborts the process. Requires the `tracing` feature, without which the
+    /// interpreter does not check for a hook
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// let lines = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    /// let lines2 = lines.clone();
+    /// vm.set_trace_hook(move |event| {
+    ///     if let TraceEvent::Line { line, .. } = event {
+    ///         lines2.borrow_mut().push(line);
+    ///     }
+    /// });
+    /// vm.exec_sync("<script>", "let x = 1\nx += 1").unwrap();
+    /// assert_eq!(*lines.borrow(), vec![1, 2]);
+    /// ```
+    #[cfg(feature = "tracing")]
+    pub fn set_trace_hook<F: FnMut(TraceEvent) + 'static>(&self, hook: F) {
+        *self.vm.get_user_data().trace_hook.borrow_mut() = Some(Box::new(hook));
+        self.vm.set_tracing(true);
+    }
+
+    /// Removes the hook set using [`VM::set_trace_hook`]
+    #[cfg(feature = "tracing")]
+    pub fn remove_trace_hook(&self) {
+        self.vm.set_tracing(false);
+        *self.vm.get_user_data().trace_hook.borrow_mut() = None;
+    }
+
+    /// Limits the memory used by objects allocated by the VM. If an allocation would exceed
+    /// the limit even after a garbage collection an `OutOfMemoryError` is thrown. `None`
+    /// removes the limit
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// vm.set_max_heap_bytes(Some(1 << 20));
+    /// assert!(vm
+    ///     .exec_sync("<script>", "let a = []\nwhile true { a.push(a.len()) }")
+    ///     .is_err());
+    /// ```
+    pub fn set_max_heap_bytes(&self, bytes: Option<usize>) {
+        self.vm.set_max_heap_bytes(bytes.unwrap_or(0))
+    }
+
+    /// Returns a handle that can be used to interrupt the VM from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.vm.get_interrupt())
+    }
+
+    /// Creates a module named  `name`.
+    /// It returns `Err(ModuleAlreadyExists)` if an existing module is named `name`
+    pub fn create_module(&self, name: &str) -> Result<(), Error> {
+        if self.vm.module_exists(name.into()) {
+            Err(Error::ModuleAlreadyExists)
+        } else {
+            self.vm.create_module(name.into());
+            Ok(())
+        }
+    }
+
+    /// Creates an synchronous efunc.
+    /// Returns Err(EFuncAlreadyExists) if an existing efunc is named `name`
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let n = VM::new(NoopModuleLoader);
+    /// // Methods of EFuncContext usually return EFuncError on error. To return NeptuneError(the
+    /// // Error class of Neptune) or EFuncError we can use the EFuncErrorOr enum.
+    /// n.create_efunc("inverse", |cx /*: &mut EFuncContext*/ | -> Result<f64,EFuncErrorOr<NeptuneError>> {
+    ///     // pop an int from the stack
+    ///     let i = cx.as_int()?;
+    ///     if i == 0 {
+    ///         // It would be better to create our own Error type and implement ToNeptuneValue for it.
+    ///         Err(EFuncErrorOr::Other(NeptuneError("Cannot divide by zero".into())))
+    ///     }else{
+    ///         Ok(1.0 / (i as f64))
+    ///     }
+    /// }).unwrap();
+    /// //This can now be called by ecall(@inverse, 6)
+    /// ```
+    pub fn create_efunc<F, T1, T2>(&self, name: &str, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut EFuncContext) -> Result<T1, T2> + 'static,
+        T1: ToNeptuneValue,
+        T2: ToNeptuneValue,
+    {
+        if self
+            .vm
+            .create_efunc_safe(name, move |cx| call_efunc(&mut callback, cx))
+        {
+            Ok(())
+        } else {
+            Err(Error::EFuncAlreadyExists)
+        }
+    }
+
+    /// Creates a synchronous efunc whose argument is converted using `FromNeptuneValue`.
+    /// If the argument cannot be converted an `EFuncError` is thrown.
+    /// Returns Err(EFuncAlreadyExists) if an existing efunc is named `name`
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let n = VM::new(NoopModuleLoader);
+    /// n.create_efunc_typed("sum", |v: Vec<i32>| -> Result<i32, ()> {
+    ///     Ok(v.iter().sum())
+    /// }).unwrap();
+    /// //This can now be called by ecall(@sum, [1, 2, 3])
+    /// ```
+    pub fn create_efunc_typed<F, A, T1, T2>(&self, name: &str, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(A) -> Result<T1, T2> + 'static,
+        A: FromNeptuneValue,
+        T1: ToNeptuneValue,
+        T2: ToNeptuneValue,
+    {
+        self.create_efunc(name, move |cx| -> Result<T1, EFuncErrorOr<T2>> {
+            let arg = A::from_neptune_value(cx)?;
+            callback(arg).map_err(EFuncErrorOr::Other)
+        })
     }
 
     /// Creates an asynchronous efunc.
@@ -424,23 +1548,353 @@ impl VM {
             Err(Error::EFuncAlreadyExists)
         }
     }
+
+    /// Returns a builder for a class named `name` exported by `module` whose instances wrap
+    /// a `T`. The instances are resources and can be freed using the `close()` method.
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// struct Vector(f64, f64);
+    /// let n = VM::new(NoopModuleLoader);
+    /// n.create_module("geometry").unwrap();
+    /// n.register_class::<Vector>("geometry", "Vector")
+    ///     .constructor(2, |cx| -> Result<Vector, EFuncError> {
+    ///         let y = cx.as_float()?;
+    ///         let x = cx.as_float()?;
+    ///         Ok(Vector(x, y))
+    ///     })
+    ///     .method("length", 0, |cx| -> Result<f64, EFuncError> {
+    ///         let v = cx.receiver::<Vector>()?;
+    ///         Ok(v.0.hypot(v.1))
+    ///     })
+    ///     .to_string(|v| format!("Vector({}, {})", v.0, v.1))
+    ///     .build()
+    ///     .unwrap();
+    /// n.exec_sync(
+    ///     "<script>",
+    ///     "const {Vector} = import('geometry')\nexport const length = new Vector(3.0, 4.0).length()",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(n.get_global::<f64>("<script>", "length").unwrap(), 5.0);
+    /// ```
+    pub fn register_class<T: 'static>(&self, module: &str, name: &str) -> ClassBuilder<'_, T> {
+        ClassBuilder {
+            vm: self,
+            module: module.into(),
+            name: name.into(),
+            methods: vec![],
+            _marker: PhantomData,
+        }
+    }
 }
 
-fn compile<'vm>(
-    vm: &'vm VMInner,
-    module: String,
-    source: &str,
-    eval: bool,
-) -> Result<(FunctionInfoWriter<'vm>, bool), Vec<CompileError>> {
-    if !vm.module_exists(module.as_str().into()) {
-        vm.create_module_with_prelude(module.as_str().into());
+impl VM {
+    /// Returns a builder for a module named `name` whose functions are Rust closures. The
+    /// functions are exported and throw an `ArgumentError` when called with the wrong number
+    /// of arguments.
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let n = VM::new(NoopModuleLoader);
+    /// n.create_native_module("strings")
+    ///     .function("repeat", 2, |cx| -> Result<String, EFuncError> {
+    ///         let times = cx.as_int()?;
+    ///         let s = cx.as_string()?.to_string();
+    ///         Ok(s.repeat(times.max(0) as usize))
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// n.exec_sync("<script>", "export const s = import('strings').repeat('ab', 2)")
+    ///     .unwrap();
+    /// assert_eq!(n.get_global::<String>("<script>", "s").unwrap(), "abab");
+    /// ```
+    pub fn create_native_module(&self, name: &str) -> NativeModuleBuilder<'_> {
+        NativeModuleBuilder {
+            vm: self,
+            name: name.into(),
+            functions: vec![],
+        }
     }
-    let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
-    let parser = Parser::new(tokens.into_iter());
-    let ast = parser.parse(eval);
-    let compiler = Compiler::new(vm, module);
-    let mut is_expr = false;
+}
+
+/// A handle that can interrupt a VM from any thread. It can outlive the VM
+#[derive(Clone)]
+pub struct InterruptHandle(SharedPtr<Interrupt>);
+
+impl InterruptHandle {
+    /// Throws a `TimeoutError` at the next backward jump or call. Every later backward jump
+    /// or call throws it again until the main task finishes, even if it is suspended waiting
+    /// for an async efunc in between
+    /// Example:
+    /// ```
+    /// use neptune_lang::*;
+    /// let vm = VM::new(NoopModuleLoader);
+    /// let handle = vm.interrupt_handle();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(std::time::Duration::from_millis(10));
+    ///     handle.interrupt();
+    /// });
+    /// assert!(vm.exec_sync("<script>", "while true {}").is_err());
+    /// ```
+    pub fn interrupt(&self) {
+        self.0.interrupt()
+    }
+}
+
+type MethodCallback = Box<dyn FnMut(EFuncContext) -> bool>;
+
+/// A builder for a native class created using `VM::register_class`
+pub struct ClassBuilder<'vm, T: 'static> {
+    vm: &'vm VM,
+    module: String,
+    name: String,
+    methods: Vec<(String, u8, MethodCallback)>,
+    _marker: PhantomData<T>,
+}
+
+impl<'vm, T: 'static> ClassBuilder<'vm, T> {
+    /// Sets the constructor of the class. `callback` is called with the arguments passed to
+    /// `new` and the value it returns is wrapped in a new instance
+    pub fn constructor<F, E>(self, arity: u8, mut callback: F) -> Self
+    where
+        F: FnMut(&mut EFuncContext) -> Result<T, E> + 'static,
+        E: ToNeptuneValue,
+    {
+        self.method("construct", arity, move |cx| callback(cx).map(Resource))
+    }
+
+    /// Adds a method named `name` taking `arity` arguments. The instance can be accessed
+    /// using `EFuncContext::receiver`. A method with the same name is replaced
+    pub fn method<F, T1, T2>(mut self, name: &str, arity: u8, mut callback: F) -> Self
+    where
+        F: FnMut(&mut EFuncContext) -> Result<T1, T2> + 'static,
+        T1: ToNeptuneValue,
+        T2: ToNeptuneValue,
+    {
+        self.methods.retain(|(method, _, _)| method != name);
+        self.methods.push((
+            name.into(),
+            arity,
+            Box::new(move |cx| call_efunc(&mut callback, cx)),
+        ));
+        self
+    }
+
+    /// Adds a `toString` method which returns the string returned by `callback`
+    pub fn to_string<F>(self, mut callback: F) -> Self
+    where
+        F: FnMut(&mut T) -> String + 'static,
+    {
+        self.method("toString", 0, move |cx| -> Result<String, EFuncError> {
+            Ok(callback(cx.receiver::<T>()?))
+        })
+    }
+
+    /// Creates the class and exports it from the module.
+    /// It returns `Err(ModuleNotFound)` if the module does not exist and
+    /// `Err(VariableAlreadyExists)` if the module already has a variable with the same name
+    pub fn build(self) -> Result<(), Error> {
+        let vm = &self.vm.vm;
+        if !vm.module_exists(self.module.as_str().into()) {
+            return Err(Error::ModuleNotFound);
+        }
+        if !vm.create_class(self.module.as_str().into(), self.name.as_str().into()) {
+            return Err(Error::VariableAlreadyExists);
+        }
+        let var = vm
+            .get_module_variable(self.module.as_str().into(), self.name.as_str().into())
+            .unwrap();
+        for (method, arity, callback) in self.methods {
+            vm.add_class_method_safe(&var, &method, arity, callback);
+        }
+        Ok(())
+    }
+}
+
+type InstallFunction = Box<dyn FnOnce(&VMInner, &str, &str)>;
+
+/// A builder for a module created using `VM::create_native_module`
+pub struct NativeModuleBuilder<'vm> {
+    vm: &'vm VM,
+    name: String,
+    functions: Vec<(String, InstallFunction)>,
+}
+
+impl<'vm> NativeModuleBuilder<'vm> {
+    /// Adds a function named `name` taking `arity` arguments. The arguments are on the stack
+    /// with the last one on top. A function with the same name is replaced
+    pub fn function<F, T1, T2>(self, name: &str, arity: u8, mut callback: F) -> Self
+    where
+        F: FnMut(&mut EFuncContext) -> Result<T1, T2> + 'static,
+        T1: ToNeptuneValue,
+        T2: ToNeptuneValue,
+    {
+        self.add_function(
+            name,
+            Box::new(move |vm, module, name| {
+                vm.create_function_safe(module, name, arity, move |cx| {
+                    call_efunc(&mut callback, cx)
+                });
+            }),
+        )
+    }
+
+    /// Adds an asynchronous function named `name` taking `arity` arguments. The task calling
+    /// it is suspended until the future completes
+    pub fn function_async<F, Fut, T1, T2>(self, name: &str, arity: u8, callback: F) -> Self
+    where
+        F: (FnMut(&mut EFuncContext) -> Fut) + 'static,
+        Fut: Future<Output = Result<T1, T2>> + 'static,
+        T1: ToNeptuneValue + 'static,
+        T2: ToNeptuneValue + 'static,
+    {
+        self.add_function(
+            name,
+            Box::new(move |vm, module, name| {
+                vm.create_function_async(module, name, arity, callback);
+            }),
+        )
+    }
+
+    fn add_function(mut self, name: &str, install: InstallFunction) -> Self {
+        self.functions.retain(|(function, _)| function != name);
+        self.functions.push((name.into(), install));
+        self
+    }
+
+    /// Creates the module with its functions.
+    /// It returns `Err(ModuleAlreadyExists)` if an existing module is named `name`
+    pub fn build(self) -> Result<(), Error> {
+        self.vm.create_module(&self.name)?;
+        for (function, install) in self.functions {
+            install(&self.vm.vm, &self.name, &function);
+        }
+        Ok(())
+    }
+}
+
+// Creates the efuncs used by import to resolve and fetch modules
+fn install_module_loader<M: ModuleLoader + 'static>(vm: &VM, module_loader: M) {
+    vm.create_efunc("resolveModule", {
+        let module_loader = module_loader.clone();
+        move |cx| -> Result<String, EFuncErrorOr<ModuleNotFound>> {
+            cx.get_property("callerModule")?;
+            let caller_module = cx.as_string()?.to_string();
+            cx.get_property("moduleName")?;
+            let module_name = cx.as_string()?.to_string();
+            cx.pop().unwrap();
+            match module_loader.resolve(&caller_module, &module_name) {
+                Some(s) => Ok(s),
+                None => Err(EFuncErrorOr::Other(ModuleNotFound {
+                    module: module_name,
+                })),
+            }
+        }
+    })
+    .unwrap();
+
+    vm.create_efunc(
+        "fetchModule",
+        move |cx| -> Result<String, EFuncErrorOr<NeptuneError>> {
+            let module = cx.as_string()?;
+            match module_loader.load(module) {
+                Some(src) => Ok(src),
+                None => Err(EFuncErrorOr::Other(NeptuneError(format!(
+                    "Cannot get source of module {}",
+                    module
+                )))),
+            }
+        },
+    )
+    .unwrap();
+}
+
+// Creates the efuncs used by import to resolve and fetch modules. They suspend only the
+// importing task
+fn install_async_module_loader<M: AsyncModuleLoader + 'static>(vm: &VM, module_loader: M) {
+    vm.create_efunc_async("resolveModule", {
+        let module_loader = module_loader.clone();
+        move |cx| {
+            let args = || -> Result<(String, String), EFuncError> {
+                cx.get_property("callerModule")?;
+                let caller_module = cx.as_string()?.to_string();
+                cx.get_property("moduleName")?;
+                let module_name = cx.as_string()?.to_string();
+                cx.pop().unwrap();
+                Ok((caller_module, module_name))
+            }();
+            let module_loader = module_loader.clone();
+            async move {
+                let (caller_module, module_name) = args?;
+                match module_loader.resolve(&caller_module, &module_name).await {
+                    Some(s) => Ok(s),
+                    None => Err(EFuncErrorOr::Other(ModuleNotFound {
+                        module: module_name,
+                    })),
+                }
+            }
+        }
+    })
+    .unwrap();
+
+    vm.create_efunc_async("fetchModule", move |cx| {
+        let module = cx.as_string().map(str::to_string);
+        let module_loader = module_loader.clone();
+        async move {
+            let module = module?;
+            match module_loader.load(&module).await {
+                Some(src) => Ok(src),
+                None => Err(EFuncErrorOr::Other(NeptuneError(format!(
+                    "Cannot get source of module {}",
+                    module
+                )))),
+            }
+        }
+    })
+    .unwrap();
+}
+
+// Calls the callback of a synchronous efunc and pushes its result
+fn call_efunc<F, T1, T2>(callback: &mut F, mut cx: EFuncContext) -> bool
+where
+    F: FnMut(&mut EFuncContext) -> Result<T1, T2>,
+    T1: ToNeptuneValue,
+    T2: ToNeptuneValue,
+{
+    match callback(&mut cx) {
+        Ok(t1) => cx.push_return_value(t1),
+        Err(t2) => {
+            t2.to_neptune_value(&mut cx);
+            false
+        }
+    }
+}
+
+type CompiledFunction<'vm> = Result<(FunctionInfoWriter<'vm>, bool), Vec<CompileError>>;
+
+fn compile<'vm>(
+    vm: &'vm VMInner,
+    module: String,
+    source: &str,
+    eval: bool,
+    relocatable: bool,
+    reload: bool,
+) -> CompiledFunction<'vm> {
+    if !vm.module_exists(module.as_str().into()) {
+        vm.create_module_with_prelude(module.as_str().into());
+    }
+    let scanner = Scanner::new(source);
+    let tokens = scanner.scan_tokens();
+    let parser = Parser::new(tokens.into_iter());
+    let ast = parser.parse(eval);
+    let mut compiler = Compiler::new(vm, module);
+    if relocatable {
+        compiler = compiler.relocatable();
+    }
+    if reload {
+        compiler = compiler.reload();
+    }
+    let mut is_expr = false;
     let mut fw = if eval {
         if let Some(expr) = Compiler::can_eval(&ast.0) {
             is_expr = true;
@@ -456,291 +1910,2005 @@ fn compile<'vm>(
     if let Err(e) = &mut fw {
         errors.append(e);
     }
-    if errors.is_empty() {
-        Ok((fw.unwrap(), is_expr))
-    } else {
-        errors.sort_by(|e1, e2| e1.line.cmp(&e2.line));
-        Err(errors)
+    if errors.is_empty() {
+        Ok((fw.unwrap(), is_expr))
+    } else {
+        errors.sort_by(|e1, e2| e1.line.cmp(&e2.line));
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::Op;
+    use crate::{
+        AsyncModuleLoader, CallError, ChainModuleLoader, EFuncContext, EFuncError, EFuncErrorOr,
+        Error, FileSystemModuleLoader, FromNeptuneValue, InMemoryModuleLoader, InterpretError,
+        ModuleChanges, ModuleLoader, NeptuneError, NeptuneValue, ReloadPolicy, Resource, Sandbox,
+        Serde, SerdeError, StackFrame, ToNeptuneValue, VMBuilder, ValueType, VM,
+    };
+    use futures::StreamExt;
+    use serde::{Deserialize, Serialize};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::HashMap,
+        env,
+        fs::File,
+        future::Future,
+        io::{Read, Write},
+        path::PathBuf,
+        pin::Pin,
+        rc::Rc,
+        task::{Context, Poll},
+    };
+    fn open(file: &str) -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests");
+        path.push(file);
+        path
+    }
+    fn read(file: &str) -> std::io::Result<String> {
+        let path = open(file);
+        let mut s = String::new();
+        File::open(path).unwrap().read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    #[derive(Clone, Copy)]
+    struct TestModuleLoader;
+    impl ModuleLoader for TestModuleLoader {
+        fn resolve(&self, _: &str, module: &str) -> Option<String> {
+            Some(module.into())
+        }
+
+        fn load(&self, module: &str) -> Option<String> {
+            read(module).ok()
+        }
+    }
+
+    #[test]
+    fn test_basic() {
+        let n = VM::new(TestModuleLoader);
+        n.create_efunc("test_str", |cx| -> Result<(), ()> {
+            let s = cx.as_string().unwrap();
+            assert_eq!(s, "\n\r\t\0\'\"");
+            Ok(())
+        })
+        .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {ecall} = import('vm')
+        ecall(@test_str,'\n\r\t\0\'\"')
+        "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_runtime() {
+        let n = VM::new(TestModuleLoader);
+        if let InterpretError::UncaughtException(e) =
+            n.exec_sync("<script>", "throw 'abc'").unwrap_err()
+        {
+            assert_eq!(e.to_string(), "'abc'");
+        } else {
+            panic!("Expected error");
+        }
+        if let InterpretError::UncaughtException(e) = n
+            .exec_sync("<script>", "throw new Error('abc')")
+            .unwrap_err()
+        {
+            assert_eq!(
+                e.to_string(),
+                "In <Task> Error: abc\nat <main> (<script>:1)"
+            );
+        } else {
+            panic!("Expected error");
+        }
+        for test in [
+            "test.np",
+            "test_lines.np",
+            "test_many_registers_constants.np",
+            "test_jumps.np",
+        ] {
+            if let Err(e) = n.exec_sync(test, &read(test).unwrap()) {
+                panic!("Error in file {}, {:?}", test, e);
+            }
+        }
+        if let InterpretError::UncaughtException(e) = n
+            .exec_sync("test_deadlock.np", &read("test_deadlock.np").unwrap())
+            .unwrap_err()
+        {
+            assert_eq!(
+                e.to_string(),
+                "In <Task> DeadlockError: All tasks were asleep\nat <main> (test_deadlock.np:7)"
+            )
+        } else {
+            panic!("Expected error")
+        }
+        n.exec_sync("test_deadlock.np", &read("test_deadlock_post.np").unwrap())
+            .unwrap();
+        if let InterpretError::UncaughtException(s) = n
+            .exec_sync(
+                "test_kill_main_task.np",
+                &read("test_kill_main_task.np").unwrap(),
+            )
+            .unwrap_err()
+        {
+            assert_eq!(
+                s.to_string(),
+                "In <Task> Error: main task killed\nat <closure> (test_kill_main_task.np:3)"
+            );
+        } else {
+            panic!("Expected UncaughtException");
+        }
+        n.exec_sync(
+            "test_kill_main_task.np",
+            &read("test_kill_main_task_post.np").unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let n = VM::new(TestModuleLoader);
+        let errors: Vec<String> = serde_json::from_str(&read("errors.json").unwrap()).unwrap();
+        for error in errors {
+            let fname = format!("{}.np", error);
+            let source = read(&fname).unwrap();
+            let res = n.exec_sync(fname, &source).unwrap_err();
+            let result = serde_json::to_string_pretty(&res).unwrap();
+            if env::var("NEPTUNE_GEN_ERRORS").is_ok() {
+                File::create(open(&format!("{}.json", error)))
+                    .unwrap()
+                    .write_all(result.as_bytes())
+                    .unwrap();
+            } else {
+                let mut expected_result = String::new();
+                println!("{}", error);
+                File::open(open(&format!("{}.json", error)))
+                    .unwrap()
+                    .read_to_string(&mut expected_result)
+                    .unwrap();
+                assert_eq!(expected_result, result);
+            }
+        }
+    }
+
+    struct FirstRest {
+        first: f64,
+        rest: Vec<f64>,
+    }
+
+    enum Bar {
+        Baz,
+        Ja,
+    }
+
+    impl ToNeptuneValue for Bar {
+        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
+            match self {
+                Bar::Baz => cx.symbol("baz"),
+                Bar::Ja => cx.symbol("ja"),
+            }
+        }
+    }
+
+    struct Foo {
+        a: bool,
+        d: Vec<(i32, i32)>,
+        b: String,
+    }
+
+    impl ToNeptuneValue for Foo {
+        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
+            cx.array();
+            cx.bool(self.a);
+            cx.push_to_array().unwrap();
+            cx.map();
+            for (a, b) in &self.d {
+                cx.int(*a);
+                cx.int(*b);
+                cx.insert_in_map().unwrap();
+            }
+            cx.push_to_array().unwrap();
+            cx.string(&self.b);
+            cx.push_to_array().unwrap();
+        }
+    }
+
+    impl ToNeptuneValue for FirstRest {
+        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
+            cx.object();
+            self.first.to_neptune_value(cx);
+            cx.set_object_property("first").unwrap();
+            self.rest.to_neptune_value(cx);
+            cx.set_object_property("rest").unwrap();
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Shape {
+        Circle(f64),
+        Rect { w: i32, h: i32 },
+        Empty,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Drawing {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: HashMap<String, i32>,
+        parent: Option<Box<Drawing>>,
+        origin: (i32, i32),
+    }
+
+    #[test]
+    fn test_efunc() {
+        let n = VM::new(TestModuleLoader);
+        n.create_efunc("add", |ctx| -> Result<i32, EFuncError> {
+            ctx.get_property("a")?;
+            let i1 = ctx.as_int()?;
+            ctx.get_property("b")?;
+            let i2 = ctx.as_int()?;
+            ctx.pop().unwrap();
+            Ok(i1 + i2)
+        })
+        .unwrap();
+        n.create_efunc("firstRest", |ctx| -> Result<FirstRest, EFuncError> {
+            let len = ctx.array_length()?;
+            ctx.get_element(0)?;
+            let first = ctx.as_float()?;
+            let mut rest = vec![];
+            for i in 1..len {
+                ctx.get_element(i)?;
+                rest.push(ctx.as_float()?);
+            }
+            ctx.pop().unwrap();
+            Ok(FirstRest { first, rest })
+        })
+        .unwrap();
+        n.create_efunc(
+            "test_sym",
+            |ctx| -> Result<Bar, EFuncErrorOr<NeptuneError>> {
+                match ctx.as_symbol()? {
+                    "abc" => Ok(Bar::Baz),
+                    "def" => Ok(Bar::Ja),
+                    _ => Err(EFuncErrorOr::Other(NeptuneError("invalid!!!".into()))),
+                }
+            },
+        )
+        .unwrap();
+        //test capturing too!
+        let d = vec![(1, 2), (3, 4)];
+        n.create_efunc("foo", move |ctx| -> Result<Foo, EFuncError> {
+            ctx.get_element(0)?;
+            let b = ctx.as_string()?.to_string();
+            ctx.get_element(1)?;
+            let a = ctx.as_bool()?;
+            ctx.pop().unwrap();
+            Ok(Foo { a, b, d: d.clone() })
+        })
+        .unwrap();
+        n.create_efunc("test_null", move |ctx| -> Result<(), NeptuneError> {
+            if ctx.is_null().unwrap() {
+                Ok(())
+            } else {
+                Err(NeptuneError("Not null!!!".into()))
+            }
+        })
+        .unwrap();
+        n.create_efunc_async("test_async", |cx| {
+            let result = cx.is_null().unwrap();
+            async move {
+                if result {
+                    Ok(1.0)
+                } else {
+                    Err(NeptuneError("AAA!".into()))
+                }
+            }
+        })
+        .unwrap();
+        n.create_efunc("create_string_resource", |_| {
+            Result::<_, ()>::Ok(Resource("hello".to_owned()))
+        })
+        .unwrap();
+        n.create_efunc("create_int_resource", |_| Result::<_, ()>::Ok(Resource(1)))
+            .unwrap();
+        n.create_efunc(
+            "string_resource_inner",
+            |cx| -> Result<String, EFuncError> { Ok(cx.as_resource::<String>()?.clone()) },
+        )
+        .unwrap();
+        n.create_efunc_typed("typed_sum", |v: Vec<i32>| -> Result<i32, ()> {
+            Ok(v.iter().sum())
+        })
+        .unwrap();
+        n.create_efunc_typed(
+            "typed_lookup",
+            |(m, key): (HashMap<String, f64>, Option<String>)| -> Result<Option<f64>, ()> {
+                Ok(key.and_then(|k| m.get(&k).copied()))
+            },
+        )
+        .unwrap();
+        n.create_efunc_typed("typed_swap", |t: (bool, String)| -> Result<_, ()> {
+            Ok((t.1, t.0))
+        })
+        .unwrap();
+        n.create_efunc_typed(
+            "serde_roundtrip",
+            |Serde(mut d): Serde<Drawing>| -> Result<Serde<Drawing>, ()> {
+                d.name.push('!');
+                d.shapes.push(Shape::Rect { w: 3, h: 4 });
+                d.origin = (d.origin.1, d.origin.0);
+                Ok(Serde(d))
+            },
+        )
+        .unwrap();
+        n.create_efunc_typed("serde_large_ints", |_: ()| -> Result<_, ()> {
+            Ok(Serde((1u64 << 53, -(1i64 << 60))))
+        })
+        .unwrap();
+        n.create_efunc_typed("serde_inexact_int", |_: ()| -> Result<_, ()> {
+            Ok(Serde(vec![1u64, (1 << 53) + 1]))
+        })
+        .unwrap();
+        n.create_efunc("serde_error", |cx| -> Result<(), SerdeError> {
+            cx.deserialize::<(i32, String)>()?;
+            Ok(())
+        })
+        .unwrap();
+        if let Err(e) =
+            futures::executor::block_on(n.exec("test_efunc.np", &read("test_efunc.np").unwrap()))
+        {
+            panic!("{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_call() {
+        let n = VM::new(TestModuleLoader);
+        n.exec_sync(
+            "<script>",
+            r#"
+        export fun add(a, b) {
+            return a + b
+        }
+        export fun fail(message) {
+            throw new Error(message)
+        }
+        export fun sleep() {
+            new Channel().recv()
+        }
+        let counter = 0
+        export const incr = || {
+            counter += 1
+            return counter
+        }
+        fun hidden() {}
+        "#,
+        )
+        .unwrap();
+        assert_eq!(n.call::<_, i32>("<script>", "add", (1, 2)).unwrap(), 3);
+        assert_eq!(n.call::<_, i32>("<script>", "add", vec![5, 6]).unwrap(), 11);
+        assert_eq!(n.call::<_, i32>("<script>", "incr", ()).unwrap(), 1);
+        assert_eq!(
+            futures::executor::block_on(n.call_async::<_, i32>("<script>", "incr", ())).unwrap(),
+            2
+        );
+        assert_eq!(n.call::<_, f64>("math", "pow", (2.0, 3.0)).unwrap(), 8.0);
+        assert!(matches!(
+            n.call::<_, ()>("nonexistent", "f", ()),
+            Err(CallError::ModuleNotFound)
+        ));
+        assert!(matches!(
+            n.call::<_, ()>("<script>", "hidden", ()),
+            Err(CallError::ExportNotFound)
+        ));
+        assert!(matches!(
+            n.call::<_, ()>("<script>", "counter", ()),
+            Err(CallError::ExportNotFound)
+        ));
+        assert!(matches!(
+            n.call::<_, String>("<script>", "add", (1, 2)),
+            Err(CallError::ConversionError(EFuncError::TypeError))
+        ));
+        if let Err(CallError::UncaughtException(e)) = n.call::<_, ()>("<script>", "fail", ("abc",))
+        {
+            assert_eq!(e.to_string(), "In <Task> Error: abc\nat fail (<script>:6)");
+        } else {
+            panic!("Expected UncaughtException");
+        }
+        if let Err(CallError::UncaughtException(e)) = n.call::<_, ()>("<script>", "add", (1,)) {
+            assert_eq!(
+                e.to_string(),
+                "In <Task> ArgumentError: Function add takes 2 arguments but 1 were given"
+            );
+        } else {
+            panic!("Expected UncaughtException");
+        }
+        if let Err(CallError::UncaughtException(e)) = n.call::<_, ()>("<script>", "sleep", ()) {
+            assert_eq!(
+                e.to_string(),
+                "In <Task> DeadlockError: All tasks were asleep\nat sleep (<script>:9)"
+            );
+        } else {
+            panic!("Expected UncaughtException");
+        }
+        if let Err(CallError::UncaughtException(e)) =
+            n.call::<_, ()>("<script>", "add", vec![0; 300])
+        {
+            assert_eq!(e.to_string(), "In <Task> ArgumentError: Too many arguments");
+        } else {
+            panic!("Expected UncaughtException");
+        }
+        assert_eq!(n.call::<_, i32>("<script>", "add", (3, 4)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_value() {
+        let n = VM::new(TestModuleLoader);
+        let stored = Rc::new(RefCell::new(Vec::new()));
+        n.create_efunc_typed("store", {
+            let stored = stored.clone();
+            move |v: NeptuneValue| -> Result<(), ()> {
+                stored.borrow_mut().push(v);
+                Ok(())
+            }
+        })
+        .unwrap();
+        n.create_efunc("load", {
+            let stored = stored.clone();
+            move |cx| -> Result<NeptuneValue, EFuncError> {
+                let i = cx.as_int()?;
+                Ok(stored.borrow()[i as usize].clone())
+            }
+        })
+        .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {ecall, gc} = import('vm')
+        const {assert_eq} = import('assert.np')
+        let counter = 0
+        ecall(@store, |x| {
+            counter += x
+            return counter
+        })
+        ecall(@store, [1, 'a'])
+        ecall(@store, 3)
+        gc()
+        "#,
+        )
+        .unwrap();
+        let f = stored.borrow()[0].clone();
+        assert_eq!(n.call_value::<_, i32>(&f, (2,)).unwrap(), 2);
+        assert_eq!(n.call_value::<_, i32>(&f, (3,)).unwrap(), 5);
+        n.exec_sync(
+            "<script>",
+            r#"
+        gc()
+        assert_eq(ecall(@load, 0)(1), 6)
+        assert_eq(ecall(@load, 1), [1, 'a'])
+        assert_eq(ecall(@load, 2), 3)
+        "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            n.call_value::<_, i32>(&stored.borrow()[1], ()),
+            Err(CallError::UncaughtException(_))
+        ));
+        drop(n);
+        drop(f);
+        drop(stored);
+    }
+
+    #[test]
+    fn test_globals() {
+        let n = VM::new(TestModuleLoader);
+        n.exec_sync(
+            "<script>",
+            r#"
+        export let config = null
+        export const limit = 10
+        let hidden = 1
+        export fun read_config() {
+            return config
+        }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(
+            n.module_exports("<script>").unwrap(),
+            vec!["config", "limit", "read_config"]
+        );
+        assert_eq!(n.get_global::<i32>("<script>", "limit").unwrap(), 10);
+        assert_eq!(
+            n.get_global::<Option<i32>>("<script>", "config").unwrap(),
+            None
+        );
+        n.set_global("<script>", "config", vec!["a", "b"]).unwrap();
+        assert_eq!(
+            n.call::<_, Vec<String>>("<script>", "read_config", ())
+                .unwrap(),
+            vec!["a", "b"]
+        );
+        assert!(matches!(
+            n.set_global("<script>", "limit", 5),
+            Err(CallError::ImmutableVariable)
+        ));
+        assert!(matches!(
+            n.get_global::<i32>("<script>", "hidden"),
+            Err(CallError::ExportNotFound)
+        ));
+        assert!(matches!(
+            n.get_global::<String>("<script>", "limit"),
+            Err(CallError::ConversionError(EFuncError::TypeError))
+        ));
+        assert!(matches!(
+            n.module_exports("nonexistent"),
+            Err(CallError::ModuleNotFound)
+        ));
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq} = import('assert.np')
+        assert_eq(config, ['a', 'b'])
+        "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_class() {
+        struct Counter(i32);
+        let n = VM::new(TestModuleLoader);
+        n.create_module("counter").unwrap();
+        n.register_class::<Counter>("counter", "Counter")
+            .constructor(1, |cx| -> Result<Counter, EFuncError> {
+                Ok(Counter(cx.as_int()?))
+            })
+            .method("incr", 1, |cx| -> Result<i32, EFuncError> {
+                let by = cx.as_int()?;
+                let counter = cx.receiver::<Counter>()?;
+                counter.0 += by;
+                Ok(counter.0)
+            })
+            .method("get", 0, |cx| -> Result<i32, EFuncError> {
+                Ok(cx.receiver::<Counter>()?.0)
+            })
+            .to_string(|c| format!("Counter({})", c.0))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            n.register_class::<Counter>("counter", "Counter").build(),
+            Err(Error::VariableAlreadyExists)
+        ));
+        assert!(matches!(
+            n.register_class::<Counter>("nonexistent", "Counter")
+                .build(),
+            Err(Error::ModuleNotFound)
+        ));
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        const {gc} = import('vm')
+        const {Counter} = import('counter')
+        let c = new Counter(5)
+        gc()
+        assert_eq(c.incr(2), 7)
+        assert_eq(c.get(), 7)
+        assert_eq(c.getClass(), Counter)
+        assert_eq(Counter.name(), 'Counter')
+        assert_eq(Counter.getSuper(), Resource)
+        assert_eq(c.toString(), 'Counter(7)')
+        assert_eq('\(c)', 'Counter(7)')
+        assert_eq(c.toDebugString(), '<Counter>')
+        assert_failed(|| new Counter('a'), EFuncError, 'TypeError')
+        assert_failed(|| c.incr(), ArgumentError, 'Function incr takes 1 arguments but 0 were given')
+        assert_failed(|| new Resource(), NoMethodError, 'Class Resource does not have a constructor')
+        c.close()
+        assert_failed(|| c.get(), EFuncError, 'ResourceClosed')
+        "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_introspection() {
+        // Encodes any value as a string, like a JSON encoder would
+        fn encode(cx: &mut EFuncContext) -> Result<String, EFuncError> {
+            Ok(match cx.value_type()? {
+                ValueType::Int => cx.as_int()?.to_string(),
+                ValueType::String => format!("{:?}", cx.as_string()?),
+                ValueType::Null => {
+                    cx.pop()?;
+                    "null".into()
+                }
+                ValueType::Range => {
+                    let (start, end) = cx.as_range()?;
+                    format!("{}..{}", start, end)
+                }
+                ValueType::Map => {
+                    cx.entries()?;
+                    let mut entries = vec![];
+                    for i in 0..cx.array_length()? {
+                        cx.get_element(i)?;
+                        cx.get_element(0)?;
+                        let k = encode(cx)?;
+                        cx.get_element(1)?;
+                        let v = encode(cx)?;
+                        cx.pop()?;
+                        entries.push(format!("{}: {}", k, v));
+                    }
+                    cx.pop()?;
+                    cx.pop()?;
+                    entries.sort();
+                    format!("{{{}}}", entries.join(", "))
+                }
+                ValueType::Object => {
+                    let class = cx.class_name()?.to_string();
+                    let mut keys = cx.object_keys()?;
+                    keys.sort();
+                    let mut props = vec![];
+                    for key in keys {
+                        cx.get_property(&key)?;
+                        props.push(format!("{}: {}", key, encode(cx)?));
+                    }
+                    cx.pop()?;
+                    format!("{} {{{}}}", class, props.join(", "))
+                }
+                t => {
+                    let s = format!("<{:?} {}>", t, cx.class_name()?);
+                    cx.pop()?;
+                    s
+                }
+            })
+        }
+
+        let n = VM::new(TestModuleLoader);
+        n.create_efunc("encode", encode).unwrap();
+        n.create_efunc("toI64", i64::from_neptune_value).unwrap();
+        n.create_efunc("toU32", u32::from_neptune_value).unwrap();
+        n.create_efunc("toUsize", usize::from_neptune_value)
+            .unwrap();
+        n.create_efunc("toI8", i8::from_neptune_value).unwrap();
+        n.create_efunc("toU8", u8::from_neptune_value).unwrap();
+        n.create_efunc("toU64", u64::from_neptune_value).unwrap();
+        n.create_efunc("toF32", f32::from_neptune_value).unwrap();
+        n.create_efunc_typed("fromU64", |shift: u32| -> Result<u64, ()> {
+            Ok(1 << shift)
+        })
+        .unwrap();
+        n.create_efunc_typed("fromI64", |shift: u32| -> Result<i64, ()> {
+            Ok((1 << shift) + 1)
+        })
+        .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        const {ecall} = import('vm')
+        class Point {
+            construct(x, y) {
+                this.x = x
+                this.y = y
+            }
+        }
+        assert_eq(ecall(@encode, new Point(1, null)), 'Point {x: 1, y: null}')
+        assert_eq(ecall(@encode, Map{'a': 0..3, 1: {b: 2}}), '{"a": 0..3, 1: Object {b: 2}}')
+        assert_eq(ecall(@encode, [1]), '<Array Array>')
+        assert_eq(ecall(@toI64, 5), 5)
+        assert_eq(ecall(@toI64, 1099511627776.0), 1099511627776.0)
+        assert_eq(ecall(@toU32, 3.0), 3)
+        assert_failed(|| ecall(@toU32, -1), EFuncError, 'OutOfBoundsError')
+        assert_failed(|| ecall(@toUsize, 1.5), EFuncError, 'TypeError')
+        assert_failed(|| ecall(@toI64, 1099511627776.0 * 1099511627776.0), EFuncError, 'OutOfBoundsError')
+        assert_failed(|| ecall(@toI64, 'a'), EFuncError, 'TypeError')
+        assert_eq(ecall(@fromI64, 40), 1099511627777.0)
+        assert_failed(|| ecall(@fromI64, 53), TypeError, '9007199254740993 cannot be represented exactly by a Float')
+        assert_eq(ecall(@toI8, -128), -128)
+        assert_failed(|| ecall(@toI8, 128), EFuncError, 'OutOfBoundsError')
+        assert_eq(ecall(@toU8, 255.0), 255)
+        assert_failed(|| ecall(@toU8, 256.0), EFuncError, 'OutOfBoundsError')
+        assert_failed(|| ecall(@toU64, -1.0), EFuncError, 'OutOfBoundsError')
+        assert_eq(ecall(@toU64, 1099511627776.0), 1099511627776.0)
+        assert_eq(ecall(@toF32, 0.5), 0.5)
+        let huge = 1099511627776.0 * 1099511627776.0 * 1099511627776.0 * 1099511627776.0
+        assert_failed(|| ecall(@toF32, huge), EFuncError, 'OutOfBoundsError')
+        assert_eq(ecall(@fromU64, 63), 1099511627776.0 * 8388608)
+        "#,
+        )
+        .unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        EFuncError, EFuncErrorOr, InterpretError, ModuleLoader, NeptuneError, Resource,
-        ToNeptuneValue, VM,
-    };
-    use std::{
-        env,
-        fs::File,
-        io::{Read, Write},
-        path::PathBuf,
-    };
-    fn open(file: &str) -> PathBuf {
-        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        path.push("tests");
-        path.push(file);
-        path
+    #[test]
+    fn test_native_module() {
+        let n = VM::new(TestModuleLoader);
+        n.create_native_module("fs")
+            .function("read", 1, |cx| -> Result<String, EFuncError> {
+                Ok(format!("contents of {}", cx.as_string()?))
+            })
+            .function("sub", 2, |cx| -> Result<i32, EFuncError> {
+                let b = cx.as_int()?;
+                let a = cx.as_int()?;
+                Ok(a - b)
+            })
+            .function("now", 0, |_| -> Result<i32, ()> { Ok(42) })
+            .function_async("readAsync", 1, |cx| {
+                let path = cx.as_string().map(|s| s.to_string());
+                async move { path.map(|path| format!("contents of {}", path)) }
+            })
+            .build()
+            .unwrap();
+        assert!(matches!(
+            n.create_native_module("fs").build(),
+            Err(Error::ModuleAlreadyExists)
+        ));
+        futures::executor::block_on(n.exec(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        const fs = import('fs')
+        assert_eq(fs.read('a.txt'), 'contents of a.txt')
+        assert_eq(fs.sub(5, 3), 2)
+        assert_eq(fs.now(), 42)
+        assert_eq(fs.readAsync('b.txt'), 'contents of b.txt')
+        assert_failed(|| fs.read(), ArgumentError, 'Function read takes 1 arguments but 0 were given')
+        assert_failed(|| fs.sub(1, 2, 3), ArgumentError, 'Function sub takes 2 arguments but 3 were given')
+        assert_failed(|| fs.read(1), EFuncError, 'TypeError')
+        assert_failed(|| fs.readAsync(1), EFuncError, 'TypeError')
+        "#,
+        ))
+        .unwrap();
     }
-    fn read(file: &str) -> std::io::Result<String> {
-        let path = open(file);
-        let mut s = String::new();
-        File::open(path).unwrap().read_to_string(&mut s)?;
-        Ok(s)
+
+    #[test]
+    fn test_efunc_call() {
+        let n = VM::new(TestModuleLoader);
+        n.create_native_module("functional")
+            .function(
+                "map",
+                2,
+                |cx| -> Result<Vec<NeptuneValue>, EFuncErrorOr<NeptuneValue>> {
+                    let f = cx.as_value()?;
+                    let array = Vec::<NeptuneValue>::from_neptune_value(cx)?;
+                    let mut mapped = vec![];
+                    for v in array {
+                        cx.value(&f);
+                        mapped.push(cx.call((v,))?);
+                    }
+                    Ok(mapped)
+                },
+            )
+            .function(
+                "apply",
+                2,
+                |cx| -> Result<NeptuneValue, EFuncErrorOr<NeptuneValue>> {
+                    let arg = cx.as_value()?;
+                    cx.call((arg,))
+                },
+            )
+            .build()
+            .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        const {gc, suspendCurrentTask} = import('vm')
+        const {map, apply} = import('functional')
+        assert_eq(map([1, 2, 3], |x| {
+            gc()
+            return x * 2
+        }), [2, 4, 6])
+        assert_eq(apply(|x| apply(|y| y + 1, x) * 2, 1), 4)
+        assert_eq(map([], |x| x), [])
+        assert_failed(|| map([1], |x| {
+            throw new TypeError('bad')
+        }), TypeError, 'bad')
+        assert_failed(|| apply(5, 1), TypeError, 'Type Int is not callable')
+        assert_failed(|| apply(|x| suspendCurrentTask(), 1), Error, 'A function called by an efunc cannot suspend its task')
+        let ran = false
+        apply(|x| spawn(|| {
+            ran = true
+        }), 1)
+        suspendCurrentTask()
+        assert_eq(ran, true)
+        fun recurse(x) {
+            return apply(recurse, x + 1)
+        }
+        assert_failed(|| recurse(0), Error, 'Too many nested calls from efuncs')
+        "#,
+        )
+        .unwrap();
     }
 
-    #[derive(Clone, Copy)]
-    struct TestModuleLoader;
-    impl ModuleLoader for TestModuleLoader {
-        fn resolve(&self, _: &str, module: &str) -> Option<String> {
-            Some(module.into())
+    #[test]
+    fn test_timeout() {
+        let n = VM::new(TestModuleLoader);
+        assert_eq!(n.instruction_budget(), None);
+        n.set_instruction_budget(Some(100));
+        n.exec_sync("<script>", "for i in 0..10 {}").unwrap();
+        assert!(n.instruction_budget().unwrap() < 100);
+        match n.exec_sync("<script>", "while true {}") {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert!(e
+                    .to_string()
+                    .starts_with("In <Task> TimeoutError: The instruction budget was exhausted"))
+            }
+            _ => panic!("Expected a TimeoutError"),
+        }
+        assert_eq!(n.instruction_budget(), Some(0));
+        n.set_instruction_budget(Some(10000));
+        n.exec_sync(
+            "<script>",
+            r#"
+        export let caught = false
+        try {
+            while true {}
+        } catch e {
+            caught = true
         }
+        "#,
+        )
+        .unwrap();
+        assert!(n.get_global::<bool>("<script>", "caught").unwrap());
+        n.set_instruction_budget(None);
+        let handle = n.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        match n.exec_sync("<script>", "fun f() {}\nwhile true { f() }") {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert!(e
+                    .to_string()
+                    .starts_with("In <Task> TimeoutError: The VM was interrupted"))
+            }
+            _ => panic!("Expected a TimeoutError"),
+        }
+        thread.join().unwrap();
+        n.exec_sync("<script>", "for i in 0..10 {}").unwrap();
 
-        fn load(&self, module: &str) -> Option<String> {
-            read(module).ok()
+        // An interrupt is not lost when the main task is suspended
+        let handle = n.interrupt_handle();
+        n.create_efunc_async("interrupt", move |_| {
+            handle.interrupt();
+            async { Result::<(), ()>::Ok(()) }
+        })
+        .unwrap();
+        // Stops the loop if the interrupt is lost
+        n.set_instruction_budget(Some(10_000_000));
+        match futures::executor::block_on(n.exec(
+            "<script>",
+            "import('vm').ecall(@interrupt, null)\nwhile true {}",
+        )) {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert_eq!(e.message, "The VM was interrupted")
+            }
+            _ => panic!("Expected a TimeoutError"),
         }
     }
 
     #[test]
-    fn test_basic() {
+    fn test_heap_limit() {
         let n = VM::new(TestModuleLoader);
-        n.create_efunc("test_str", |cx| -> Result<(), ()> {
-            let s = cx.as_string().unwrap();
-            assert_eq!(s, "\n\r\t\0\'\"");
-            Ok(())
-        })
-        .unwrap();
+        n.set_max_heap_bytes(Some(1 << 20));
+        match n.exec_sync("<script>", "let a = []\nwhile true { a.push(a.len()) }") {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert!(e
+                    .to_string()
+                    .starts_with("In <Task> OutOfMemoryError: The heap limit of 1048576"))
+            }
+            _ => panic!("Expected an OutOfMemoryError"),
+        }
+        // The array is still reachable from the module variable
+        let n = VM::new(TestModuleLoader);
+        n.set_max_heap_bytes(Some(1 << 20));
         n.exec_sync(
             "<script>",
             r#"
-        const {ecall} = import('vm')
-        ecall(@test_str,'\n\r\t\0\'\"')
+        const {assert_failed} = import('assert.np')
+        assert_failed(|| {
+            let m = new Map()
+            let i = 0
+            while true {
+                m[i] = i
+                i += 1
+            }
+        }, OutOfMemoryError, 'The heap limit of 1048576 bytes was exceeded')
+        assert_failed(|| {
+            new Array(1000000, 0)
+        }, OutOfMemoryError, 'Cannot allocate an array of size 1000000')
+        assert_failed(|| {
+            let s = 'aaaaaaaaaaaaaaaa'
+            while true {
+                s = s ~ s
+            }
+        }, OutOfMemoryError, 'Cannot allocate a string of 1048576 bytes')
+        assert_failed(|| {
+            let s = 'aaaaaaaaaaaaaaaa'
+            for i in 0..12 {
+                s = s ~ s
+            }
+            s.replace('a', s)
+        }, OutOfMemoryError, 'The result of replace would exceed the heap limit')
         "#,
         )
         .unwrap();
+        n.set_max_heap_bytes(None);
+        n.exec_sync("<script>", "new Array(1000000, 0)").unwrap();
     }
 
     #[test]
-    fn test_runtime() {
+    fn test_uncaught_exception() {
         let n = VM::new(TestModuleLoader);
-        if let InterpretError::UncaughtException(e) =
-            n.exec_sync("<script>", "throw 'abc'").unwrap_err()
-        {
-            assert_eq!(e, "'abc'");
-        } else {
-            panic!("Expected error");
-        }
-        if let InterpretError::UncaughtException(e) = n
-            .exec_sync("<script>", "throw new Error('abc')")
-            .unwrap_err()
-        {
-            assert_eq!(e, "In <Task> Error: abc\nat <main> (<script>:1)");
-        } else {
-            panic!("Expected error");
+        let e = match n.exec_sync(
+            "<script>",
+            "const {currentTask} = import('vm')\ncurrentTask().setName('main')\nfun f() { [].pop() }\nf()",
+        ) {
+            Err(InterpretError::UncaughtException(e)) => e,
+            _ => panic!("Expected UncaughtException"),
+        };
+        assert_eq!(e.class, "IndexError");
+        assert_eq!(e.message, "Cannot pop from empty array");
+        assert_eq!(e.task.as_deref(), Some("main"));
+        assert_eq!(e.stack[0].function, "pop");
+        assert_eq!(e.stack[0].line, None);
+        assert_eq!(
+            &e.stack[1..],
+            &[
+                StackFrame {
+                    module: "<script>".into(),
+                    function: "f".into(),
+                    line: Some(3)
+                },
+                StackFrame {
+                    module: "<script>".into(),
+                    function: "<main>".into(),
+                    line: Some(4)
+                }
+            ]
+        );
+        assert!(e.value.is_none());
+        assert!(e
+            .to_string()
+            .starts_with("In <Task 'main'> IndexError: Cannot pop from empty array\nat pop ("));
+
+        let e = match n.exec_sync("<script>", "export let thrown = null\nthrow [1, 2]") {
+            Err(InterpretError::UncaughtException(e)) => e,
+            _ => panic!("Expected UncaughtException"),
+        };
+        assert_eq!(e.class, "Array");
+        assert!(e.stack.is_empty());
+        assert_eq!(e.to_string(), "[ 1, 2 ]");
+        n.set_global("<script>", "thrown", e.value.unwrap())
+            .unwrap();
+        assert_eq!(
+            n.get_global::<Vec<i32>>("<script>", "thrown").unwrap(),
+            vec![1, 2]
+        );
+
+        let module = r"C:\scripts (old)\main.np";
+        let e = match n.exec_sync(module, "fun f() { throw new Error('a') }\nf()") {
+            Err(InterpretError::UncaughtException(e)) => e,
+            _ => panic!("Expected UncaughtException"),
+        };
+        assert_eq!(
+            e.stack,
+            vec![
+                StackFrame {
+                    module: module.into(),
+                    function: "f".into(),
+                    line: Some(1)
+                },
+                StackFrame {
+                    module: module.into(),
+                    function: "<main>".into(),
+                    line: Some(2)
+                }
+            ]
+        );
+
+        let e = match n.exec_sync(
+            "<script>",
+            "const e = new Error('a')\ne.frames = [1]\nthrow e",
+        ) {
+            Err(InterpretError::UncaughtException(e)) => e,
+            _ => panic!("Expected UncaughtException"),
+        };
+        assert_eq!(e.message, "a");
+        assert!(e.stack.is_empty());
+    }
+
+    #[test]
+    fn test_builder() {
+        let script = r#"
+        const {range} = import('random')
+        export const numbers = (0..10).map(|_| range(0, 1000000)).collect()
+        "#;
+        let numbers = || {
+            let n = VMBuilder::new(TestModuleLoader)
+                .initial_heap_size(0)
+                .heap_growth_factor(1)
+                .initial_frames(1)
+                .seed(1234)
+                .build();
+            n.exec_sync("<script>", script).unwrap();
+            n.get_global::<Vec<i32>>("<script>", "numbers").unwrap()
+        };
+        assert_eq!(numbers(), numbers());
+        let n = VMBuilder::new(TestModuleLoader).stress_gc(true).build();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq} = import('assert.np')
+        let a = []
+        for i in 0..100 {
+            a.push('\(i)')
         }
-        for test in [
+        assert_eq(a[99], '99')
+        "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_bytecode() {
+        let compiler = VM::new(TestModuleLoader);
+        // Shift the positions of module variables so that they differ between the VMs
+        compiler
+            .exec_sync("<other>", "let a = 1\nlet b = 2")
+            .unwrap();
+        let add = compiler
+            .compile_to_bytecode(
+                "<script>",
+                "const offset = 1\nexport fun add(a, b) { return a + b + offset }",
+            )
+            .unwrap();
+        let files = [
             "test.np",
             "test_lines.np",
             "test_many_registers_constants.np",
             "test_jumps.np",
-        ] {
-            if let Err(e) = n.exec_sync(test, &read(test).unwrap()) {
+        ]
+        .map(|test| {
+            (
+                test,
+                compiler
+                    .compile_to_bytecode(test, &read(test).unwrap())
+                    .unwrap(),
+            )
+        });
+        assert!(compiler.call::<_, i32>("<script>", "add", (1, 2)).is_err());
+
+        let n = VMBuilder::new(TestModuleLoader).stress_gc(true).build();
+        n.load_bytecode_sync("<script>", &add).unwrap();
+        assert_eq!(n.call::<_, i32>("<script>", "add", (1, 2)).unwrap(), 4);
+        for (test, bytes) in &files {
+            if let Err(e) = n.load_bytecode_sync(*test, bytes) {
                 panic!("Error in file {}, {:?}", test, e);
             }
         }
-        if let InterpretError::UncaughtException(e) = n
-            .exec_sync("test_deadlock.np", &read("test_deadlock.np").unwrap())
-            .unwrap_err()
-        {
-            assert_eq!(
-                e,
-                "In <Task> DeadlockError: All tasks were asleep\nat <main> (test_deadlock.np:7)"
-            )
-        } else {
-            panic!("Expected error")
+        match n.load_bytecode_sync("<script>", b"garbage") {
+            Err(InterpretError::InvalidBytecode(e)) => assert_eq!(e, "Not Neptune bytecode"),
+            _ => panic!("Expected error"),
         }
-        n.exec_sync("test_deadlock.np", &read("test_deadlock_post.np").unwrap())
+        match n.load_bytecode_sync("<script>", &add[..add.len() - 1]) {
+            Err(InterpretError::InvalidBytecode(e)) => {
+                assert_eq!(e, "Unexpected end of bytecode")
+            }
+            _ => panic!("Expected error"),
+        }
+        // Rejected bytecode does not declare its module variables
+        let lib = compiler
+            .compile_to_bytecode("lib", "export let x = 1")
             .unwrap();
-        if let InterpretError::UncaughtException(s) = n
-            .exec_sync(
-                "test_kill_main_task.np",
-                &read("test_kill_main_task.np").unwrap(),
-            )
-            .unwrap_err()
-        {
-            assert_eq!(
-                s,
-                "In <Task> Error: main task killed\nat <closure> (test_kill_main_task.np:3)"
-            );
-        } else {
-            panic!("Expected UncaughtException");
+        assert!(n.load_bytecode_sync("lib", &lib[..lib.len() - 1]).is_err());
+        assert!(n.get_global::<NeptuneValue>("lib", "x").is_err());
+        n.load_bytecode_sync("lib", &lib).unwrap();
+        assert_eq!(n.get_global::<i32>("lib", "x").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_print() {
+        #[derive(Clone, Default)]
+        struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
+
+        let n = VM::new(TestModuleLoader);
+        let stdout = Buffer::default();
+        let stderr = Buffer::default();
+        n.set_stdout(stdout.clone());
+        n.set_stderr(stderr.clone());
         n.exec_sync(
-            "test_kill_main_task.np",
-            &read("test_kill_main_task_post.np").unwrap(),
+            "<script>",
+            "print('Hello')\nprint([1, 'a', @b])\neprint(1.5)",
         )
         .unwrap();
+        let other = VM::new(TestModuleLoader);
+        let other_stdout = Buffer::default();
+        other.set_stdout(other_stdout.clone());
+        other.exec_sync("<script>", "print(null)").unwrap();
+        assert_eq!(stdout.0.take(), b"Hello\n[ 1, 'a', @b ]\n");
+        assert_eq!(stderr.0.take(), b"1.5\n");
+        assert_eq!(other_stdout.0.take(), b"null\n");
     }
 
     #[test]
-    fn test_errors() {
-        let n = VM::new(TestModuleLoader);
-        let errors: Vec<String> = serde_json::from_str(&read("errors.json").unwrap()).unwrap();
-        for error in errors {
-            let fname = format!("{}.np", error);
-            let source = read(&fname).unwrap();
-            let res = n.exec_sync(fname, &source).unwrap_err();
-            let result = serde_json::to_string_pretty(&res).unwrap();
-            if env::var("NEPTUNE_GEN_ERRORS").is_ok() {
-                File::create(open(&format!("{}.json", error)))
-                    .unwrap()
-                    .write_all(result.as_bytes())
-                    .unwrap();
-            } else {
-                let mut expected_result = String::new();
-                println!("{}", error);
-                File::open(open(&format!("{}.json", error)))
+    fn test_module_loaders() {
+        let dir = env::temp_dir().join(format!("neptune-loaders-{}", std::process::id()));
+        let root = dir.join("root");
+        let lib = dir.join("lib");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(lib.join("pkg")).unwrap();
+        let write = |path: PathBuf, source: &str| std::fs::write(path, source).unwrap();
+        write(
+            root.join("a.np"),
+            "export const a = import('./sub/b').b + 1",
+        );
+        write(
+            root.join("sub").join("b.np"),
+            "export const b = import('../c.np').c * 2",
+        );
+        write(root.join("c.np"), "export const c = import('pkg').pkg");
+        write(lib.join("pkg").join("index.np"), "export const pkg = 5");
+        write(lib.join("hidden.np"), "export const hidden = 1");
+
+        let loader = FileSystemModuleLoader::new(&root).search_path(&lib);
+        let n = VM::new(loader.clone());
+        n.exec_sync("<script>", "export const a = import('a').a")
+            .unwrap();
+        assert_eq!(n.get_global::<i32>("<script>", "a").unwrap(), 11);
+        // Relative paths are not looked up in the search paths
+        assert!(n.exec_sync("<script>", "import('./hidden')").is_err());
+        assert_eq!(
+            crate::ModuleLoader::resolve(&loader, "<script>", "pkg"),
+            Some(
+                std::fs::canonicalize(lib.join("pkg").join("index.np"))
                     .unwrap()
-                    .read_to_string(&mut expected_result)
-                    .unwrap();
-                assert_eq!(expected_result, result);
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+        assert_eq!(crate::ModuleLoader::resolve(&loader, "<script>", "d"), None);
+
+        let in_memory: InMemoryModuleLoader = [("c.np", "export const c = 'memory'")]
+            .into_iter()
+            .collect();
+        let n = VM::new(
+            ChainModuleLoader::new()
+                .loader(in_memory.module("d", "export const d = import('c.np').c"))
+                .loader(FileSystemModuleLoader::new(&root).search_path(&lib)),
+        );
+        n.exec_sync(
+            "<script>",
+            "export const d = import('d').d\nexport const a = import('a').a",
+        )
+        .unwrap();
+        assert_eq!(n.get_global::<String>("<script>", "d").unwrap(), "memory");
+        // b.np imports ../c.np which only the file system loader resolves
+        assert_eq!(n.get_global::<i32>("<script>", "a").unwrap(), 11);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // Loads every module but cannot resolve any
+        #[derive(Clone)]
+        struct LoadOnly;
+        impl ModuleLoader for LoadOnly {
+            fn resolve(&self, _: &str, _: &str) -> Option<String> {
+                None
+            }
+
+            fn load(&self, _: &str) -> Option<String> {
+                Some("export const source = 'LoadOnly'".into())
+            }
+        }
+        let loader = ChainModuleLoader::new()
+            .loader(LoadOnly)
+            .loader(InMemoryModuleLoader::new().module("m", "export const source = 'memory'"));
+        assert_eq!(crate::ModuleLoader::load(&loader, "m"), None);
+        let n = VM::new(loader);
+        n.exec_sync("<script>", "export const source = import('m').source")
+            .unwrap();
+        assert_eq!(
+            n.get_global::<String>("<script>", "source").unwrap(),
+            "memory"
+        );
+    }
+
+    #[test]
+    fn test_async_module_loader() {
+        // Returns Pending once so that other tasks run while a module loads
+        struct YieldNow(bool);
+
+        impl Future for YieldNow {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                if self.0 {
+                    Poll::Ready(())
+                } else {
+                    self.0 = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        }
+
+        #[derive(Clone)]
+        struct AsyncLoader(Rc<Cell<u32>>);
+
+        impl AsyncModuleLoader for AsyncLoader {
+            async fn resolve(&self, _: &str, module: &str) -> Option<String> {
+                YieldNow(false).await;
+                Some(module.to_string())
+            }
+
+            async fn load(&self, module: &str) -> Option<String> {
+                YieldNow(false).await;
+                self.0.set(self.0.get() + 1);
+                match module {
+                    "double.np" => Some("export fun double(x) { return x * 2 }".into()),
+                    "triple.np" => Some("export fun triple(x) { return x * 3 }".into()),
+                    _ => None,
+                }
+            }
+        }
+
+        let loads = Rc::new(Cell::new(0));
+        let n = VMBuilder::new_async(AsyncLoader(loads.clone())).build();
+        futures::executor::block_on(n.exec(
+            "<script>",
+            r#"
+        let ran = false
+        spawn(|| {
+            ran = true
+        })
+        const {double} = import('double.np')
+        if !ran {
+            throw new Error('The spawned task did not run during the import')
+        }
+        if double(2) !== 4 {
+            throw new Error('Wrong result')
+        }
+        // Concurrent imports of the same module return the same module
+        let chan = new Channel()
+        spawn(|| chan.send(import('triple.np').triple))
+        spawn(|| chan.send(import('triple.np').triple))
+        if chan.recv() !== chan.recv() {
+            throw new Error('Expected the same function')
+        }
+        "#,
+        ))
+        .unwrap();
+        assert_eq!(loads.get(), 3);
+        match futures::executor::block_on(n.exec("<script>", "import('missing.np')")) {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert_eq!(e.message, "Cannot get source of module missing.np")
             }
+            _ => panic!("Expected an error"),
         }
     }
 
-    struct FirstRest {
-        first: f64,
-        rest: Vec<f64>,
-    }
+    #[test]
+    fn test_channel() {
+        let n = VM::new(TestModuleLoader);
+        n.exec_sync(
+            "<script>",
+            r#"
+        export fun sum(chan, n) {
+            let s = 0
+            for i in 0..n {
+                s += chan.recv()
+            }
+            return s
+        }
+        export fun produce(chan) {
+            for i in 0..3 {
+                chan.send(i * 2)
+            }
+        }
+        "#,
+        )
+        .unwrap();
+        let (sender, mut receiver) = n.channel::<i32>();
+        let (sum, _) = futures::executor::block_on(futures::future::join(
+            n.call_async::<_, i32>("<script>", "sum", (sender.channel(), 3)),
+            async {
+                for i in 1..=3 {
+                    sender.send(i).unwrap();
+                }
+            },
+        ));
+        assert_eq!(sum.unwrap(), 6);
+        // Values sent while no task is waiting are queued
+        sender.send(10).unwrap();
+        assert_eq!(
+            n.call::<_, i32>("<script>", "sum", (sender.channel(), 1))
+                .unwrap(),
+            10
+        );
 
-    enum Bar {
-        Baz,
-        Ja,
+        let channel = receiver.channel().clone();
+        let (received, result) = futures::executor::block_on(futures::future::join(
+            receiver.by_ref().take(3).collect::<Vec<_>>(),
+            n.call_async::<_, ()>("<script>", "produce", (&channel,)),
+        ));
+        result.unwrap();
+        assert_eq!(
+            received.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            [0, 2, 4]
+        );
+        sender.send("a").unwrap();
+        assert!(matches!(
+            futures::executor::block_on(receiver.next()),
+            Some(Err(EFuncError::TypeError))
+        ));
+
+        // A task waiting on a channel is deadlocked once there are no senders
+        drop(sender);
+        match futures::executor::block_on(n.call_async::<_, i32>("<script>", "sum", (&channel, 1)))
+        {
+            Err(CallError::UncaughtException(e)) => assert_eq!(e.class, "DeadlockError"),
+            _ => panic!("Expected a DeadlockError"),
+        }
+        // Senders of other channels do not keep it from being deadlocked
+        let (other_sender, _) = n.channel::<i32>();
+        match futures::executor::block_on(n.call_async::<_, i32>("<script>", "sum", (&channel, 1)))
+        {
+            Err(CallError::UncaughtException(e)) => assert_eq!(e.class, "DeadlockError"),
+            _ => panic!("Expected a DeadlockError"),
+        }
+        drop(other_sender);
+
+        let (sender, mut receiver) = n.channel::<i32>();
+        drop(n);
+        assert_eq!(sender.send(1), Err(1));
+        assert!(futures::executor::block_on(receiver.next()).is_none());
     }
 
-    impl ToNeptuneValue for Bar {
-        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
-            match self {
-                Bar::Baz => cx.symbol("baz"),
-                Bar::Ja => cx.symbol("ja"),
+    #[test]
+    fn test_cancel_futures() {
+        struct DropGuard(Rc<Cell<bool>>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.set(true);
             }
         }
+
+        let n = VM::new(TestModuleLoader);
+        let dropped = Rc::new(Cell::new(false));
+        let dropped2 = dropped.clone();
+        n.create_efunc_async("pending", move |_| {
+            let guard = DropGuard(dropped2.clone());
+            async move {
+                futures::future::pending::<()>().await;
+                drop(guard);
+                Result::<(), ()>::Ok(())
+            }
+        })
+        .unwrap();
+        let dropped2 = dropped.clone();
+        n.create_efunc_async("dropped", move |_| {
+            let dropped = dropped2.clone();
+            async move { Result::<bool, ()>::Ok(dropped.get()) }
+        })
+        .unwrap();
+        futures::executor::block_on(n.exec(
+            "<script>",
+            r#"
+        const {ecall, suspendCurrentTask} = import('vm')
+        const {assert_eq} = import('assert.np')
+        let t = spawn(|| ecall(@pending, null))
+        suspendCurrentTask()
+        assert_eq(ecall(@dropped, null), false)
+        t.kill(new Error('killed'))
+        assert_eq(t.status(), @killed)
+        assert_eq(ecall(@dropped, null), true)
+        "#,
+        ))
+        .unwrap();
+        assert!(n.vm.get_user_data().futures.borrow().is_empty());
+        // The futures of linked tasks are dropped when the main task fails
+        dropped.set(false);
+        let result = futures::executor::block_on(n.exec(
+            "<script>",
+            r#"
+        spawn_link(|| ecall(@pending, null))
+        suspendCurrentTask()
+        throw new Error('a')
+        "#,
+        ));
+        assert!(matches!(result, Err(InterpretError::UncaughtException(_))));
+        assert!(dropped.get());
+        assert!(n.vm.get_user_data().futures.borrow().is_empty());
     }
 
-    struct Foo {
-        a: bool,
-        d: Vec<(i32, i32)>,
-        b: String,
+    #[test]
+    fn test_eval() {
+        let n = VM::new(TestModuleLoader);
+        assert_eq!(n.eval_sync::<i32, _>("<script>", "6 * 7").unwrap(), 42);
+        n.exec_sync("<script>", "let x = 2").unwrap();
+        assert_eq!(n.eval_sync::<i32, _>("<script>", "x + 1").unwrap(), 3);
+        assert_eq!(
+            n.eval_sync::<Vec<String>, _>("<script>", "['a', 'b' ~ 'c']")
+                .unwrap(),
+            vec!["a", "bc"]
+        );
+        assert_eq!(
+            n.eval_sync::<Option<i32>, _>("<script>", "x = 5").unwrap(),
+            None
+        );
+        assert_eq!(
+            n.eval_sync::<Option<i32>, _>("<script>", "if true { 1 }")
+                .unwrap(),
+            None
+        );
+        assert_eq!(n.eval_sync::<i32, _>("<script>", "x").unwrap(), 5);
+        assert!(matches!(
+            n.eval_sync::<i32, _>("<script>", "'a'"),
+            Err(InterpretError::ConversionError(EFuncError::TypeError))
+        ));
+        assert!(matches!(
+            n.eval_sync::<i32, _>("<script>", "1 +"),
+            Err(InterpretError::CompileError(_))
+        ));
+        assert!(matches!(
+            n.eval_sync::<i32, _>("<script>", "throw new Error('a')"),
+            Err(InterpretError::UncaughtException(_))
+        ));
+        let result = futures::executor::block_on(n.eval::<f64, _>("<script>", "x * 0.5"));
+        assert_eq!(result.unwrap(), 2.5);
     }
 
-    impl ToNeptuneValue for Foo {
-        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
-            cx.array();
-            cx.bool(self.a);
-            cx.push_to_array().unwrap();
-            cx.map();
-            for (a, b) in &self.d {
-                cx.int(*a);
-                cx.int(*b);
-                cx.insert_in_map().unwrap();
+    #[test]
+    fn test_panic() {
+        let n = VM::new(TestModuleLoader);
+        n.create_efunc("parse", |cx| -> Result<i32, EFuncError> {
+            Ok(cx.as_string()?.parse::<i32>().expect("Not an int"))
+        })
+        .unwrap();
+        n.create_efunc_async("panic_async", |_| -> std::future::Ready<Result<(), ()>> {
+            panic!("Async panic")
+        })
+        .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {ecall} = import('vm')
+        const {assert_eq, assert_failed} = import('assert.np')
+        assert_eq(ecall(@parse, '12'), 12)
+        assert_failed(|| ecall(@parse, 'a'), PanicError, 'Not an int: ParseIntError { kind: InvalidDigit }')
+        assert_failed(|| ecall(@panic_async, null), PanicError, 'Async panic')
+        assert_eq(ecall(@parse, '3'), 3)
+        "#,
+        )
+        .unwrap();
+        match n.exec_sync("<script>", "import('vm').ecall(@parse, 'b')") {
+            Err(InterpretError::UncaughtException(e)) => {
+                assert_eq!(e.class, "PanicError");
+                assert!(e.message.starts_with("Not an int"));
             }
-            cx.push_to_array().unwrap();
-            cx.string(&self.b);
-            cx.push_to_array().unwrap();
+            _ => panic!("Expected a PanicError"),
         }
-    }
+        n.exec_sync("<script>", "import('vm').ecall(@parse, '5')")
+            .unwrap();
 
-    impl ToNeptuneValue for FirstRest {
-        fn to_neptune_value(self, cx: &mut crate::EFuncContext) {
-            cx.object();
-            self.first.to_neptune_value(cx);
-            cx.set_object_property("first").unwrap();
-            self.rest.to_neptune_value(cx);
-            cx.set_object_property("rest").unwrap();
+        fn fail() -> Result<(), ()> {
+            panic!("Panic after await")
+        }
+        n.create_efunc_async("panic_after_await", |_| async {
+            std::future::ready(()).await;
+            fail()
+        })
+        .unwrap();
+        futures::executor::block_on(n.exec(
+            "<script>",
+            r#"
+        assert_failed(|| ecall(@panic_after_await, null), PanicError, 'Panic after await')
+        "#,
+        ))
+        .unwrap();
+
+        // Panics in conversions made outside efuncs are resumed once the VM returns
+        struct PanicOnConversion;
+        impl ToNeptuneValue for PanicOnConversion {
+            fn to_neptune_value(self, _: &mut EFuncContext) {
+                panic!("Conversion panic")
+            }
         }
+        n.exec_sync("<script>", "export fun identity(x) { return x }")
+            .unwrap();
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            n.call::<_, NeptuneValue>("<script>", "identity", (PanicOnConversion,))
+        }))
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"Conversion panic"));
+        assert_eq!(n.call::<_, i32>("<script>", "identity", (1,)).unwrap(), 1);
+        let (sender, _) = n.channel::<i32>();
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            sender.send(PanicOnConversion)
+        }))
+        .is_err());
     }
 
     #[test]
-    fn test_efunc() {
+    fn test_verifier() {
+        // Serializes a function without constants, lines, upvalues, exception handlers and
+        // jump tables
+        fn function(max_registers: u32, code: &[u8]) -> Vec<u8> {
+            let mut bytes = b"NPBC".to_vec();
+            bytes.extend(1u32.to_le_bytes());
+            bytes.extend(0u32.to_le_bytes());
+            bytes.extend(6u32.to_le_bytes());
+            bytes.extend(b"<main>");
+            bytes.push(0);
+            bytes.extend(max_registers.to_le_bytes());
+            bytes.extend((code.len() as u32).to_le_bytes());
+            bytes.extend(code);
+            for _ in 0..5 {
+                bytes.extend(0u32.to_le_bytes());
+            }
+            bytes
+        }
         let n = VM::new(TestModuleLoader);
-        n.create_efunc("add", |ctx| -> Result<i32, EFuncError> {
-            ctx.get_property("a")?;
-            let i1 = ctx.as_int()?;
-            ctx.get_property("b")?;
-            let i2 = ctx.as_int()?;
-            ctx.pop().unwrap();
-            Ok(i1 + i2)
-        })
+        n.load_bytecode_sync(
+            "<script>",
+            &function(1, &[Op::LoadR0.repr, Op::Return.repr]),
+        )
         .unwrap();
-        n.create_efunc("firstRest", |ctx| -> Result<FirstRest, EFuncError> {
-            let len = ctx.array_length()?;
-            ctx.get_element(0)?;
-            let first = ctx.as_float()?;
-            let mut rest = vec![];
-            for i in 1..len {
-                ctx.get_element(i)?;
-                rest.push(ctx.as_float()?);
+        for (max_registers, code, error) in [
+            (
+                1,
+                vec![Op::LoadRegister.repr, 5, Op::Return.repr],
+                "LoadRegister at offset 0 uses register 5 but the function has 1 registers",
+            ),
+            (
+                0,
+                vec![Op::StoreR1.repr, Op::Return.repr],
+                "StoreR1 at offset 0 uses register 1 but the function has 0 registers",
+            ),
+            (
+                2,
+                vec![Op::Call.repr, 1, 2, Op::Return.repr],
+                "Call at offset 0 uses register 2 but the function has 2 registers",
+            ),
+            (
+                0,
+                vec![Op::LoadNull.repr],
+                "Execution can continue past the end of the bytecode",
+            ),
+            (
+                0,
+                vec![Op::Jump.repr, 1, Op::Return.repr],
+                "Jump at offset 0 jumps to 1 which is not the start of an instruction",
+            ),
+            (
+                0,
+                vec![Op::JumpBack.repr, 1, Op::Return.repr],
+                "JumpBack at offset 0 jumps to -1 which is not the start of an instruction",
+            ),
+            (
+                0,
+                vec![Op::LoadConstant.repr, 0, Op::Return.repr],
+                "LoadConstant at offset 0 uses constant 0 but there are only 0 constants",
+            ),
+            (
+                0,
+                vec![Op::LoadUpvalue.repr, 0, Op::Return.repr],
+                "LoadUpvalue at offset 0 uses upvalue 0 but the function has 0 upvalues",
+            ),
+            (
+                0,
+                vec![Op::Switch.repr, 0, Op::Return.repr],
+                "Switch at offset 0 uses jump table 0 but there are only 0 jump tables",
+            ),
+            (
+                u32::MAX,
+                vec![Op::LoadR0.repr, Op::Return.repr],
+                "The function has 4294967295 registers but at most 1048576 are allowed",
+            ),
+            (
+                1,
+                vec![Op::StoreArrayUnchecked.repr, 0, 0, Op::Return.repr],
+                "StoreArrayUnchecked at offset 0 is not allowed in loaded bytecode",
+            ),
+            (
+                2,
+                vec![Op::LoadNull.repr, Op::ForLoop.repr, 1, 0, Op::Return.repr],
+                "ForLoop at offset 1 is not allowed in loaded bytecode",
+            ),
+        ] {
+            match n.load_bytecode_sync("<script>", &function(max_registers, &code)) {
+                Err(InterpretError::InvalidBytecode(e)) => {
+                    assert_eq!(e, format!("{} in function <main>", error))
+                }
+                _ => panic!("Expected error {}", error),
             }
-            ctx.pop().unwrap();
-            Ok(FirstRest { first, rest })
+        }
+        for (code, error) in [
+            (vec![255], "Invalid op 255 at offset 0"),
+            (
+                vec![Op::Wide.repr, Op::LoadNull.repr, Op::Return.repr],
+                "LoadNull at offset 0 does not have a wide variant",
+            ),
+        ] {
+            match n.load_bytecode_sync("<script>", &function(0, &code)) {
+                Err(InterpretError::InvalidBytecode(e)) => assert_eq!(e, error),
+                _ => panic!("Expected error {}", error),
+            }
+        }
+        // The checked variants throw instead of trusting the types of registers
+        for code in [
+            vec![Op::StoreArray.repr, 0, 0, Op::Return.repr],
+            vec![
+                Op::LoadNull.repr,
+                Op::ForLoopChecked.repr,
+                1,
+                0,
+                Op::Return.repr,
+            ],
+        ] {
+            match n.load_bytecode_sync("<script>", &function(2, &code)) {
+                Err(InterpretError::UncaughtException(e)) => assert_eq!(e.class, "TypeError"),
+                _ => panic!("Expected a TypeError"),
+            }
+        }
+        // Nests a function in the only constant of another function depth times
+        let nested = |depth| {
+            let bytes = function(1, &[Op::LoadR0.repr, Op::Return.repr]);
+            let (header, prefix) = (&bytes[..12], &bytes[12..bytes.len() - 20]);
+            let mut body = bytes[12..].to_vec();
+            for _ in 0..depth {
+                let mut outer = prefix.to_vec();
+                outer.extend(1u32.to_le_bytes());
+                // The tag of a function constant
+                outer.push(7);
+                outer.extend(body);
+                for _ in 0..4 {
+                    outer.extend(0u32.to_le_bytes());
+                }
+                body = outer;
+            }
+            [header, &body].concat()
+        };
+        n.load_bytecode_sync("<script>", &nested(256)).unwrap();
+        match n.load_bytecode_sync("<script>", &nested(257)) {
+            Err(InterpretError::InvalidBytecode(e)) => {
+                assert_eq!(e, "Functions are nested more than 256 levels deep")
+            }
+            _ => panic!("Expected deeply nested functions to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let n = VM::new_sandboxed(
+            TestModuleLoader,
+            Sandbox {
+                hidden_modules: vec!["vm".into()],
+                allowed_efuncs: Some(vec!["double".into()]),
+                disable_compilation: true,
+            },
+        );
+        n.create_efunc("double", |cx| -> Result<i32, EFuncError> {
+            Ok(cx.as_int()? * 2)
         })
         .unwrap();
-        n.create_efunc(
-            "test_sym",
-            |ctx| -> Result<Bar, EFuncErrorOr<NeptuneError>> {
-                match ctx.as_symbol()? {
-                    "abc" => Ok(Bar::Baz),
-                    "def" => Ok(Bar::Ja),
-                    _ => Err(EFuncErrorOr::Other(NeptuneError("invalid!!!".into()))),
-                }
+        n.create_efunc("secret", |_| -> Result<i32, EFuncError> { Ok(42) })
+            .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        assert_failed(|| { import('vm') }, PermissionError, 'Cannot import module vm')
+        assert_failed(|| { eval('1') }, PermissionError, 'Runtime compilation is disabled')
+        assert_failed(|| { exec('1') }, PermissionError, 'Runtime compilation is disabled')
+        assert_eq(import('math').abs(-1), 1)
+        "#,
+        )
+        .unwrap();
+
+        let n = VM::new_sandboxed(
+            TestModuleLoader,
+            Sandbox {
+                disable_compilation: true,
+                ..Default::default()
             },
+        );
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_failed} = import('assert.np')
+        const {ecall} = import('vm')
+        const args = {moduleName: 'x', eval: false, runtime: false, source: 'throw 1'}
+        assert_failed(|| { ecall(@compile, args)() }, PermissionError, 'Cannot call EFunc compile')
+        assert_failed(|| { ecall(@compileModule, args)() }, PermissionError, 'Cannot call EFunc compileModule')
+        assert_failed(|| { ecall(@fetchModule, '/etc/passwd') }, PermissionError, 'Cannot call EFunc fetchModule')
+        assert_failed(|| { ecall(@resolveModule, {callerModule: 'x', moduleName: 'y'}) }, PermissionError, 'Cannot call EFunc resolveModule')
+        assert_failed(|| { eval('1') }, PermissionError, 'Runtime compilation is disabled')
+        "#,
         )
         .unwrap();
-        //test capturing too!
-        let d = vec![(1, 2), (3, 4)];
-        n.create_efunc("foo", move |ctx| -> Result<Foo, EFuncError> {
-            ctx.get_element(0)?;
-            let b = ctx.as_string()?.to_string();
-            ctx.get_element(1)?;
-            let a = ctx.as_bool()?;
-            ctx.pop().unwrap();
-            Ok(Foo { a, b, d: d.clone() })
+
+        let n = VM::new_sandboxed(
+            TestModuleLoader,
+            Sandbox {
+                allowed_efuncs: Some(vec!["double".into()]),
+                ..Default::default()
+            },
+        );
+        n.create_efunc("double", |cx| -> Result<i32, EFuncError> {
+            Ok(cx.as_int()? * 2)
         })
         .unwrap();
-        n.create_efunc("test_null", move |ctx| -> Result<(), NeptuneError> {
-            if ctx.is_null().unwrap() {
-                Ok(())
-            } else {
-                Err(NeptuneError("Not null!!!".into()))
+        n.create_efunc("secret", |_| -> Result<i32, EFuncError> { Ok(42) })
+            .unwrap();
+        n.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq, assert_failed} = import('assert.np')
+        const {ecall} = import('vm')
+        assert_eq(ecall(@double, 2), 4)
+        assert_failed(|| { ecall(@secret, null) }, PermissionError, 'Cannot call EFunc secret')
+        assert_failed(|| { ecall(@compile, null) }, PermissionError, 'Cannot call EFunc compile')
+        assert_eq(eval('1 + 1'), 2)
+        "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reload_module() {
+        let vm = VM::new(TestModuleLoader);
+        vm.exec_sync(
+            "lib",
+            r#"
+        export let count = 0
+        export let limit = 10
+        export fun incr() { count += 1 }
+        export class Point {
+            construct(x) { this.x = x }
+            get() { return this.x }
+        }
+        export fun old() {}
+        "#,
+        )
+        .unwrap();
+        vm.exec_sync(
+            "<script>",
+            r#"
+        const {incr, Point} = import('lib')
+        const lib = import('lib')
+        let p = new Point(3)
+        incr()
+        "#,
+        )
+        .unwrap();
+
+        let changes = vm
+            .reload_module_sync(
+                "lib",
+                r#"
+        export let count = 0
+        export let limit = 20
+        export fun incr() { count += 10 }
+        export class Point {
+            construct(x) { this.x = x }
+            get() { return this.x * 2 }
+        }
+        export fun added() {}
+        let origin = new Point(0)
+        "#,
+                ReloadPolicy::KeepState,
+            )
+            .unwrap();
+        assert_eq!(
+            changes,
+            ModuleChanges {
+                added: vec!["added".into()],
+                removed: vec!["old".into()],
+                replaced: vec!["incr".into(), "Point".into()],
+                rebound: vec![],
             }
-        })
+        );
+        vm.exec_sync(
+            "<script>",
+            r#"
+        const {assert_eq} = import('assert.np')
+        incr()
+        assert_eq(lib.count, 11)
+        assert_eq(lib.limit, 10)
+        assert_eq(p.get(), 6)
+        assert_eq(p.getClass(), Point)
+        assert_eq(new Point(4).get(), 8)
+        "#,
+        )
         .unwrap();
-        n.create_efunc_async("test_async", |cx| {
-            let result = cx.is_null().unwrap();
-            async move {
-                if result {
-                    Ok(1.0)
-                } else {
-                    Err(NeptuneError("AAA!".into()))
-                }
+        // Objects created by the new source belong to the old class
+        assert!(vm
+            .eval_sync::<bool, _>("lib", "origin.getClass() === Point")
+            .unwrap());
+        assert!(vm.get_global::<NeptuneValue>("lib", "old").is_err());
+
+        let changes = vm
+            .reload_module_sync(
+                "lib",
+                "export let count = 0\nexport let limit = 20",
+                ReloadPolicy::ResetState,
+            )
+            .unwrap();
+        assert_eq!(
+            changes,
+            ModuleChanges {
+                added: vec![],
+                removed: vec!["incr".into(), "Point".into(), "added".into()],
+                replaced: vec!["count".into(), "limit".into()],
+                rebound: vec![],
             }
+        );
+        assert_eq!(vm.get_global::<i32>("lib", "count").unwrap(), 0);
+        assert_eq!(vm.get_global::<i32>("lib", "limit").unwrap(), 20);
+        // Exports assigned the same values are unchanged
+        let changes = vm
+            .reload_module_sync(
+                "lib",
+                "export let count = 0\nexport let limit = 20",
+                ReloadPolicy::ResetState,
+            )
+            .unwrap();
+        assert_eq!(changes, ModuleChanges::default());
+
+        vm.exec_sync("lib", "count = 5").unwrap();
+        assert!(matches!(
+            vm.reload_module_sync("lib", "export let x = ", ReloadPolicy::ResetState),
+            Err(InterpretError::CompileError(_))
+        ));
+        assert!(matches!(
+            vm.reload_module_sync(
+                "lib",
+                "export let count = 1\nexport let y = 2\nthrow Error('failed')",
+                ReloadPolicy::ResetState
+            ),
+            Err(InterpretError::UncaughtException(_))
+        ));
+        assert_eq!(vm.get_global::<i32>("lib", "count").unwrap(), 5);
+        assert!(vm.get_global::<i32>("lib", "y").is_err());
+        assert!(vm.get_global::<i32>("lib", "limit").is_ok());
+
+        // A function that a suspended task is executing is not updated in place
+        vm.create_efunc_async("wait", |_| {
+            futures::future::pending::<Result<(), EFuncError>>()
         })
         .unwrap();
-        n.create_efunc("create_string_resource", |_| {
-            Result::<_, ()>::Ok(Resource("hello".to_owned()))
-        })
+        vm.exec_sync(
+            "waiting",
+            "export fun f() {\n    import('vm').ecall(@wait, null)\n    return 1\n}",
+        )
         .unwrap();
-        n.create_efunc("create_int_resource", |_| Result::<_, ()>::Ok(Resource(1)))
+        // The main task stays suspended in f
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vm.exec_sync("<script>", "import('waiting').f()")
+        }))
+        .is_err());
+        let changes = vm
+            .reload_module_sync(
+                "waiting",
+                "export fun f() { return 2 }",
+                ReloadPolicy::KeepState,
+            )
             .unwrap();
-        n.create_efunc(
-            "string_resource_inner",
-            |cx| -> Result<String, EFuncError> { Ok(cx.as_resource::<String>()?.clone()) },
+        assert_eq!(changes.replaced, vec!["f"]);
+        assert_eq!(changes.rebound, vec!["f"]);
+        assert_eq!(vm.call::<_, i32>("waiting", "f", ()).unwrap(), 2);
+
+        let changes = vm
+            .reload_module_sync("fresh", "export let a = 1", ReloadPolicy::KeepState)
+            .unwrap();
+        assert_eq!(changes.added, vec!["a"]);
+        assert!(vm
+            .reload_module_sync("fresh", "let a = 1\nlet a = 2", ReloadPolicy::KeepState)
+            .is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_trace_hook() {
+        use crate::TraceEvent;
+
+        let vm = VM::new(TestModuleLoader);
+        let events = Rc::new(RefCell::new(vec![]));
+        let events2 = events.clone();
+        vm.set_trace_hook(move |event| {
+            let event = match event {
+                TraceEvent::Call { function, .. } => format!("call {}", function),
+                TraceEvent::Return { function, .. } => format!("return {}", function),
+                TraceEvent::Line { function, line, .. } => format!("{} {}", function, line),
+                TraceEvent::Exception { line, class, .. } => format!("throw {} {}", class, line),
+                TraceEvent::TaskSwitch { task, .. } => format!("task {:?}", task),
+            };
+            events2.borrow_mut().push(event);
+        });
+        vm.exec_sync(
+            "<script>",
+            r#"fun add(a, b) {
+    return a + b
+}
+fun fail() {
+    throw 'failed'
+}
+let x = add(1,
+    2)
+try {
+    fail()
+} catch e {
+    x = 0
+}"#,
         )
         .unwrap();
-        if let Err(e) =
-            futures::executor::block_on(n.exec("test_efunc.np", &read("test_efunc.np").unwrap()))
-        {
-            panic!("{:?}", e);
-        }
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "task None",
+                "call <main>",
+                "<main> 3",
+                "<main> 1",
+                "<main> 6",
+                "<main> 4",
+                "<main> 7",
+                "call add",
+                "add 2",
+                "return add",
+                "<main> 10",
+                "call fail",
+                "fail 5",
+                "throw String 5",
+                "return fail",
+                "<main> 12",
+                "return <main>",
+            ]
+        );
+
+        events.borrow_mut().clear();
+        vm.exec_sync(
+            "<script>",
+            r#"const {suspendCurrentTask} = import('vm')
+spawn(|| {}).setName('worker')
+suspendCurrentTask()"#,
+        )
+        .unwrap();
+        let events2: Vec<_> = events
+            .borrow()
+            .iter()
+            .filter(|e| e.starts_with("task") || e.contains("closure"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            events2,
+            vec![
+                "task None",
+                "task Some(\"worker\")",
+                "call <closure>",
+                "<closure> 2",
+                "return <closure>",
+                "task None",
+            ]
+        );
+        vm.remove_trace_hook();
+        events.borrow_mut().clear();
+        vm.exec_sync("<script>", "x += 1").unwrap();
+        assert!(events.borrow().is_empty());
     }
 }
@@ -0,0 +1,212 @@
+use crate::ModuleLoader;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A module loader backed by a map from module names to sources. Modules are imported
+/// using their names
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// let loader =
+///     InMemoryModuleLoader::new().module("shapes", "export fun square(x) { return x * x }");
+/// let vm = VM::new(loader);
+/// vm.exec_sync("<script>", "export const x = import('shapes').square(3)").unwrap();
+/// assert_eq!(vm.get_global::<i32>("<script>", "x").unwrap(), 9);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryModuleLoader {
+    modules: HashMap<String, String>,
+}
+
+impl InMemoryModuleLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the module `name` with source `source`
+    pub fn module(mut self, name: impl Into<String>, source: impl Into<String>) -> Self {
+        self.insert(name, source);
+        self
+    }
+
+    /// Adds or replaces the module `name`
+    pub fn insert(&mut self, name: impl Into<String>, source: impl Into<String>) {
+        self.modules.insert(name.into(), source.into());
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for InMemoryModuleLoader {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            modules: iter
+                .into_iter()
+                .map(|(name, source)| (name.into(), source.into()))
+                .collect(),
+        }
+    }
+}
+
+impl ModuleLoader for InMemoryModuleLoader {
+    fn resolve(&self, _: &str, module: &str) -> Option<String> {
+        self.modules
+            .contains_key(module)
+            .then(|| module.to_string())
+    }
+
+    fn load(&self, module: &str) -> Option<String> {
+        self.modules.get(module).cloned()
+    }
+}
+
+/// A module loader that reads modules from the file system. Modules are named by their
+/// canonical path.
+///
+/// `import(module)` looks for `module` relative to the directory of the importing module,
+/// or the root directory if the importing module is not a file, and then in each search
+/// path. Paths starting with `./` or `../` are only looked up relative to the importing
+/// module. For each candidate path `path`, `path`, `path.np` and `path/index.np` are tried
+/// in order
+/// Example:
+/// ```no_run
+/// use neptune_lang::*;
+/// let loader = FileSystemModuleLoader::new(std::env::current_dir().unwrap())
+///     .search_paths_from_env("NEPTUNE_PATH");
+/// let vm = VM::new(loader);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FileSystemModuleLoader {
+    root: PathBuf,
+    search_paths: Vec<PathBuf>,
+}
+
+impl FileSystemModuleLoader {
+    /// Creates a loader that resolves the imports of modules that are not files, like
+    /// `<script>`, relative to `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            search_paths: Vec::new(),
+        }
+    }
+
+    /// Adds a directory where modules are looked up
+    pub fn search_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(path.into());
+        self
+    }
+
+    /// Adds the search paths in the environment variable `var`, which are separated like
+    /// in `PATH`. Nothing is added if it is not set
+    pub fn search_paths_from_env(mut self, var: &str) -> Self {
+        if let Some(paths) = std::env::var_os(var) {
+            self.search_paths.extend(std::env::split_paths(&paths));
+        }
+        self
+    }
+
+    // Returns the canonical path of the module file at path
+    fn find(path: &Path) -> Option<PathBuf> {
+        let mut with_extension = path.as_os_str().to_owned();
+        with_extension.push(".np");
+        [
+            path.to_path_buf(),
+            with_extension.into(),
+            path.join("index.np"),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())
+        .and_then(|path| std::fs::canonicalize(path).ok())
+    }
+}
+
+impl ModuleLoader for FileSystemModuleLoader {
+    fn resolve(&self, caller_module: &str, module: &str) -> Option<String> {
+        let caller_path = Path::new(caller_module);
+        let current_dir = if caller_path.is_absolute() {
+            caller_path.parent()?
+        } else {
+            &self.root
+        };
+        let module_path = Path::new(module);
+        let relative = module.starts_with("./") || module.starts_with("../");
+        let search_paths: &[PathBuf] = if relative || module_path.is_absolute() {
+            &[]
+        } else {
+            &self.search_paths
+        };
+        std::iter::once(current_dir)
+            .chain(search_paths.iter().map(PathBuf::as_path))
+            .find_map(|dir| Self::find(&dir.join(module_path)))
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    fn load(&self, module: &str) -> Option<String> {
+        std::fs::read_to_string(module).ok()
+    }
+}
+
+// An object safe version of ModuleLoader
+trait DynModuleLoader {
+    fn resolve(&self, caller_module: &str, module: &str) -> Option<String>;
+    fn load(&self, module: &str) -> Option<String>;
+}
+
+impl<M: ModuleLoader> DynModuleLoader for M {
+    fn resolve(&self, caller_module: &str, module: &str) -> Option<String> {
+        ModuleLoader::resolve(self, caller_module, module)
+    }
+
+    fn load(&self, module: &str) -> Option<String> {
+        ModuleLoader::load(self, module)
+    }
+}
+
+/// A module loader that tries several loaders in the order they were added. A module is
+/// resolved by the first loader that can do it and loaded by the loader that resolved it.
+/// Modules that were not resolved by this loader cannot be loaded
+/// Example:
+/// ```no_run
+/// use neptune_lang::*;
+/// let loader = ChainModuleLoader::new()
+///     .loader(InMemoryModuleLoader::new().module("config", "export const debug = true"))
+///     .loader(FileSystemModuleLoader::new("scripts"));
+/// let vm = VM::new(loader);
+/// ```
+#[derive(Clone, Default)]
+pub struct ChainModuleLoader {
+    loaders: Vec<Rc<dyn DynModuleLoader>>,
+    // The index of the loader that resolved each module. It is shared with the clones as
+    // the VM resolves and loads modules using different clones
+    resolved_by: Rc<RefCell<HashMap<String, usize>>>,
+}
+
+impl ChainModuleLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a loader that is tried after the ones already added
+    pub fn loader<M: ModuleLoader + 'static>(mut self, loader: M) -> Self {
+        self.loaders.push(Rc::new(loader));
+        self
+    }
+}
+
+impl ModuleLoader for ChainModuleLoader {
+    fn resolve(&self, caller_module: &str, module: &str) -> Option<String> {
+        let (i, resolved) = self
+            .loaders
+            .iter()
+            .enumerate()
+            .find_map(|(i, loader)| Some((i, loader.resolve(caller_module, module)?)))?;
+        self.resolved_by.borrow_mut().insert(resolved.clone(), i);
+        Some(resolved)
+    }
+
+    fn load(&self, module: &str) -> Option<String> {
+        let i = *self.resolved_by.borrow().get(module)?;
+        self.loaders[i].load(module)
+    }
+}
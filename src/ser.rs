@@ -0,0 +1,346 @@
+use crate::vm::exact_float;
+use crate::{EFuncContext, SerdeError};
+use serde::ser::{self, Serialize};
+
+impl<'a> EFuncContext<'a> {
+    /// Serializes `value` and pushes it to the stack.
+    /// Structs are converted to objects, sequences and tuples to arrays, maps to maps and
+    /// unit variants to symbols. Other enum variants are converted to an object with a single
+    /// property named after the variant. Integers that do not fit in an Int are converted to
+    /// Floats and it is an error if they cannot be represented exactly. Nothing is pushed if
+    /// an error is returned.
+    pub fn serialize<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let len = self.stack_len();
+        let result = value.serialize(&mut Serializer { cx: self });
+        if result.is_err() {
+            while self.stack_len() > len {
+                self.pop()?;
+            }
+        }
+        result
+    }
+}
+
+pub struct Serializer<'a, 'b> {
+    cx: &'b mut EFuncContext<'a>,
+}
+
+impl<'a, 'b> Serializer<'a, 'b> {
+    fn int(&mut self, v: i128) -> Result<(), SerdeError> {
+        if let Ok(i) = i32::try_from(v) {
+            self.cx.int(i);
+        } else if let Some(f) = exact_float(v) {
+            self.cx.float(f);
+        } else {
+            return Err(SerdeError::Custom(format!(
+                "{} cannot be represented exactly by a Float",
+                v
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> ser::Serializer for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = VariantSerializer<'a, 'b, 'c>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = VariantSerializer<'a, 'b, 'c>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerdeError> {
+        self.cx.bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), SerdeError> {
+        self.int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerdeError> {
+        self.int(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), SerdeError> {
+        match i128::try_from(v) {
+            Ok(v) => self.int(v),
+            Err(_) => Err(SerdeError::Custom(format!(
+                "{} cannot be represented exactly by a Float",
+                v
+            ))),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerdeError> {
+        self.cx.float(v as f64);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerdeError> {
+        self.cx.float(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerdeError> {
+        self.cx.string(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerdeError> {
+        self.cx.string(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+        self.cx.array();
+        for b in v {
+            self.cx.int(*b as i32);
+            self.cx.push_to_array()?;
+        }
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), SerdeError> {
+        self.cx.null();
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerdeError> {
+        self.cx.null();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerdeError> {
+        self.cx.null();
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), SerdeError> {
+        self.cx.symbol(variant);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.cx.object();
+        value.serialize(&mut *self)?;
+        self.cx.set_object_property(variant)?;
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, SerdeError> {
+        self.cx.array();
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, SerdeError> {
+        self.cx.array();
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        self.cx.array();
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantSerializer<'a, 'b, 'c>, SerdeError> {
+        self.cx.object();
+        self.cx.array();
+        Ok(VariantSerializer { ser: self, variant })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, SerdeError> {
+        self.cx.map();
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        self.cx.object();
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantSerializer<'a, 'b, 'c>, SerdeError> {
+        self.cx.object();
+        self.cx.object();
+        Ok(VariantSerializer { ser: self, variant })
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeSeq for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)?;
+        Ok(self.cx.push_to_array()?)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeTuple for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeTupleStruct for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeMap for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)?;
+        Ok(self.cx.insert_in_map()?)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeStruct for &'c mut Serializer<'a, 'b> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)?;
+        Ok(self.cx.set_object_property(key)?)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+/// Serializes the fields of a tuple or struct variant and then sets them as the property
+/// named after the variant of the enclosing object
+pub struct VariantSerializer<'a, 'b, 'c> {
+    ser: &'c mut Serializer<'a, 'b>,
+    variant: &'static str,
+}
+
+impl<'a, 'b, 'c> ser::SerializeTupleVariant for VariantSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(&mut self.ser, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(self.ser.cx.set_object_property(self.variant)?)
+    }
+}
+
+impl<'a, 'b, 'c> ser::SerializeStructVariant for VariantSerializer<'a, 'b, 'c> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        ser::SerializeStruct::serialize_field(&mut self.ser, key, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(self.ser.cx.set_object_property(self.variant)?)
+    }
+}
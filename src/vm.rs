@@ -1,7 +1,13 @@
-use cxx::{type_id, ExternType};
-use futures::{stream::FuturesUnordered, Future};
-use std::any::TypeId;
-use std::cell::RefCell;
+use crate::EFuncErrorOr;
+use cxx::{type_id, ExternType, UniquePtr};
+use futures::{stream::FuturesUnordered, Future, FutureExt, Stream};
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::panic::AssertUnwindSafe;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll, Waker};
 use std::{ffi::c_void, fmt::Display, marker::PhantomData, pin::Pin};
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -79,7 +85,25 @@ impl<'vm> Drop for TaskHandle<'vm> {
     }
 }
 
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct VMOptions {
+    pub initial_heap_size: usize,
+    pub heap_growth_factor: u32,
+    pub initial_frames: usize,
+    pub stress_gc: bool,
+    pub debug_gc: bool,
+    pub has_seed: bool,
+    pub seed: u64,
+}
+
+unsafe impl ExternType for VMOptions {
+    type Id = type_id!("neptune_vm::VMOptions");
+    type Kind = cxx::kind::Trivial;
+}
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct ModuleVariable {
     pub position: u32,
     pub mutable: bool,
@@ -96,6 +120,7 @@ pub struct EFuncContextInner<'a> {
     vm: *const VM,
     task: *mut c_void,
     value: *mut c_void,
+    receiver: *mut c_void,
     _marker: PhantomData<&'a ()>,
 }
 
@@ -159,6 +184,7 @@ mod ffi {
         StoreUpvalue,
         LoadSubscript,
         StoreArrayUnchecked,
+        StoreArray,
         StoreSubscript,
         AddRegister,
         SubtractRegister,
@@ -192,6 +218,7 @@ mod ffi {
         MakeClass,
         Range,
         ForLoop,
+        ForLoopChecked,
         Jump,
         JumpIfFalseOrNull,
         JumpIfNotFalseOrNull,
@@ -224,20 +251,66 @@ mod ffi {
         PropertyError,
     }
 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    enum ValueType {
+        Int,
+        Float,
+        Bool,
+        Null,
+        String,
+        Symbol,
+        Array,
+        Map,
+        Object,
+        Function,
+        Class,
+        Module,
+        Task,
+        Range,
+        ArrayIterator,
+        MapIterator,
+        StringIterator,
+        Channel,
+        Resource,
+    }
+
+    #[repr(u8)]
+    enum TraceEventKind {
+        Call,
+        Return,
+        Line,
+        Exception,
+        TaskSwitch,
+    }
+
     extern "Rust" {
         type UserData<'a>;
+        fn trace_event(
+            user_data: &UserData,
+            kind: TraceEventKind,
+            module: StringSlice,
+            function: StringSlice,
+            line: u32,
+            name: StringSlice,
+        );
     }
 
     unsafe extern "C++" {
         include!("neptune-lang/neptune-vm/neptune-vm.h");
         type StringSlice<'a> = super::StringSlice<'a>;
         type ModuleVariable = super::ModuleVariable;
+        type VMOptions = super::VMOptions;
         type Op;
         type VMStatus;
         type EFuncStatus;
+        type ValueType;
+        type TraceEventKind;
         type VM;
         type FunctionInfoWriter<'a> = super::FunctionInfoWriter<'a>;
         type TaskHandle<'a> = super::TaskHandle<'a>;
+        type ValueHandle;
+        type Interrupt;
         type EFuncContext<'a> = super::EFuncContextInner<'a>;
         type EFuncCallback;
         type FreeDataCallback;
@@ -285,7 +358,7 @@ mod ffi {
             name: StringSlice,
             arity: u8,
         ) -> FunctionInfoWriter<'vm>;
-        fn new_vm(user_data: Box<UserData<'static>>) -> UniquePtr<VM>;
+        fn new_vm(user_data: Box<UserData<'static>>, options: VMOptions) -> UniquePtr<VM>;
         fn get_user_data<'vm>(self: &'vm VM) -> &'vm UserData;
         // This must only be called by drop
         unsafe fn release(self: &mut FunctionInfoWriter);
@@ -305,7 +378,9 @@ mod ffi {
             offset: u32,
         ) -> bool;
         fn size(self: &FunctionInfoWriter) -> usize;
-        fn get_result(self: &VM) -> String;
+        /*callback should have correct type and must not exhibit undefined behaviour
+        if data is passed to it*/
+        unsafe fn with_uncaught_exception(self: &VM, callback: *mut EFuncCallback, data: *mut Data);
         fn create_module(self: &VM, module_name: StringSlice);
         fn create_module_with_prelude(self: &VM, module_name: StringSlice);
         fn module_exists(self: &VM, module_name: StringSlice) -> bool;
@@ -318,7 +393,108 @@ mod ffi {
             data: *mut Data,
             free_data: *mut FreeDataCallback,
         ) -> bool;
-        fn kill_main_task(self: &VM, error: StringSlice, message: StringSlice) -> String;
+        fn kill_main_task(self: &VM, error: StringSlice, message: StringSlice);
+        fn take_cancelled_futures(self: &VM) -> bool;
+        fn take_queued_channel_values(self: &VM) -> bool;
+        fn create_channel(self: &VM) -> UniquePtr<ValueHandle>;
+        fn has_waiting_tasks(self: &VM, channel: &ValueHandle) -> bool;
+        /*the handle must be a channel, push_value should have correct type and must not
+        exhibit undefined behaviour if data is passed to it*/
+        unsafe fn send_to_channel(
+            self: &VM,
+            channel: &ValueHandle,
+            push_value: *mut EFuncCallback,
+            data: *mut Data,
+        ) -> bool;
+        /*the handle must be a channel, callback should have correct type and must not
+        exhibit undefined behaviour if data is passed to it*/
+        unsafe fn receive_from_channel(
+            self: &VM,
+            channel: &ValueHandle,
+            callback: *mut EFuncCallback,
+            data: *mut Data,
+        ) -> bool;
+        fn run_tasks(self: &VM) -> VMStatus;
+        /*the module variable must exist, push_args should have correct type and must not
+        exhibit undefined behaviour if data is passed to it*/
+        unsafe fn call(
+            self: &VM,
+            module: StringSlice,
+            name: StringSlice,
+            push_args: *mut EFuncCallback,
+            data: *mut Data,
+        ) -> VMStatus;
+        /*callback should have correct type and must not exhibit undefined behaviour
+        if data is passed to it*/
+        unsafe fn with_return_value(self: &VM, callback: *mut EFuncCallback, data: *mut Data);
+        /*the position must be of an existing module variable, callback should have correct
+        type and must not exhibit undefined behaviour if data is passed to it*/
+        unsafe fn with_module_variable(
+            self: &VM,
+            position: u32,
+            callback: *mut EFuncCallback,
+            data: *mut Data,
+        );
+        /*the position must be of an existing module variable, push_value should have correct
+        type and must not exhibit undefined behaviour if data is passed to it*/
+        unsafe fn set_module_variable(
+            self: &VM,
+            position: u32,
+            push_value: *mut EFuncCallback,
+            data: *mut Data,
+        );
+        fn module_exports(self: &VM, module_name: StringSlice) -> Result<Vec<String>>;
+        fn module_variable_names(self: &VM, module_name: StringSlice) -> Result<Vec<String>>;
+        fn redeclare_module_variable(
+            self: &VM,
+            module: StringSlice,
+            name: StringSlice,
+            mutable_: bool,
+            exported: bool,
+        ) -> Result<()>;
+        fn begin_reload(self: &VM);
+        fn end_reload(self: &VM);
+        fn replace_definition(self: &VM, old: &ValueHandle, new_: &ValueHandle) -> bool;
+        fn strict_equals(self: &VM, a: &ValueHandle, b: &ValueHandle) -> bool;
+        fn serialize(self: &FunctionInfoWriter) -> Result<Vec<u8>>;
+        fn load_bytecode<'vm>(
+            self: &'vm VM,
+            module: StringSlice,
+            bytes: &[u8],
+        ) -> Result<FunctionInfoWriter<'vm>>;
+        fn get_interrupt(self: &VM) -> SharedPtr<Interrupt>;
+        fn interrupt(self: &Interrupt);
+        fn set_instruction_budget(self: &VM, budget: u64);
+        fn set_tracing(self: &VM, enabled: bool);
+        fn remove_instruction_budget(self: &VM);
+        fn get_instruction_budget(self: &VM, budget: &mut u64) -> bool;
+        fn set_max_heap_bytes(self: &VM, bytes: usize);
+        fn hide_module(self: &VM, module_name: StringSlice);
+        fn restrict_efuncs(self: &VM);
+        fn allow_efunc(self: &VM, name: StringSlice);
+        fn make_efunc_internal(self: &VM, name: StringSlice);
+        fn create_class(self: &VM, module: StringSlice, name: StringSlice) -> bool;
+        /*the position must be of a class created using create_class, functions of the correct
+        type should be passed and the functions must not exhibit undefined behaviour if
+        data is passed to them*/
+        unsafe fn add_class_method(
+            self: &VM,
+            position: u32,
+            method: StringSlice,
+            arity: u8,
+            callback: *mut EFuncCallback,
+            data: *mut Data,
+            free_data: *mut FreeDataCallback,
+        );
+        unsafe fn create_function(
+            self: &VM,
+            module: StringSlice,
+            name: StringSlice,
+            arity: u8,
+            callback: *mut EFuncCallback,
+            data: *mut Data,
+            free_data: *mut FreeDataCallback,
+        ) -> bool;
 
         fn push_int(self: &mut EFuncContext, i: i32);
         fn push_float(self: &mut EFuncContext, f: f64);
@@ -345,7 +521,20 @@ mod ffi {
         fn get_array_length(self: &EFuncContext, len: &mut usize) -> EFuncStatus;
         fn get_array_element(self: &mut EFuncContext, pos: usize) -> EFuncStatus;
         fn get_object_property(self: &mut EFuncContext, prop: StringSlice) -> EFuncStatus;
+        fn get_entries(self: &mut EFuncContext) -> EFuncStatus;
+        fn get_object_keys(self: &EFuncContext, keys: &mut Vec<String>) -> EFuncStatus;
+        fn as_range(self: &mut EFuncContext, start: &mut i32, end: &mut i32) -> EFuncStatus;
+        fn get_class_name<'a>(self: &'a EFuncContext, name: &mut StringSlice<'a>) -> EFuncStatus;
+        unsafe fn call(
+            self: &mut EFuncContext,
+            push_args: *mut EFuncCallback,
+            data: *mut Data,
+            status: &mut VMStatus,
+        ) -> EFuncStatus;
+        fn peek_is_null(self: &EFuncContext) -> EFuncStatus;
+        fn peek_type(self: &EFuncContext, t: &mut ValueType) -> EFuncStatus;
         fn pop(self: &mut EFuncContext) -> bool;
+        fn stack_len(self: &EFuncContext) -> usize;
         fn push_empty_map(self: &mut EFuncContext);
         fn insert_in_map(self: &mut EFuncContext) -> EFuncStatus;
         fn get_vm<'a>(self: &EFuncContext<'a>) -> &'a VM;
@@ -354,6 +543,7 @@ mod ffi {
         // This must only be called by drop
         unsafe fn release(self: &mut TaskHandle);
         fn get_current_task<'vm>(self: &'vm VM) -> TaskHandle<'vm>;
+        fn is_alive(self: &TaskHandle) -> bool;
         /*callbacks should have correct type and must not exhibit undefined behaviour
         if data is passed to it*/
         unsafe fn resume(
@@ -369,20 +559,157 @@ mod ffi {
             free_data: *mut FreeDataCallback,
         );
         fn as_resource(self: &mut EFuncContext, status: &mut EFuncStatus) -> *mut Data;
+        fn as_receiver(self: &EFuncContext, status: &mut EFuncStatus) -> *mut Data;
+        fn as_value_handle(
+            self: &mut EFuncContext,
+            status: &mut EFuncStatus,
+        ) -> UniquePtr<ValueHandle>;
+        fn push_value_handle(self: &mut EFuncContext, handle: &ValueHandle);
+        // The VM of the handle must not be dropped
+        unsafe fn release(self: Pin<&mut ValueHandle>);
+        // The VM of the handle must not be dropped
+        unsafe fn copy(self: &ValueHandle) -> UniquePtr<ValueHandle>;
+        // The VM of the handle must not be dropped
+        unsafe fn get_vm<'a>(self: &'a ValueHandle) -> &'a VM;
+        /*push_args should have correct type and must not exhibit undefined behaviour if data
+        is passed to it*/
+        unsafe fn call_value(
+            self: &VM,
+            callee: &ValueHandle,
+            push_args: *mut EFuncCallback,
+            data: *mut Data,
+        ) -> VMStatus;
     }
 }
 
 use ffi::EFuncStatus;
-pub use ffi::{new_vm, Data, FreeDataCallback, Op, VMStatus, VM};
+
+// The interrupt flag is atomic
+unsafe impl Send for ffi::Interrupt {}
+unsafe impl Sync for ffi::Interrupt {}
+pub use ffi::ValueType;
+pub use ffi::{new_vm, Data, FreeDataCallback, Interrupt, Op, VMStatus, VM};
 
 use crate::{CompileError, CompileErrorList};
 
 pub struct UserData<'vm> {
+    // NeptuneValues are released only while this is alive. It must be dropped before the
+    // futures as they may contain NeptuneValues
+    pub alive: Rc<()>,
     pub futures: RefCell<FuturesUnordered<NeptuneFuture<'vm>>>,
+    // Whether a panicking efunc aborts the process instead of throwing a PanicError
+    pub abort_on_panic: bool,
+    // The channels of the live channel senders. A suspended main task is not deadlocked
+    // while a task waits on one of them
+    pub senders: RefCell<Vec<Weak<NeptuneValue>>>,
+    // Set when a channel sender wakes a task while the VM is waiting for futures
+    pub tasks_woken: Cell<bool>,
+    // Woken to run the tasks woken by a channel sender
+    pub scheduler: Cell<Option<Waker>>,
+    // The wakers of the channel receivers waiting for a value
+    pub receivers: RefCell<Vec<Waker>>,
+    // Set when a value that could not be converted was replaced by a TypeError
+    pub conversion_failed: Cell<bool>,
+    #[cfg(feature = "tracing")]
+    pub trace_hook: RefCell<Option<TraceHook>>,
+}
+
+#[cfg(feature = "tracing")]
+type TraceHook = Box<dyn FnMut(TraceEvent)>;
+
+/// An event passed to the hook set using `VM::set_trace_hook`. Functions are identified by
+/// their module and name
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent<'a> {
+    /// A function was called. Native functions are not traced
+    Call { module: &'a str, function: &'a str },
+    /// A function returned or was exited by an exception
+    Return { module: &'a str, function: &'a str },
+    /// A function started executing a different line
+    Line {
+        module: &'a str,
+        function: &'a str,
+        line: u32,
+    },
+    /// A value of class `class` was thrown in a function
+    Exception {
+        module: &'a str,
+        function: &'a str,
+        line: u32,
+        class: &'a str,
+    },
+    /// The VM started or resumed running a task. `task` is its name if it has one
+    TaskSwitch {
+        module: &'a str,
+        function: &'a str,
+        task: Option<&'a str>,
+    },
 }
 
-type NeptuneFuture<'vm> =
-    Pin<Box<dyn Future<Output = (Box<dyn FnOnce(EFuncContext) -> bool>, TaskHandle<'vm>)> + 'vm>>;
+fn trace_event(
+    user_data: &UserData,
+    kind: ffi::TraceEventKind,
+    module: StringSlice,
+    function: StringSlice,
+    line: u32,
+    name: StringSlice,
+) {
+    #[cfg(feature = "tracing")]
+    if let Some(hook) = user_data.trace_hook.borrow_mut().as_mut() {
+        let (module, function) = (module.as_str(), function.as_str());
+        hook(match kind {
+            ffi::TraceEventKind::Call => TraceEvent::Call { module, function },
+            ffi::TraceEventKind::Return => TraceEvent::Return { module, function },
+            ffi::TraceEventKind::Line => TraceEvent::Line {
+                module,
+                function,
+                line,
+            },
+            ffi::TraceEventKind::Exception => TraceEvent::Exception {
+                module,
+                function,
+                line,
+                class: name.as_str(),
+            },
+            ffi::TraceEventKind::TaskSwitch => TraceEvent::TaskSwitch {
+                module,
+                function,
+                task: (!name.data.is_null()).then(|| name.as_str()),
+            },
+            _ => unreachable!(),
+        })
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (user_data, kind, module, function, line, name);
+}
+
+// Pushes the result of a future to the stack of the task that waited for it
+type ResumeCallback = Box<dyn FnOnce(EFuncContext) -> bool>;
+
+// A future returned by an async efunc together with the task waiting for it
+pub struct NeptuneFuture<'vm> {
+    task: Option<TaskHandle<'vm>>,
+    future: Pin<Box<dyn Future<Output = ResumeCallback> + 'vm>>,
+}
+
+impl<'vm> NeptuneFuture<'vm> {
+    // Whether the task has not been killed
+    pub fn is_alive(&self) -> bool {
+        self.task.as_ref().is_some_and(|task| task.is_alive())
+    }
+}
+
+impl<'vm> Future for NeptuneFuture<'vm> {
+    type Output = (ResumeCallback, TaskHandle<'vm>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future
+            .as_mut()
+            .poll(cx)
+            .map(|closure| (closure, self.task.take().unwrap()))
+    }
+}
 
 impl VM {
     pub fn create_efunc_safe<F>(&self, name: &str, callback: F) -> bool
@@ -417,6 +744,207 @@ impl VM {
     }
 }
 
+impl VM {
+    pub fn create_channel_safe<T>(&self) -> (ChannelSender, ChannelReceiver<T>) {
+        let channel = NeptuneValue {
+            handle: self.create_channel(),
+            vm: Rc::downgrade(&self.get_user_data().alive),
+        };
+        let sender = Rc::new(channel.clone());
+        self.get_user_data()
+            .senders
+            .borrow_mut()
+            .push(Rc::downgrade(&sender));
+        (
+            ChannelSender { channel: sender },
+            ChannelReceiver {
+                channel,
+                _marker: PhantomData,
+            },
+        )
+    }
+
+    // Whether a task is waiting on a channel that a channel sender can still send to
+    pub fn has_tasks_waiting_for_senders(&self) -> bool {
+        let mut senders = self.get_user_data().senders.borrow_mut();
+        senders.retain(|sender| sender.strong_count() != 0);
+        senders
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|channel| self.has_waiting_tasks(&channel.handle))
+    }
+
+    // Wakes the channel receivers if a value was added to the queue of a channel
+    pub fn wake_channel_receivers(&self) {
+        if self.take_queued_channel_values() {
+            let receivers = std::mem::take(&mut *self.get_user_data().receivers.borrow_mut());
+            for waker in receivers {
+                waker.wake();
+            }
+        }
+    }
+
+    // The module variable `name` must exist in `module`
+    pub fn call_safe<F>(&self, module: &str, name: &str, push_args: F) -> VMStatus
+    where
+        F: FnOnce(&mut EFuncContext),
+    {
+        let mut push_args = OnceCallback::new(push_args);
+        let status = unsafe {
+            self.call(
+                module.into(),
+                name.into(),
+                push_args.trampoline(),
+                push_args.data(),
+            )
+        };
+        push_args.resume_panic();
+        status
+    }
+
+    pub fn call_value_safe<F>(&self, callee: &NeptuneValue, push_args: F) -> VMStatus
+    where
+        F: FnOnce(&mut EFuncContext),
+    {
+        callee.check_vm(self);
+        let mut push_args = OnceCallback::new(push_args);
+        let status =
+            unsafe { self.call_value(&callee.handle, push_args.trampoline(), push_args.data()) };
+        push_args.resume_panic();
+        status
+    }
+
+    /// Makes `old` take the definition of `new`, so that existing references to `old` use
+    /// the new function or class. Returns false if the values cannot be merged
+    pub fn replace_definition_safe(&self, old: &NeptuneValue, new: &NeptuneValue) -> bool {
+        old.check_vm(self);
+        new.check_vm(self);
+        self.replace_definition(&old.handle, &new.handle)
+    }
+
+    /// Compares two values like `===`
+    pub fn strict_equals_safe(&self, a: &NeptuneValue, b: &NeptuneValue) -> bool {
+        a.check_vm(self);
+        b.check_vm(self);
+        self.strict_equals(&a.handle, &b.handle)
+    }
+
+    /// Calls `callback` with a context whose stack only contains the last value
+    /// returned by the main task or the uncaught exception
+    pub fn with_return_value_safe<F, T>(&self, callback: F) -> T
+    where
+        F: FnOnce(&mut EFuncContext) -> T,
+    {
+        let mut result = None;
+        let mut callback = OnceCallback::new(|cx: &mut EFuncContext| result = Some(callback(cx)));
+        unsafe { self.with_return_value(callback.trampoline(), callback.data()) }
+        callback.resume_panic();
+        result.unwrap()
+    }
+
+    /// Calls `callback` with a context whose stack only contains an object with the
+    /// properties `value`, `isError`, `class`, `message`, `task` and `stack` describing
+    /// the uncaught exception
+    pub fn with_uncaught_exception_safe<F, T>(&self, callback: F) -> T
+    where
+        F: FnOnce(&mut EFuncContext) -> T,
+    {
+        let mut result = None;
+        let mut callback = OnceCallback::new(|cx: &mut EFuncContext| result = Some(callback(cx)));
+        unsafe { self.with_uncaught_exception(callback.trampoline(), callback.data()) }
+        callback.resume_panic();
+        result.unwrap()
+    }
+
+    /// Calls `callback` with a context whose stack only contains the value of the
+    /// module variable `var`
+    pub fn with_module_variable_safe<F, T>(&self, var: ModuleVariable, callback: F) -> T
+    where
+        F: FnOnce(&mut EFuncContext) -> T,
+    {
+        let mut result = None;
+        let mut callback = OnceCallback::new(|cx: &mut EFuncContext| result = Some(callback(cx)));
+        unsafe { self.with_module_variable(var.position, callback.trampoline(), callback.data()) }
+        callback.resume_panic();
+        result.unwrap()
+    }
+
+    /// Sets the module variable `var` to the last value pushed by `push_value`
+    pub fn set_module_variable_safe<F>(&self, var: ModuleVariable, push_value: F)
+    where
+        F: FnOnce(&mut EFuncContext),
+    {
+        let mut push_value = OnceCallback::new(push_value);
+        unsafe {
+            self.set_module_variable(var.position, push_value.trampoline(), push_value.data())
+        }
+        push_value.resume_panic();
+    }
+
+    /// Adds a method to the class stored in `var`, which must be created using `create_class`
+    pub fn add_class_method_safe<F>(
+        &self,
+        var: &ModuleVariable,
+        method: &str,
+        arity: u8,
+        callback: F,
+    ) where
+        F: FnMut(EFuncContext) -> bool + 'static,
+    {
+        unsafe {
+            self.add_class_method(
+                var.position,
+                method.into(),
+                arity,
+                trampoline::<F> as *mut ffi::EFuncCallback,
+                Box::into_raw(Box::new(callback)) as *mut ffi::Data,
+                free_data::<F> as *mut ffi::FreeDataCallback,
+            )
+        }
+    }
+
+    pub fn create_function_safe<F>(&self, module: &str, name: &str, arity: u8, callback: F) -> bool
+    where
+        F: FnMut(EFuncContext) -> bool + 'static,
+    {
+        unsafe {
+            self.create_function(
+                module.into(),
+                name.into(),
+                arity,
+                trampoline::<F> as *mut ffi::EFuncCallback,
+                Box::into_raw(Box::new(callback)) as *mut ffi::Data,
+                free_data::<F> as *mut ffi::FreeDataCallback,
+            )
+        }
+    }
+
+    pub fn create_function_async<F, Fut, T1, T2>(
+        &self,
+        module: &str,
+        name: &str,
+        arity: u8,
+        callback: F,
+    ) -> bool
+    where
+        F: (FnMut(&mut EFuncContext) -> Fut) + 'static,
+        Fut: Future<Output = Result<T1, T2>> + 'static,
+        T1: ToNeptuneValue + 'static,
+        T2: ToNeptuneValue + 'static,
+    {
+        unsafe {
+            self.create_function(
+                module.into(),
+                name.into(),
+                arity,
+                async_trampoline::<F, Fut, T1, T2> as *mut ffi::EFuncCallback,
+                Box::into_raw(Box::new(callback)) as *mut ffi::Data,
+                free_data::<F> as *mut ffi::FreeDataCallback,
+            )
+        }
+    }
+}
+
 impl<'vm> TaskHandle<'vm> {
     pub fn resume_safe<F>(&mut self, callback: F) -> VMStatus
     where
@@ -437,13 +965,12 @@ where
     F: FnMut(EFuncContext) -> bool + 'static,
 {
     let callback = &mut *(data as *mut F);
+    let mut panic_cx = std::ptr::read(&cx);
     // https://github.com/rust-lang/rust/issues/52652#issuecomment-695034481
-    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(cx)))
-        .unwrap_or_else(|_| std::process::abort())
-    {
-        VMStatus::Success
-    } else {
-        VMStatus::Error
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(cx))) {
+        Ok(true) => VMStatus::Success,
+        Ok(false) => VMStatus::Error,
+        Err(payload) => throw_panic(&mut panic_cx, payload),
     }
 }
 
@@ -453,13 +980,81 @@ where
     F: FnOnce(EFuncContext) -> bool + 'static,
 {
     let callback = Box::from_raw(data as *mut F);
+    let mut panic_cx = std::ptr::read(&cx);
     // https://github.com/rust-lang/rust/issues/52652#issuecomment-695034481
-    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(cx)))
-        .unwrap_or_else(|_| std::process::abort())
-    {
-        VMStatus::Success
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(cx))) {
+        Ok(true) => VMStatus::Success,
+        Ok(false) => VMStatus::Error,
+        Err(payload) => throw_panic(&mut panic_cx, payload),
+    }
+}
+
+// Pushes a PanicError with the message of the panic, or aborts if the VM was built with
+// abort_on_panic
+fn throw_panic(cx: &mut EFuncContext, payload: Box<dyn std::any::Any + Send>) -> VMStatus {
+    if cx.vm().get_user_data().abort_on_panic {
+        std::process::abort()
+    }
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
     } else {
-        VMStatus::Error
+        "EFunc panicked"
+    };
+    cx.error("<prelude>", "PanicError", message).unwrap();
+    VMStatus::Error
+}
+
+// A callback that C++ calls at most once. Unwinding through C++ is undefined behaviour,
+// so a panic is caught and stored until control returns to rust
+struct OnceCallback<F> {
+    callback: Option<F>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<F> OnceCallback<F>
+where
+    F: FnOnce(&mut EFuncContext),
+{
+    fn new(callback: F) -> Self {
+        OnceCallback {
+            callback: Some(callback),
+            panic: None,
+        }
+    }
+
+    fn trampoline(&self) -> *mut ffi::EFuncCallback {
+        once_trampoline::<F> as *mut ffi::EFuncCallback
+    }
+
+    fn data(&mut self) -> *mut ffi::Data {
+        self as *mut Self as *mut ffi::Data
+    }
+
+    // Resumes the panic of the callback. It must be called after the C++ function that
+    // was passed the callback returns
+    fn resume_panic(self) {
+        if let Some(payload) = self.panic {
+            std::panic::resume_unwind(payload)
+        }
+    }
+}
+
+// data must contain a valid pointer to a OnceCallback<F>. If the callback panics, the
+// error status tells C++ not to use the values it pushed
+unsafe extern "C" fn once_trampoline<F>(mut cx: EFuncContext, data: *mut c_void) -> VMStatus
+where
+    F: FnOnce(&mut EFuncContext),
+{
+    let once = &mut *(data as *mut OnceCallback<F>);
+    let callback = once.callback.take().unwrap();
+    match std::panic::catch_unwind(AssertUnwindSafe(|| callback(&mut cx))) {
+        Ok(()) => VMStatus::Success,
+        Err(payload) => {
+            once.panic = Some(payload);
+            VMStatus::Error
+        }
     }
 }
 
@@ -475,12 +1070,13 @@ where
     T2: ToNeptuneValue + 'static,
 {
     let callback = &mut *(data as *mut F);
-    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         let fut = callback(&mut cx);
         register_future(&cx, fut);
-    }))
-    .unwrap_or_else(|_| std::process::abort());
-    VMStatus::Suspend
+    })) {
+        Ok(()) => VMStatus::Suspend,
+        Err(payload) => throw_panic(&mut cx, payload),
+    }
 }
 
 fn register_future<T1, T2>(cx: &EFuncContext, fut: impl Future<Output = Result<T1, T2>> + 'static)
@@ -492,19 +1088,25 @@ where
     let user_data = vm.get_user_data();
     let task = vm.get_current_task();
     let fut = async move {
-        let closure: Box<dyn FnOnce(EFuncContext) -> bool> = match fut.await {
-            Ok(value) => Box::new(move |mut ctx| {
+        // A panic while the future is polled is thrown in the task like a panic in the
+        // efunc itself
+        let closure: ResumeCallback = match AssertUnwindSafe(fut).catch_unwind().await {
+            Ok(Ok(value)) => Box::new(move |mut ctx| ctx.push_return_value(value)),
+            Ok(Err(value)) => Box::new(move |mut ctx| {
                 value.to_neptune_value(&mut ctx);
-                true
+                false
             }),
-            Err(value) => Box::new(move |mut ctx| {
-                value.to_neptune_value(&mut ctx);
+            Err(payload) => Box::new(move |mut ctx| {
+                throw_panic(&mut ctx, payload);
                 false
             }),
         };
-        (closure, task)
+        closure
     };
-    user_data.futures.borrow_mut().push(Box::pin(fut));
+    user_data.futures.borrow_mut().push(NeptuneFuture {
+        task: Some(task),
+        future: Box::pin(fut),
+    });
 }
 
 // data must contain a valid pointer to a boxed callback of type F and must only be called once
@@ -512,7 +1114,7 @@ pub unsafe extern "C" fn free_data<F>(data: *mut c_void) {
     Box::from_raw(data as *mut F);
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum EFuncError {
     TypeError,
     PropertyError,
@@ -697,6 +1299,98 @@ impl<'a> EFuncContext<'a> {
         }
     }
 
+    /// Pushes an array of `[key, value]` pairs of the map or object at the top of the stack.
+    /// The keys of an object are pushed as strings
+    pub fn entries(&mut self) -> Result<(), EFuncError> {
+        match self.0.get_entries() {
+            EFuncStatus::Ok => Ok(()),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            EFuncStatus::TypeError => Err(EFuncError::TypeError),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks if the value at the top of the stack is null without popping it
+    pub(crate) fn peek_is_null(&self) -> Result<bool, EFuncError> {
+        match self.0.peek_is_null() {
+            EFuncStatus::Ok => Ok(true),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            EFuncStatus::TypeError => Ok(false),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the type of the value at the top of the stack without popping it
+    pub fn value_type(&self) -> Result<ValueType, EFuncError> {
+        let mut t = ValueType::Null;
+        match self.0.peek_type(&mut t) {
+            EFuncStatus::Ok => Ok(t),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the property names of the object at the top of the stack without popping it
+    pub fn object_keys(&self) -> Result<Vec<String>, EFuncError> {
+        let mut keys = vec![];
+        match self.0.get_object_keys(&mut keys) {
+            EFuncStatus::Ok => Ok(keys),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            EFuncStatus::TypeError => Err(EFuncError::TypeError),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Pops a range from the stack and returns its start and end
+    pub fn as_range(&mut self) -> Result<(i32, i32), EFuncError> {
+        let (mut start, mut end) = (0, 0);
+        match self.0.as_range(&mut start, &mut end) {
+            EFuncStatus::Ok => Ok((start, end)),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            EFuncStatus::TypeError => Err(EFuncError::TypeError),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the name of the class of the value at the top of the stack without popping it
+    pub fn class_name(&self) -> Result<&str, EFuncError> {
+        let mut s = StringSlice::from("");
+        match self.0.get_class_name(&mut s) {
+            EFuncStatus::Ok => Ok(s.as_str()),
+            EFuncStatus::Underflow => Err(EFuncError::Underflow),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Pops the function at the top of the stack, calls it with `args` and converts the value
+    /// it returns. An exception thrown by the function is returned as `EFuncErrorOr::Other`,
+    /// so returning it from the efunc rethrows it.
+    /// The function runs to completion in a new task before `call` returns, so it cannot
+    /// suspend. This can only be used from inside an efunc
+    pub fn call<A, T>(&mut self, args: A) -> Result<T, EFuncErrorOr<NeptuneValue>>
+    where
+        A: ToNeptuneArgs,
+        T: FromNeptuneValue,
+    {
+        let mut push_args = OnceCallback::new(|cx: &mut EFuncContext| args.to_neptune_args(cx));
+        let mut status = VMStatus::Success;
+        let result = unsafe {
+            self.0
+                .call(push_args.trampoline(), push_args.data(), &mut status)
+        };
+        push_args.resume_panic();
+        match result {
+            EFuncStatus::Ok => {}
+            EFuncStatus::Underflow => return Err(EFuncError::Underflow.into()),
+            _ => unreachable!(),
+        }
+        if status == VMStatus::Error {
+            Err(EFuncErrorOr::Other(self.as_value()?))
+        } else {
+            Ok(T::from_neptune_value(self)?)
+        }
+    }
+
     /// Pops the top of the stack
     pub fn pop(&mut self) -> Result<(), EFuncError> {
         if self.0.pop() {
@@ -744,6 +1438,26 @@ impl<'a> EFuncContext<'a> {
         self.0.push_function(fw)
     }
 
+    // The number of values pushed by this context that were not popped
+    pub(crate) fn stack_len(&self) -> usize {
+        self.0.stack_len()
+    }
+
+    // Pushes a TypeError in place of a value that cannot be converted
+    pub(crate) fn conversion_error(&mut self, message: &str) {
+        self.error("<prelude>", "TypeError", message).unwrap();
+        self.vm().get_user_data().conversion_failed.set(true);
+    }
+
+    // Pushes the value returned by an efunc. Returns false if it could not be converted, in
+    // which case the efunc must throw the TypeError pushed instead
+    pub(crate) fn push_return_value<T: ToNeptuneValue>(&mut self, value: T) -> bool {
+        let conversion_failed = &self.vm().get_user_data().conversion_failed;
+        conversion_failed.set(false);
+        value.to_neptune_value(self);
+        !conversion_failed.replace(false)
+    }
+
     pub(crate) fn vm(&self) -> &'a VM {
         self.0.get_vm()
     }
@@ -761,28 +1475,62 @@ impl<'a> EFuncContext<'a> {
         }
     }
 
-    pub fn as_resource<T: 'static>(&mut self) -> Result<&mut T, EFuncError> {
+    /// Pops the value at the top of the stack and roots it so that it can be used after the
+    /// efunc returns
+    pub fn as_value(&mut self) -> Result<NeptuneValue, EFuncError> {
         let mut status = EFuncStatus::Ok;
-        let data = self.0.as_resource(&mut status);
+        let handle = self.0.as_value_handle(&mut status);
         match status {
-            EFuncStatus::Ok => {
-                if data.is_null() {
-                    Err(EFuncError::ResourceClosed)
-                } else {
-                    unsafe {
-                        if *(data as *mut TypeId) == TypeId::of::<T>() {
-                            Ok(&mut (*(data as *mut ThinAny<T>)).data)
-                        } else {
-                            Err(EFuncError::TypeError)
-                        }
-                    }
-                }
-            }
+            EFuncStatus::Ok => Ok(NeptuneValue {
+                handle,
+                vm: Rc::downgrade(&self.vm().get_user_data().alive),
+            }),
             EFuncStatus::Underflow => Err(EFuncError::Underflow),
-            EFuncStatus::TypeError => Err(EFuncError::TypeError),
             _ => unreachable!(),
         }
     }
+
+    /// Pushes a rooted value to the stack.
+    /// It panics if the value belongs to another VM
+    pub fn value(&mut self, value: &NeptuneValue) {
+        value.check_vm(self.vm());
+        self.0.push_value_handle(&value.handle)
+    }
+
+    pub fn as_resource<T: 'static>(&mut self) -> Result<&mut T, EFuncError> {
+        let mut status = EFuncStatus::Ok;
+        let data = self.0.as_resource(&mut status);
+        unsafe { downcast_resource(data, status) }
+    }
+
+    /// Returns the instance on which a method of a class created using `VM::register_class`
+    /// was called. The instance is not part of the stack
+    pub fn receiver<T: 'static>(&mut self) -> Result<&mut T, EFuncError> {
+        let mut status = EFuncStatus::Ok;
+        let data = self.0.as_receiver(&mut status);
+        unsafe { downcast_resource(data, status) }
+    }
+}
+
+// data must be null or point to a ThinAny
+unsafe fn downcast_resource<'a, T: 'static>(
+    data: *mut ffi::Data,
+    status: EFuncStatus,
+) -> Result<&'a mut T, EFuncError> {
+    match status {
+        EFuncStatus::Ok => {
+            if data.is_null() {
+                Err(EFuncError::ResourceClosed)
+            } else if *(data as *mut TypeId) == TypeId::of::<T>() {
+                Ok(&mut (*(data as *mut ThinAny<T>)).data)
+            } else {
+                Err(EFuncError::TypeError)
+            }
+        }
+        EFuncStatus::Underflow => Err(EFuncError::Underflow),
+        EFuncStatus::TypeError => Err(EFuncError::TypeError),
+        _ => unreachable!(),
+    }
 }
 
 /// Types that can be converted to Neptune values implement this trait
@@ -816,12 +1564,65 @@ impl ToNeptuneValue for i32 {
     }
 }
 
+// Returns i as a Float if it can be represented exactly. i128::MAX as f64 is rounded up to
+// 2^127 which does not fit in an i128 so it is checked separately
+pub(crate) fn exact_float(i: i128) -> Option<f64> {
+    let f = i as f64;
+    if f as i128 == i && f != i128::MAX as f64 {
+        Some(f)
+    } else {
+        None
+    }
+}
+
+// Values that do not fit in an Int are pushed as Floats. A TypeError is pushed instead if
+// the Float cannot represent the value exactly
+macro_rules! integer_to_neptune_value {
+    ($($t:ty),+) => {
+        $(impl ToNeptuneValue for $t {
+            fn to_neptune_value(self, cx: &mut EFuncContext) {
+                if let Ok(i) = i32::try_from(self) {
+                    cx.int(i)
+                } else if let Some(f) = exact_float(self as i128) {
+                    cx.float(f)
+                } else {
+                    cx.conversion_error(&format!(
+                        "{} cannot be represented exactly by a Float",
+                        self
+                    ))
+                }
+            }
+        })+
+    };
+}
+
+integer_to_neptune_value!(i64, isize, u32, u64, usize);
+
+// Integers that always fit in an Int
+macro_rules! small_integer_to_neptune_value {
+    ($($t:ty),+) => {
+        $(impl ToNeptuneValue for $t {
+            fn to_neptune_value(self, cx: &mut EFuncContext) {
+                cx.int(self.into())
+            }
+        })+
+    };
+}
+
+small_integer_to_neptune_value!(i8, i16, u8, u16);
+
 impl ToNeptuneValue for f64 {
     fn to_neptune_value(self, cx: &mut EFuncContext) {
         cx.float(self)
     }
 }
 
+impl ToNeptuneValue for f32 {
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        cx.float(self as f64)
+    }
+}
+
 impl ToNeptuneValue for bool {
     fn to_neptune_value(self, cx: &mut EFuncContext) {
         cx.bool(self)
@@ -908,6 +1709,184 @@ impl<T: 'static> ToNeptuneValue for Resource<T> {
     }
 }
 
+/// A Neptune value that is not garbage collected until it is dropped. It can be obtained
+/// using `EFuncContext::as_value` and stored by the embedder to be used after the efunc
+/// returns. If the VM is dropped the value can no longer be used.
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// use std::{cell::RefCell, rc::Rc};
+/// let vm = VM::new(NoopModuleLoader);
+/// let stored = Rc::new(RefCell::new(None));
+/// vm.create_efunc_typed("store", {
+///     let stored = stored.clone();
+///     move |v: NeptuneValue| -> Result<(), ()> {
+///         *stored.borrow_mut() = Some(v);
+///         Ok(())
+///     }
+/// })
+/// .unwrap();
+/// vm.exec_sync(
+///     "<script>",
+///     "const {ecall} = import('vm')
+///     ecall(@store, |x| x * 2)",
+/// )
+/// .unwrap();
+/// let f = stored.borrow_mut().take().unwrap();
+/// assert_eq!(vm.call_value::<_, i32>(&f, (21,)).unwrap(), 42);
+/// ```
+pub struct NeptuneValue {
+    handle: UniquePtr<ffi::ValueHandle>,
+    vm: Weak<()>,
+}
+
+impl NeptuneValue {
+    fn check_vm(&self, vm: &VM) {
+        if !self.vm.ptr_eq(&Rc::downgrade(&vm.get_user_data().alive)) {
+            panic!("NeptuneValue belongs to another VM");
+        }
+    }
+}
+
+impl Clone for NeptuneValue {
+    /// Roots the value again.
+    /// It panics if the VM is dropped
+    fn clone(&self) -> Self {
+        if self.vm.strong_count() == 0 {
+            panic!("The VM of the NeptuneValue is dropped");
+        }
+        Self {
+            handle: unsafe { self.handle.copy() },
+            vm: self.vm.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for NeptuneValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NeptuneValue").finish_non_exhaustive()
+    }
+}
+
+impl Drop for NeptuneValue {
+    fn drop(&mut self) {
+        if self.vm.strong_count() != 0 {
+            unsafe { self.handle.pin_mut().release() }
+        }
+    }
+}
+
+impl ToNeptuneValue for NeptuneValue {
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        cx.value(&self)
+    }
+}
+
+impl ToNeptuneValue for &NeptuneValue {
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        cx.value(self)
+    }
+}
+
+/// The sending half of a channel created by `VM::channel`. The values are sent to a
+/// Neptune `Channel` which can be passed to scripts using `channel`.
+/// While a task waits on a channel that has a sender a suspended main task is not
+/// considered deadlocked
+/// Example:
+/// ```
+/// use neptune_lang::*;
+/// let vm = VM::new(NoopModuleLoader);
+/// vm.exec_sync("<script>", "export fun recv(chan) { return chan.recv() }").unwrap();
+/// let (sender, _) = vm.channel::<NeptuneValue>();
+/// sender.send(42).unwrap();
+/// let x: i32 = vm.call("<script>", "recv", (sender.channel(),)).unwrap();
+/// assert_eq!(x, 42);
+/// ```
+#[derive(Clone)]
+pub struct ChannelSender {
+    channel: Rc<NeptuneValue>,
+}
+
+impl ChannelSender {
+    /// Sends `value` to the channel. If a task is waiting on the channel it is woken and
+    /// run by the pending `exec` or `call_async`. The value is returned if the VM is dropped
+    pub fn send<T: ToNeptuneValue>(&self, value: T) -> Result<(), T> {
+        if self.channel.vm.strong_count() == 0 {
+            return Err(value);
+        }
+        let vm = unsafe { self.channel.handle.get_vm() };
+        let mut push_value = OnceCallback::new(|cx: &mut EFuncContext| value.to_neptune_value(cx));
+        let woken = unsafe {
+            vm.send_to_channel(
+                &self.channel.handle,
+                push_value.trampoline(),
+                push_value.data(),
+            )
+        };
+        push_value.resume_panic();
+        let user_data = vm.get_user_data();
+        if woken {
+            user_data.tasks_woken.set(true);
+            if let Some(waker) = user_data.scheduler.take() {
+                waker.wake();
+            }
+        }
+        vm.wake_channel_receivers();
+        Ok(())
+    }
+
+    /// The Neptune `Channel` the values are sent to
+    pub fn channel(&self) -> &NeptuneValue {
+        &self.channel
+    }
+}
+
+/// The receiving half of a channel created by `VM::channel`. It is a stream of the values
+/// sent to the Neptune `Channel` returned by `channel` converted to `T`. Tasks waiting
+/// on the channel receive values before it. The stream ends when the VM is dropped.
+/// Values sent by scripts are only received after the VM suspends or finishes
+pub struct ChannelReceiver<T> {
+    channel: NeptuneValue,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ChannelReceiver<T> {
+    /// The Neptune `Channel` the values are received from
+    pub fn channel(&self) -> &NeptuneValue {
+        &self.channel
+    }
+}
+
+// The channel is behind a pointer so moving the receiver does not move it
+impl<T> Unpin for ChannelReceiver<T> {}
+
+impl<T: FromNeptuneValue> Stream for ChannelReceiver<T> {
+    type Item = Result<T, EFuncError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.channel.vm.strong_count() == 0 {
+            return Poll::Ready(None);
+        }
+        let vm = unsafe { self.channel.handle.get_vm() };
+        let mut result = None;
+        let mut callback =
+            OnceCallback::new(|cx: &mut EFuncContext| result = Some(T::from_neptune_value(cx)));
+        let received = unsafe {
+            vm.receive_from_channel(&self.channel.handle, callback.trampoline(), callback.data())
+        };
+        callback.resume_panic();
+        if received {
+            Poll::Ready(result)
+        } else {
+            let mut receivers = vm.get_user_data().receivers.borrow_mut();
+            if !receivers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                receivers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
 impl<T: ToNeptuneValue> ToNeptuneValue for Vec<T> {
     fn to_neptune_value(self, cx: &mut EFuncContext) {
         cx.array();
@@ -917,3 +1896,263 @@ impl<T: ToNeptuneValue> ToNeptuneValue for Vec<T> {
         }
     }
 }
+
+impl<K: ToNeptuneValue, V: ToNeptuneValue, S> ToNeptuneValue for HashMap<K, V, S> {
+    fn to_neptune_value(self, cx: &mut EFuncContext) {
+        cx.map();
+        for (k, v) in self {
+            k.to_neptune_value(cx);
+            v.to_neptune_value(cx);
+            cx.insert_in_map().unwrap();
+        }
+    }
+}
+
+macro_rules! tuple_to_neptune_value {
+    ($($t:ident),+) => {
+        impl<$($t: ToNeptuneValue),+> ToNeptuneValue for ($($t,)+) {
+            #[allow(non_snake_case)]
+            fn to_neptune_value(self, cx: &mut EFuncContext) {
+                let ($($t,)+) = self;
+                cx.array();
+                $(
+                    $t.to_neptune_value(cx);
+                    cx.push_to_array().unwrap();
+                )+
+            }
+        }
+    };
+}
+
+tuple_to_neptune_value!(A);
+tuple_to_neptune_value!(A, B);
+tuple_to_neptune_value!(A, B, C);
+tuple_to_neptune_value!(A, B, C, D);
+tuple_to_neptune_value!(A, B, C, D, E);
+tuple_to_neptune_value!(A, B, C, D, E, F);
+
+/// Types that can be converted from Neptune values implement this trait
+/// Example:
+/// ```
+/// use neptune_lang::*;
+///
+/// struct Point {
+///     x: i32,
+///     y: i32
+/// }
+///
+/// impl FromNeptuneValue for Point {
+///     fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+///         cx.get_property("x")?; // push property x of the object at the top of the stack
+///         let x = cx.as_int()?;  // pop it as an int
+///         cx.get_property("y")?;
+///         let y = i32::from_neptune_value(cx)?; // an alternate way to pop from the stack
+///         cx.pop()?; // pop the object
+///         Ok(Point { x, y })
+///     }
+/// }
+/// ```
+pub trait FromNeptuneValue: Sized {
+    /// Pops the value at the top of the stack and converts it
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError>;
+}
+
+impl FromNeptuneValue for i32 {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.as_int()
+    }
+}
+
+// Ints and Floats without a fractional part are converted. Values that do not fit in the
+// type are an OutOfBoundsError
+macro_rules! integer_from_neptune_value {
+    ($($t:ty),+) => {
+        $(impl FromNeptuneValue for $t {
+            fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+                match cx.value_type()? {
+                    ValueType::Int => {
+                        <$t>::try_from(cx.as_int()?).map_err(|_| EFuncError::OutOfBoundsError)
+                    }
+                    ValueType::Float => {
+                        let f = cx.as_float()?;
+                        if f.fract() != 0.0 || !f.is_finite() {
+                            return Err(EFuncError::TypeError);
+                        }
+                        if f < <$t>::MIN as f64 || f >= <$t>::MAX as f64 + 1.0 {
+                            return Err(EFuncError::OutOfBoundsError);
+                        }
+                        Ok(f as $t)
+                    }
+                    _ => {
+                        cx.pop()?;
+                        Err(EFuncError::TypeError)
+                    }
+                }
+            }
+        })+
+    };
+}
+
+integer_from_neptune_value!(i8, i16, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromNeptuneValue for f64 {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.as_float()
+    }
+}
+
+// Finite Floats outside the range of f32 are an OutOfBoundsError instead of becoming
+// infinite
+impl FromNeptuneValue for f32 {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        let f = cx.as_float()?;
+        if f.is_finite() && f.abs() > f32::MAX as f64 {
+            Err(EFuncError::OutOfBoundsError)
+        } else {
+            Ok(f as f32)
+        }
+    }
+}
+
+impl FromNeptuneValue for bool {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.as_bool()
+    }
+}
+
+impl FromNeptuneValue for () {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        if cx.is_null()? {
+            Ok(())
+        } else {
+            Err(EFuncError::TypeError)
+        }
+    }
+}
+
+impl FromNeptuneValue for String {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        Ok(cx.as_string()?.to_owned())
+    }
+}
+
+impl<T: FromNeptuneValue> FromNeptuneValue for Option<T> {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        if cx.peek_is_null()? {
+            cx.pop()?;
+            Ok(None)
+        } else {
+            Ok(Some(T::from_neptune_value(cx)?))
+        }
+    }
+}
+
+impl<T: FromNeptuneValue> FromNeptuneValue for Vec<T> {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        let len = cx.array_length()?;
+        let mut v = Vec::with_capacity(len);
+        for i in 0..len {
+            cx.get_element(i)?;
+            v.push(T::from_neptune_value(cx)?);
+        }
+        cx.pop()?;
+        Ok(v)
+    }
+}
+
+impl<K, V, S> FromNeptuneValue for HashMap<K, V, S>
+where
+    K: FromNeptuneValue + Eq + Hash,
+    V: FromNeptuneValue,
+    S: BuildHasher + Default,
+{
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.entries()?;
+        let len = cx.array_length()?;
+        let mut map = HashMap::with_capacity_and_hasher(len, S::default());
+        for i in 0..len {
+            cx.get_element(i)?;
+            cx.get_element(0)?;
+            let k = K::from_neptune_value(cx)?;
+            cx.get_element(1)?;
+            let v = V::from_neptune_value(cx)?;
+            cx.pop()?;
+            map.insert(k, v);
+        }
+        cx.pop()?;
+        cx.pop()?;
+        Ok(map)
+    }
+}
+
+macro_rules! tuple_from_neptune_value {
+    ($len:literal; $($t:ident),+) => {
+        impl<$($t: FromNeptuneValue),+> FromNeptuneValue for ($($t,)+) {
+            fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+                if cx.array_length()? != $len {
+                    return Err(EFuncError::TypeError);
+                }
+                let mut i = 0;
+                let tuple = ($({
+                    cx.get_element(i)?;
+                    i += 1;
+                    $t::from_neptune_value(cx)?
+                },)+);
+                let _ = i;
+                cx.pop()?;
+                Ok(tuple)
+            }
+        }
+    };
+}
+
+tuple_from_neptune_value!(1; A);
+tuple_from_neptune_value!(2; A, B);
+tuple_from_neptune_value!(3; A, B, C);
+tuple_from_neptune_value!(4; A, B, C, D);
+tuple_from_neptune_value!(5; A, B, C, D, E);
+tuple_from_neptune_value!(6; A, B, C, D, E, F);
+
+impl FromNeptuneValue for NeptuneValue {
+    fn from_neptune_value(cx: &mut EFuncContext) -> Result<Self, EFuncError> {
+        cx.as_value()
+    }
+}
+
+/// Types that can be passed as the arguments of a Neptune function implement this trait.
+/// It is implemented for `()`, tuples and `Vec`s of `ToNeptuneValue` types
+pub trait ToNeptuneArgs {
+    /// Pushes each argument on the stack
+    fn to_neptune_args(self, cx: &mut EFuncContext);
+}
+
+impl ToNeptuneArgs for () {
+    fn to_neptune_args(self, _: &mut EFuncContext) {}
+}
+
+impl<T: ToNeptuneValue> ToNeptuneArgs for Vec<T> {
+    fn to_neptune_args(self, cx: &mut EFuncContext) {
+        for arg in self {
+            arg.to_neptune_value(cx);
+        }
+    }
+}
+
+macro_rules! tuple_to_neptune_args {
+    ($($t:ident),+) => {
+        impl<$($t: ToNeptuneValue),+> ToNeptuneArgs for ($($t,)+) {
+            #[allow(non_snake_case)]
+            fn to_neptune_args(self, cx: &mut EFuncContext) {
+                let ($($t,)+) = self;
+                $($t.to_neptune_value(cx);)+
+            }
+        }
+    };
+}
+
+tuple_to_neptune_args!(A);
+tuple_to_neptune_args!(A, B);
+tuple_to_neptune_args!(A, B, C);
+tuple_to_neptune_args!(A, B, C, D);
+tuple_to_neptune_args!(A, B, C, D, E);
+tuple_to_neptune_args!(A, B, C, D, E, F);